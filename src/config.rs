@@ -0,0 +1,2338 @@
+//! The command line options, the rule files of `--criteria-file` and printing the settings.
+
+use regex::Regex;
+use x11rb::protocol::xproto::{EventMask, GrabMode, Keysym, ModMask};
+
+use crate::{
+    filter::EventFilter,
+    keymap::{keysym_name, parse_keysym, Keymap},
+};
+
+pub(crate) fn usage() -> &'static str {
+    "mmk(mimic)
+  use a different keyboard layout for a given window.
+
+  options:
+    values can also be given as --option=value, and short options bundled like -al 1 for --all --layout 1
+    -h | --help                    \tprints this help message
+    -V | --version                 \tprints the name and version of mmk
+    -l | --layout                  \tspecify which layout to use, starts from 0, or its name like `us` or `dvorak`
+        default: 0, meaning use the current layout
+        a comma separated list hands the layouts out to the matched windows in turn
+        `auto-toggle` uses whichever of exactly two configured layouts isn't active at the moment
+    --layout-by-locale <lang>      \tuse the configured layout for the given language instead of an index,
+        e.g. `de`, `ru` or `pt_BR`
+    -w | --window <wid>            \ttry to run on a window with the given x11 id
+        default: [needs to be specified]
+    --window-from-env <var>        \tthe same as --window with the id read from the given environment variable
+    --window-ids-file <file>       \ttry to run on the windows whose ids are listed in the given file, one per
+        line in hex or decimal. `#` starts a comment, lines which aren't an id are skipped
+    -c | --class <class>           \ttry to run on a window with the given x11 window class, like `Firefox`.
+        the combined `<class>.<instance>` still works too
+        default: [needs to be specified]
+    --instance <instance>          \ttry to run on a window with the given x11 window instance, like
+        `Navigator`. along with --class both have to match
+    --class-separator <str>        \tput the given string between class and instance instead of `.`, for
+        instances which contain dots. applies to --class and everywhere a class is printed
+    --instance-regex <pattern>     \ttry to run on a window whose x11 window instance matches the given regex
+        default: [needs to be specified]
+    --role <role>                  \ttry to run on a window with the given WM_WINDOW_ROLE, like `browser`
+        or `pop-up`, which GTK apps and browsers tell their windows apart with
+    --window-type <types>          \tonly run on windows of the given comma separated _NET_WM_WINDOW_TYPE,
+        like `normal` or `dialog`. windows without one count as `normal`. on its own it matches every
+        window of the type, with other criteria it narrows down their matches, so
+        `--all --class foo --window-type normal` leaves out the docks and popups of foo
+    -p | --pid <pid>               \ttry to run on a client with the given process id
+        default: [needs to be specified]
+    -n | --name <name>             \ttry to run on a window with a given WM_NAME or _NET_WM_NAME property
+    --name-regex <pattern>         \ttry to run on a window whose WM_NAME or _NET_WM_NAME matches the given regex
+    --ignore-case                  \tcompare --class and --name without regard to case
+    --contains                     \tmatch a --class or --name which is only part of the window's, e.g. with
+        --ignore-case `--name firefox` matches `Mozilla Firefox`
+    -a | --all                     \ttry to run on all windows matching the specified criteria
+        and skip the ones whose keyboard another client already grabbed. without it such a
+        window stops mmk with the exit code 3
+    --combine <or|and>             \twhether a window has to match any or all of the given criteria
+        default: or
+    --match-mode <snapshot|live>   \thow to match windows, snapshot only matches the windows which exist on startup,
+        live also matches windows that show up later and forgets about destroyed ones. without
+        --all the next matching window takes the place of a destroyed one
+        default: snapshot
+    --follow-class                 \trun on every window of the class of the active window, and move on to
+        the windows of the next class when another app becomes active. needs an EWMH window manager
+    --max-tracked <n>              \trun on at most the given number of windows, beyond it let go of the
+        window which had no key for the longest time, along with what mmk kept about it. bounds the
+        memory of a long running live match, the match cache only keeps windows which still exist
+    --grab-on-map                  \tthe same as --match-mode live, for starting mmk before the app it targets
+    --dedup-windows <pid|class>    \tof the matched windows sharing a pid or a class only keep the one with
+        the lowest id. windows without a pid or class are always kept
+    --expand-children              \talso run on the input windows nested inside of the matched windows
+    --dry-run                      \tprint the windows matching the specified criteria without running on them
+    --list-windows                 \tprint the id, class, pid and title of every matching window and exit
+    --test-window                  \topen a window of mmk's own, print its id and run on it as well, for
+        trying out a layout without another app. the window is destroyed on exit
+    --select                       \tclick the window to run on with a crosshair, like xprop and xwininfo,
+        instead of giving its id with --window
+    --watch                        \twith --dry-run keep reprinting the matching windows as windows come and go,
+        without it keep running on the windows which match later, the same as --match-mode live
+    --require-focus                \tonly send keys to a window while it or one of its children has the input focus
+    --grab-keysym-combos <combos>  \tonly grab the given comma separated key combinations instead of every key
+        example: Control+c,Super+space
+    --grab-keyboard                \tgrab the whole keyboard for the window instead of its keys, so every key
+        is translated wherever the focus is. the grab is exclusive: no other client gets any key until
+        mmk exits. only works on a single window
+    --list-atoms <wid>             \tprint the properties set on the window with the given x11 id and exit
+    --debounce <ms>                \tdrop presses of the same key which arrive within the given milliseconds
+    --criteria-file | --config <file>
+                                   \trun on the windows matched by each [[rule]] in the given file with its own layout
+        the keys of a rule are the long options which pick windows or the layout, with - or _,
+        for example:
+            [[rule]]
+            class = \"discord.discord\"
+            layout = 1
+        a rule also fits on a line of its own, like `{ name_regex = \".*vim.*\", layout = 2 }`,
+        in a `rules = [...]` array before the first [[rule]]. the file is the part of toml with
+        bare keys, strings with their escapes, integers, booleans and # comments.
+        without any criteria on the command line ~/.config/mmk/rules.toml is used, if it exists
+    --self-test                    \tcheck that common keysyms translate back and forth in the current keymap and exit
+    --list-layouts                 \tprint the layouts configured with setxkbmap and their indices and exit,
+        e.g. `1: us` for --layout 1. without setxkbmap the names of the keymap's groups are printed
+    --output <text|json>           \tthe format to print lists in
+        default: text
+    --key-hook <cmd>               \trun the given shell command for every translated key
+        the key is passed in the MMK_EVENT, MMK_WINDOW, MMK_ORIGINAL_KEYCODE, MMK_ORIGINAL_STATE,
+        MMK_KEYCODE, MMK_STATE and MMK_KEYSYM environment variables. this forks a process per key,
+        so at most 8 hooks run at the same time and keys beyond that don't run it
+    --output-on-match <cmd>        \trun the given shell command for every window once it is grabbed, with
+        MMK_WINDOW, MMK_CLASS, MMK_PID and MMK_NAME set. like --key-hook at most 8 run at once
+    --remap <src>=<dst>            \tsend the keysym dst instead of src, no matter the layout, can be repeated
+        example: --remap Caps_Lock=Escape
+    --translate-caps-to <keysym>   \tthe same as --remap Caps_Lock=<keysym>
+    --record <file>                \tappend every sent key to the given file as a line of json
+    --min-keycode <keycode>        \tkeys which translate to a keycode below this one are left alone
+    --max-keycode <keycode>        \tkeys which translate to a keycode above this one are left alone
+        default: the highest keycode of the X server
+    --pointer-mode <sync|async>    \tthe pointer mode of the key grabs, sync freezes the pointer until mmk
+        handled the key
+        default: async
+    --keyboard-mode <sync|async>   \tthe keyboard mode of the key grabs, sync freezes the keyboard until mmk
+        handled the key
+        default: async
+    --drain-before-grab            \tdiscard the events which were already queued when the windows got grabbed
+    --verbose-matching             \tprint every examined window which did not match and why
+    --profile-startup              \tprint how long connecting, reading the keymap, walking the window tree,
+        each criterion of the matching and grabbing the windows took
+    --check-grab-conflicts         \tbefore running, check whether another client already grabs the keys
+        and stop if it does
+    --force                        \twith --check-grab-conflicts, run anyway with the grabs that succeeded
+    --emit-to-focus-subtree        \tsend keys to the focused window if it is inside of the matched window
+    --no-xwayland-warning          \tdon't warn when running under Xwayland
+    -q | --quiet                   \tdon't print warnings
+    --x-error-mode <strict|lenient>\twhat to do about an error from the X server, e.g. for a window which is
+        gone by the time it's read or grabbed. strict stops mmk, lenient skips the window or request
+        default: lenient
+    --strict                       \tstop with an error when several windows match without --all, a window
+        has a malformed WM_CLASS or a grab conflicts, even with --force. other warnings stay warnings
+    --group-lock <n>               \tswitch the whole keyboard to the given layout while running and
+        switch it back afterwards. unlike --layout this affects every window, not only the matched ones
+    --translate-level <n>          \tlook keys up at the given shift level no matter the modifiers, 1 is
+        the second level, usually shifted. for testing the levels of a layout
+    --respect-numlock-state        \ttake the locked NumLock and CapsLock state into account when the
+        key event itself doesn't carry it, so keypad keys translate to digits
+    --match-cache <file>           \tremember the class, pid and names of windows in the given file so
+        repeated runs don't have to read them again
+    --match-cache-ttl <seconds>    \thow long a window stays in the match cache
+        default: 10
+    --deliver-mask <mask>          \tsend keys with this event mask instead of the one of the window
+        a number or a comma separated list like `key-press,key-release`, `none` sends to the client
+        which created the window
+    --only-printable               \tonly translate keys which type a character, navigation and function
+        keys are left alone
+    --reconnect                    \twhen the X server goes away, keep trying to connect again and grab
+        the matching windows once it is back. the default with --match-mode live, --watch and
+        --follow-class
+    --no-reconnect                 \texit when the X server goes away, even with --match-mode live
+    --dump-config                  \tprint the settings in effect, including the rules of --criteria-file
+        after the command line was applied to them, and exit
+    --keymap-string <keymap|file>  \tlook up the keys to send in this xkb keymap instead of the one of the
+        X server. either the keymap itself, starting with `xkb_keymap`, or a file containing it.
+        needs mmk to be built with the `xkbcommon` feature
+    --pause-on-signal              \tSIGUSR1 turns translation off and on again, SIGUSR2 prints how many
+        keys were translated so far
+    --switch-layout-on-signal      \tSIGUSR1 switches the windows to the next layout and SIGUSR2 to the previous
+        one, wrapping around the layouts set up with setxkbmap. uses the same signals as --pause-on-signal
+    --toggle-key <combo>           \tturn translation off and on again with a key combination, like
+        `Super+m`. it is grabbed on the root window, so no window gets it
+    --suspend-while-fullscreen     \tsend the keys of a window unchanged while it is fullscreen, like games
+        usually are. needs an EWMH window manager
+    --layout-name-in-title         \tshow the layout a window's keys are translated to at the end of its
+        title, like `Original Title [dvorak]`. the titles are put back on exit
+    --layout-indicator             \tpublish the layout of the last translated key as `<index>:<name>` in the
+        _MMK_ACTIVE_LAYOUT property of the root window, for status bars
+    --ignore-modifiers <list>      \ttranslate keys as if these modifiers weren't held, e.g. `mod3,mod5`
+    --event-filter <expr>          \tonly translate keys for which the expression holds, e.g.
+        `state & mod1 && detail != 36`. `detail` is the keycode, `state` the modifier mask and
+        modifier names stand for their bit. numbers, `( )`, `!`, `&`, `|`, `==`, `!=`, `<`, `<=`,
+        `>`, `>=`, `&&` and `||` work like in Rust, any value other than 0 is true
+    --send-as-core                 \tsend keys as synthetic core events, the default
+    --dry-run-keys                 \tgrab and translate the keys as usual but only print the original and
+        the translated (detail, state) to stderr instead of sending them. the grab keeps the
+        keys from the window, which gets none of them. unlike --dry-run this runs on the windows
+    --exclude-wm-frames            \tdon't match the frames a reparenting window manager puts around
+        clients. a frame is a top level window which isn't override-redirect, has no WM_CLASS
+        and has a child with a WM_CLASS. a window given with --window is always kept
+    --managed-only                 \tonly match the top level windows the window manager lists in
+        _NET_CLIENT_LIST instead of every window of the tree. without an EWMH window manager the
+        whole tree is searched as usual
+    --translate-dead-keys          \ttreat dead keys like the accents they type: --only-printable translates
+        them and a dead key the keymap can't type is left alone instead of sent as keycode 0.
+        the client composes it with the next key as usual
+    --translate-compose            \tcompose the translated keys with the Compose table of the locale, like
+        the input method of the window would, and send the composed character once a sequence is
+        complete. the keys of the sequence itself aren't sent. uses the compose support of libxkbcommon,
+        so mmk has to be built with the `xkbcommon` feature
+    --translate-buffer <n>         \tsend up to the given number of translated keys at once instead of one by
+        one, keys are never held back once no more are queued. helps with very fast typing
+        default: 1
+    --translate-only-when-grab-active
+                                   \ton a key make sure mmk still holds its grab on the window, at most once a
+        second per window. while another client holds it the window gets its keys anyway, so mmk
+        leaves them alone
+    --persist-state <file>         \tadd up the counts of translated and untouched keys and of every translated
+        keysym across runs in the given file, read on start and written on exit
+    --emit-metrics <file>          \tkeep the given file up to date with metrics in the prometheus text
+        format, for the textfile collector of node_exporter. it is rewritten within a second of a
+        metric changing
+    --socket <path>                \tlisten for commands on a unix socket at the given path, one per line:
+        `layout <index|name>` switches every window, `add-window <wid>` and `remove-window <wid>`
+        start and stop running on a window, `status` lists the windows and their layouts and
+        `metrics` answers with the metrics of --emit-metrics.
+        every command is answered with a line, or several for `status`, then an empty one
+    --capture-screenshot-on-match <dir>
+                                   \tsave what is visible of every matched window as <dir>/<wid>.ppm, to
+        check which window was matched. windows which aren't on screen are skipped
+    --report-unmapped-keysyms      \ton exit, list the keysyms which had no keycode in the keymap and
+        were left alone, i.e. what the target layout is missing
+    --json-schema                  \tprint a json schema of the --criteria-file format for editors and exit
+    --explain-key <keycode>        \tprint every step of translating a press of the given keycode and exit
+    --remember-layouts <file>      \tremember the layout used for each window class in the given file
+        a class without an explicit --layout gets its remembered layout
+    --detect-layout-change         \twatch for the layouts being changed with setxkbmap while running. windows
+        with a layout given by name follow it to its new index, otherwise a warning is printed
+  how to use:
+    1. set up two layouts you want to use using setxkbmap:
+        $ setxkbmap -layout dvorak,us
+    2. run with something specified
+        $ mmk --layout 1 --name MyWindow
+    3. the window should now receive the mimiced layout keys
+  how a key is translated, the first step that applies wins:
+    1. while paused by --pause-on-signal or --toggle-key, or the window is fullscreen with --suspend-while-fullscreen,
+       the key is sent unchanged
+    2. a --remap of the key's unshifted keysym, whichever layout the window uses
+    3. the keysym at the same position in the window's layout, looked up in --keymap-string if given,
+       at the level of the modifiers or of --translate-level
+    4. keys the X server's keymap can't type, or which --only-printable, --translate-dead-keys,
+       --event-filter, --min-keycode or --max-keycode rule out, are sent unchanged
+"
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Output {
+    #[default]
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown output format `{s}`, expected `text` or `json`"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum MatchMode {
+    #[default]
+    Snapshot,
+    Live,
+}
+
+// a --layout given by its index or by the name of the layout or its variant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LayoutSpec {
+    Index(usize),
+    Name(String),
+}
+
+impl std::str::FromStr for LayoutSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(index) => Ok(Self::Index(index)),
+            Err(_) if !s.is_empty() => Ok(Self::Name(s.to_string())),
+            Err(_) => Err("empty layout in --layout".to_string()),
+        }
+    }
+}
+
+// what --dedup-windows compares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DedupBy {
+    Pid,
+    Class,
+}
+
+impl std::str::FromStr for DedupBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pid" => Ok(Self::Pid),
+            "class" => Ok(Self::Class),
+            _ => Err(format!(
+                "unknown dedup key `{s}`, expected `pid` or `class`"
+            )),
+        }
+    }
+}
+
+// how the criteria of --combine are put together
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Combine {
+    // a window matching any criterion
+    #[default]
+    Or,
+    // only the windows matching every criterion
+    And,
+}
+
+impl std::str::FromStr for Combine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "or" => Ok(Self::Or),
+            "and" => Ok(Self::And),
+            _ => Err(format!("unknown combination `{s}`, expected `or` or `and`")),
+        }
+    }
+}
+
+// what --x-error-mode does about an error from the X server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum XErrorMode {
+    Strict,
+    #[default]
+    Lenient,
+}
+
+impl std::str::FromStr for XErrorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            _ => Err(format!(
+                "unknown x error mode `{s}`, expected `strict` or `lenient`"
+            )),
+        }
+    }
+}
+
+// how translated keys are handed to the window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum SendMode {
+    // a synthetic core event through SendEvent
+    #[default]
+    Core,
+    // nothing is sent, the translation is printed for --dry-run-keys
+    Print,
+}
+
+impl std::str::FromStr for MatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snapshot" => Ok(Self::Snapshot),
+            "live" => Ok(Self::Live),
+            _ => Err(format!(
+                "unknown match mode `{s}`, expected `snapshot` or `live`"
+            )),
+        }
+    }
+}
+
+/// Every setting of mmk, named after the command line options in `--help`.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub(crate) help: bool,
+    pub(crate) version: bool,
+    pub(crate) all_windows: bool,
+    pub(crate) layout: Vec<usize>,
+    // --layout auto-toggle
+    pub(crate) layout_toggle: bool,
+    pub(crate) wid: Option<u32>,
+    pub(crate) window_ids_file: Option<String>,
+    // read from the file in main, so bad lines can be warned about
+    pub(crate) window_ids: Option<Vec<u32>>,
+    pub(crate) class: Option<String>,
+    pub(crate) class_separator: Option<String>,
+    pub(crate) pid: Option<u32>,
+    pub(crate) name: Option<String>,
+    pub(crate) remember_layouts: Option<String>,
+    pub(crate) detect_layout_change: bool,
+    pub(crate) expand_children: bool,
+    pub(crate) dry_run: bool,
+    pub(crate) list_windows: bool,
+    pub(crate) test_window: bool,
+    pub(crate) select: bool,
+    pub(crate) follow_class: bool,
+    pub(crate) max_tracked: Option<usize>,
+    pub(crate) watch: bool,
+    pub(crate) require_focus: bool,
+    pub(crate) grab_combos: Vec<(Keysym, u16)>,
+    pub(crate) grab_keyboard: bool,
+    pub(crate) toggle_key: Option<(Keysym, u16)>,
+    pub(crate) list_atoms: Option<u32>,
+    pub(crate) debounce: Option<u32>,
+    pub(crate) criteria_file: Option<String>,
+    pub(crate) self_test: bool,
+    pub(crate) list_layouts: bool,
+    pub(crate) output: Output,
+    pub(crate) key_hook: Option<String>,
+    pub(crate) instance: Option<String>,
+    pub(crate) instance_regex: Option<Regex>,
+    pub(crate) role: Option<String>,
+    pub(crate) window_types: Vec<String>,
+    pub(crate) name_regex: Option<Regex>,
+    pub(crate) ignore_case: bool,
+    pub(crate) contains: bool,
+    pub(crate) match_mode: MatchMode,
+    pub(crate) remaps: Vec<(Keysym, Keysym)>,
+    pub(crate) record: Option<String>,
+    pub(crate) min_keycode: Option<u8>,
+    pub(crate) max_keycode: Option<u8>,
+    pub(crate) drain_before_grab: bool,
+    pub(crate) pointer_mode: Option<GrabMode>,
+    pub(crate) keyboard_mode: Option<GrabMode>,
+    pub(crate) verbose_matching: bool,
+    pub(crate) profile_startup: bool,
+    pub(crate) check_grab_conflicts: bool,
+    pub(crate) force: bool,
+    pub(crate) emit_to_focus_subtree: bool,
+    pub(crate) no_xwayland_warning: bool,
+    pub(crate) quiet: bool,
+    pub(crate) x_error_mode: XErrorMode,
+    pub(crate) group_lock: Option<u32>,
+    pub(crate) translate_level: Option<u32>,
+    pub(crate) explain_key: Option<u8>,
+    pub(crate) respect_numlock_state: bool,
+    pub(crate) match_cache: Option<String>,
+    pub(crate) match_cache_ttl: Option<u64>,
+    pub(crate) deliver_mask: Option<u32>,
+    pub(crate) reconnect: bool,
+    pub(crate) no_reconnect: bool,
+    pub(crate) only_printable: bool,
+    pub(crate) dump_config: bool,
+    pub(crate) keymap: Option<Keymap>,
+    pub(crate) pause_on_signal: bool,
+    pub(crate) switch_layout_on_signal: bool,
+    pub(crate) suspend_while_fullscreen: bool,
+    pub(crate) layout_indicator: bool,
+    pub(crate) layout_name_in_title: bool,
+    pub(crate) ignore_modifiers: u16,
+    pub(crate) event_filter: Option<EventFilter>,
+    pub(crate) send_mode: SendMode,
+    pub(crate) exclude_wm_frames: bool,
+    pub(crate) managed_only: bool,
+    pub(crate) translate_dead_keys: bool,
+    pub(crate) translate_compose: bool,
+    pub(crate) translate_buffer: Option<usize>,
+    pub(crate) json_schema: bool,
+    pub(crate) report_unmapped_keysyms: bool,
+    pub(crate) match_hook: Option<String>,
+    pub(crate) strict: bool,
+    pub(crate) layout_locale: Option<String>,
+    // layouts given by name, only known after asking the X server
+    pub(crate) layout_names: Vec<LayoutSpec>,
+    pub(crate) dedup_windows: Option<DedupBy>,
+    pub(crate) combine: Option<Combine>,
+    pub(crate) translate_only_when_grab_active: bool,
+    pub(crate) metrics: Option<String>,
+    pub(crate) socket: Option<String>,
+    pub(crate) screenshot_dir: Option<String>,
+    pub(crate) state_file: Option<String>,
+}
+
+impl Config {
+    /// Parses the command line options, such as those of [`std::env::args`]. Arguments which
+    /// aren't options, like the name of the program, are ignored.
+    pub fn from_args(input: Vec<String>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut ret = Self::default();
+        let input = split_args(input)?;
+        let mut iter = input.iter().peekable();
+
+        while let Some(value) = iter.next() {
+            match &value[..] {
+                "-w" | "--window" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_wid(parse_wid(next)?);
+                }
+                "-c" | "--class" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_class(next.to_string());
+                }
+                "--class-separator" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_class_separator(next.to_string());
+                }
+                "--window-ids-file" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_window_ids_file(next.to_string());
+                }
+                "--window-from-env" => {
+                    let next = flag_value(value, iter.peek())?;
+                    let value = std::env::var(next)
+                        .map_err(|_| format!("the environment variable `{next}` is not set"))?;
+                    let wid = parse_wid(value.trim())
+                        .map_err(|_| format!("`{value}` in `{next}` is not a window id"))?;
+                    ret = ret.with_wid(wid);
+                }
+                "--instance" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_instance(next.to_string());
+                }
+                "--instance-regex" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_instance_regex(Regex::new(next)?);
+                }
+                "--role" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_role(next.to_string());
+                }
+                "--window-type" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_window_types(parse_window_types(next)?);
+                }
+                "-p" | "--pid" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_pid(next.parse()?);
+                }
+                "-n" | "--name" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_name(next.to_string());
+                }
+                "--name-regex" => {
+                    let next = flag_value(value, iter.peek())?;
+                    let regex = Regex::new(next)
+                        .map_err(|e| format!("`{next}` given to `{value}` is no regex: {e}"))?;
+                    ret = ret.with_name_regex(regex);
+                }
+                "--ignore-case" => ret = ret.with_ignore_case(),
+                "--contains" => ret = ret.with_contains(),
+                "-h" | "--help" => ret = ret.with_help(),
+                "-V" | "--version" => ret = ret.with_version(),
+                "-l" | "--layout" if iter.peek().is_some_and(|next| *next == "auto-toggle") => {
+                    iter.next();
+                    ret = ret.with_layout_toggle();
+                }
+                "-l" | "--layout" => {
+                    let next = flag_value(value, iter.peek())?;
+                    let specs: Vec<LayoutSpec> =
+                        next.split(',').map(str::parse).collect::<Result<_, _>>()?;
+                    let indices: Option<Vec<usize>> = specs
+                        .iter()
+                        .map(|spec| match spec {
+                            LayoutSpec::Index(index) => Some(*index),
+                            LayoutSpec::Name(_) => None,
+                        })
+                        .collect();
+                    ret = match indices {
+                        Some(indices) => ret.with_layout(indices),
+                        None => ret.with_layout_names(specs),
+                    }
+                }
+                "--layout-by-locale" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_layout_locale(next.to_string());
+                }
+                "-a" | "--all" => ret = ret.with_all_windows(),
+                "--expand-children" => ret = ret.with_expand_children(),
+                "--combine" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_combine(next.parse()?);
+                }
+                "--dedup-windows" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_dedup_windows(next.parse()?);
+                }
+                "--dry-run" => ret = ret.with_dry_run(),
+                "--list-windows" => ret = ret.with_list_windows(),
+                "--test-window" => ret = ret.with_test_window(),
+                "--select" => ret = ret.with_select(),
+                "--follow-class" => ret = ret.with_follow_class(),
+                "--max-tracked" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_max_tracked(next.parse()?);
+                }
+                "--watch" => ret = ret.with_watch(),
+                "--require-focus" => ret = ret.with_require_focus(),
+                "--list-atoms" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_list_atoms(parse_wid(next)?);
+                }
+                "--debounce" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_debounce(next.parse()?);
+                }
+                "--criteria-file" | "--config" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_criteria_file(next.to_string());
+                }
+                "--self-test" => ret = ret.with_self_test(),
+                "--list-layouts" => ret = ret.with_list_layouts(),
+                "--output" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_output(next.parse()?);
+                }
+                "--key-hook" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_key_hook(next.to_string());
+                }
+                "--grab-on-map" => ret = ret.with_match_mode(MatchMode::Live),
+                "--match-mode" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_match_mode(next.parse()?);
+                }
+                "--remap" => {
+                    let next = flag_value(value, iter.peek())?;
+                    let (src, dst) = next
+                        .split_once('=')
+                        .ok_or_else(|| format!("expected `<src>=<dst>`, got `{next}`"))?;
+                    ret = ret.with_remap(parse_keysym(src)?, parse_keysym(dst)?);
+                }
+                "--translate-caps-to" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_remap(parse_keysym("Caps_Lock")?, parse_keysym(next)?);
+                }
+                "--record" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_record(next.to_string());
+                }
+                "--min-keycode" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_min_keycode(next.parse()?);
+                }
+                "--max-keycode" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_max_keycode(next.parse()?);
+                }
+                "--drain-before-grab" => ret = ret.with_drain_before_grab(),
+                "--pointer-mode" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_pointer_mode(parse_grab_mode(next)?);
+                }
+                "--keyboard-mode" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_keyboard_mode(parse_grab_mode(next)?);
+                }
+                "--verbose-matching" => ret = ret.with_verbose_matching(),
+                "--profile-startup" => ret = ret.with_profile_startup(),
+                "--check-grab-conflicts" => ret = ret.with_check_grab_conflicts(),
+                "--force" => ret = ret.with_force(),
+                "--emit-to-focus-subtree" => ret = ret.with_emit_to_focus_subtree(),
+                "--no-xwayland-warning" => ret = ret.with_no_xwayland_warning(),
+                "-q" | "--quiet" => ret = ret.with_quiet(),
+                "--x-error-mode" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_x_error_mode(next.parse()?);
+                }
+                "--strict" => ret = ret.with_strict(),
+                "--group-lock" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_group_lock(next.parse()?);
+                }
+                "--translate-level" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_translate_level(next.parse()?);
+                }
+                "--respect-numlock-state" => ret = ret.with_respect_numlock_state(),
+                "--match-cache" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_match_cache(next.to_string());
+                }
+                "--match-cache-ttl" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_match_cache_ttl(next.parse()?);
+                }
+                "--deliver-mask" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_deliver_mask(parse_event_mask(next)?);
+                }
+                "--only-printable" => ret = ret.with_only_printable(),
+                "--reconnect" => ret = ret.with_reconnect(),
+                "--no-reconnect" => ret = ret.with_no_reconnect(),
+                "--dump-config" => ret = ret.with_dump_config(),
+                "--pause-on-signal" => ret = ret.with_pause_on_signal(),
+                "--switch-layout-on-signal" => ret = ret.with_switch_layout_on_signal(),
+                "--suspend-while-fullscreen" => ret = ret.with_suspend_while_fullscreen(),
+                "--layout-indicator" => ret = ret.with_layout_indicator(),
+                "--layout-name-in-title" => ret = ret.with_layout_name_in_title(),
+                "--send-as-core" => ret = ret.with_send_mode(SendMode::Core),
+                "--dry-run-keys" => ret = ret.with_send_mode(SendMode::Print),
+                "--exclude-wm-frames" => ret = ret.with_exclude_wm_frames(),
+                "--managed-only" => ret = ret.with_managed_only(),
+                "--translate-dead-keys" => ret = ret.with_translate_dead_keys(),
+                "--translate-compose" => ret = ret.with_translate_compose(),
+                "--translate-buffer" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_translate_buffer(next.parse()?);
+                }
+                "--translate-only-when-grab-active" => {
+                    ret = ret.with_translate_only_when_grab_active()
+                }
+                "--json-schema" => ret = ret.with_json_schema(),
+                "--report-unmapped-keysyms" => ret = ret.with_report_unmapped_keysyms(),
+                "--persist-state" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_state_file(next.to_string());
+                }
+                "--emit-metrics" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_metrics(next.to_string());
+                }
+                "--socket" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_socket(next.to_string());
+                }
+                "--capture-screenshot-on-match" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_screenshot_dir(next.to_string());
+                }
+                "--output-on-match" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_match_hook(next.to_string());
+                }
+                "--ignore-modifiers" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_ignore_modifiers(parse_modifiers(next)?);
+                }
+                "--event-filter" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_event_filter(EventFilter::parse(next)?);
+                }
+                "--keymap-string" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_keymap(Keymap::compile(next)?);
+                }
+                "--explain-key" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_explain_key(next.parse()?);
+                }
+                "--grab-keyboard" => ret = ret.with_grab_keyboard(),
+                "--toggle-key" => {
+                    let next = flag_value(value, iter.peek())?;
+                    let [combo] = parse_combos(next)?[..] else {
+                        return Err("--toggle-key takes a single key combination".into());
+                    };
+                    ret = ret.with_toggle_key(combo);
+                }
+                "--grab-keysym-combos" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_grab_combos(parse_combos(next)?);
+                }
+                "--remember-layouts" => {
+                    let next = flag_value(value, iter.peek())?;
+                    ret = ret.with_remember_layouts(next.to_string());
+                }
+                "--detect-layout-change" => ret = ret.with_detect_layout_change(),
+                _ => (),
+            }
+        }
+
+        Ok(ret)
+    }
+
+    // a rule of --criteria-file with every other setting taken from the command line, which also
+    // fills in the layout of a rule without one and turns on --all, --expand-children,
+    // --exclude-wm-frames and --managed-only for every rule, and --dedup-windows and --combine
+    // for the rules without their own. RULE_FILE_KEYS has to list what is taken from the rule
+    pub(crate) fn merge_rule(&self, rule: &Config) -> Config {
+        let mut ret = self.clone();
+        ret.wid = rule.wid;
+        ret.window_ids_file = rule.window_ids_file.clone();
+        ret.window_ids = rule.window_ids.clone();
+        ret.class = rule.class.clone();
+        ret.instance = rule.instance.clone();
+        ret.instance_regex = rule.instance_regex.clone();
+        ret.role = rule.role.clone();
+        ret.window_types = rule.window_types.clone();
+        ret.pid = rule.pid;
+        ret.name = rule.name.clone();
+        ret.name_regex = rule.name_regex.clone();
+        ret.ignore_case = rule.ignore_case;
+        ret.contains = rule.contains;
+        if !rule.layout.is_empty() {
+            ret.layout = rule.layout.clone();
+            ret.layout_names = rule.layout_names.clone();
+            ret.layout_locale = rule.layout_locale.clone();
+        }
+        ret.all_windows |= rule.all_windows;
+        ret.expand_children |= rule.expand_children;
+        ret.exclude_wm_frames |= rule.exclude_wm_frames;
+        ret.managed_only |= rule.managed_only;
+        ret.dedup_windows = rule.dedup_windows.or(self.dedup_windows);
+        ret.combine = rule.combine.or(self.combine);
+
+        ret
+    }
+
+    pub(crate) fn with_wid(mut self, wid: u32) -> Self {
+        self.wid = Some(wid);
+        self
+    }
+    pub(crate) fn with_window_ids_file(mut self, path: String) -> Self {
+        self.window_ids_file = Some(path);
+        self
+    }
+    pub(crate) fn with_window_ids(mut self, ids: Vec<u32>) -> Self {
+        self.window_ids = Some(ids);
+        self
+    }
+    pub(crate) fn with_class(mut self, class: String) -> Self {
+        self.class = Some(class);
+        self
+    }
+    pub(crate) fn with_instance(mut self, instance: String) -> Self {
+        self.instance = Some(instance);
+        self
+    }
+    pub(crate) fn with_instance_regex(mut self, instance_regex: Regex) -> Self {
+        self.instance_regex = Some(instance_regex);
+        self
+    }
+    pub(crate) fn with_role(mut self, role: String) -> Self {
+        self.role = Some(role);
+        self
+    }
+    pub(crate) fn with_window_types(mut self, window_types: Vec<String>) -> Self {
+        self.window_types = window_types;
+        self
+    }
+    pub(crate) fn with_pid(mut self, pid: u32) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+    pub(crate) fn with_name(mut self, name: String) -> Self {
+        self.name = Some(name);
+        self
+    }
+    pub(crate) fn with_name_regex(mut self, name_regex: Regex) -> Self {
+        self.name_regex = Some(name_regex);
+        self
+    }
+    pub(crate) fn with_ignore_case(mut self) -> Self {
+        self.ignore_case = true;
+        self
+    }
+    pub(crate) fn with_contains(mut self) -> Self {
+        self.contains = true;
+        self
+    }
+    pub(crate) fn with_help(mut self) -> Self {
+        self.help = true;
+        self
+    }
+    pub(crate) fn with_version(mut self) -> Self {
+        self.version = true;
+        self
+    }
+    pub(crate) fn with_layout(mut self, layout: Vec<usize>) -> Self {
+        self.layout = layout;
+        self
+    }
+    pub(crate) fn with_layout_toggle(mut self) -> Self {
+        self.layout_toggle = true;
+        self
+    }
+    pub(crate) fn with_all_windows(mut self) -> Self {
+        self.all_windows = true;
+        self
+    }
+    pub(crate) fn with_remember_layouts(mut self, path: String) -> Self {
+        self.remember_layouts = Some(path);
+        self
+    }
+    pub(crate) fn with_detect_layout_change(mut self) -> Self {
+        self.detect_layout_change = true;
+        self
+    }
+    pub(crate) fn with_expand_children(mut self) -> Self {
+        self.expand_children = true;
+        self
+    }
+    pub(crate) fn with_dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+    pub(crate) fn with_list_windows(mut self) -> Self {
+        self.list_windows = true;
+        self
+    }
+    pub(crate) fn with_test_window(mut self) -> Self {
+        self.test_window = true;
+        self
+    }
+    pub(crate) fn with_select(mut self) -> Self {
+        self.select = true;
+        self
+    }
+    pub(crate) fn with_follow_class(mut self) -> Self {
+        self.follow_class = true;
+        self
+    }
+    pub(crate) fn with_max_tracked(mut self, max: usize) -> Self {
+        self.max_tracked = Some(max);
+        self
+    }
+    pub(crate) fn with_watch(mut self) -> Self {
+        self.watch = true;
+        self
+    }
+    pub(crate) fn with_require_focus(mut self) -> Self {
+        self.require_focus = true;
+        self
+    }
+    pub(crate) fn with_grab_combos(mut self, combos: Vec<(Keysym, u16)>) -> Self {
+        self.grab_combos = combos;
+        self
+    }
+    pub(crate) fn with_grab_keyboard(mut self) -> Self {
+        self.grab_keyboard = true;
+        self
+    }
+    pub(crate) fn with_toggle_key(mut self, combo: (Keysym, u16)) -> Self {
+        self.toggle_key = Some(combo);
+        self
+    }
+    pub(crate) fn with_list_atoms(mut self, wid: u32) -> Self {
+        self.list_atoms = Some(wid);
+        self
+    }
+    pub(crate) fn with_debounce(mut self, ms: u32) -> Self {
+        self.debounce = Some(ms);
+        self
+    }
+    pub(crate) fn with_criteria_file(mut self, path: String) -> Self {
+        self.criteria_file = Some(path);
+        self
+    }
+    pub(crate) fn with_self_test(mut self) -> Self {
+        self.self_test = true;
+        self
+    }
+    pub(crate) fn with_list_layouts(mut self) -> Self {
+        self.list_layouts = true;
+        self
+    }
+    pub(crate) fn with_output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+    pub(crate) fn with_key_hook(mut self, command: String) -> Self {
+        self.key_hook = Some(command);
+        self
+    }
+    pub(crate) fn with_match_mode(mut self, match_mode: MatchMode) -> Self {
+        self.match_mode = match_mode;
+        self
+    }
+    pub(crate) fn with_remap(mut self, src: Keysym, dst: Keysym) -> Self {
+        self.remaps.push((src, dst));
+        self
+    }
+    pub(crate) fn with_record(mut self, path: String) -> Self {
+        self.record = Some(path);
+        self
+    }
+    pub(crate) fn with_min_keycode(mut self, keycode: u8) -> Self {
+        self.min_keycode = Some(keycode);
+        self
+    }
+    pub(crate) fn with_max_keycode(mut self, keycode: u8) -> Self {
+        self.max_keycode = Some(keycode);
+        self
+    }
+    pub(crate) fn with_drain_before_grab(mut self) -> Self {
+        self.drain_before_grab = true;
+        self
+    }
+    pub(crate) fn with_pointer_mode(mut self, mode: GrabMode) -> Self {
+        self.pointer_mode = Some(mode);
+        self
+    }
+    pub(crate) fn with_keyboard_mode(mut self, mode: GrabMode) -> Self {
+        self.keyboard_mode = Some(mode);
+        self
+    }
+    pub(crate) fn with_verbose_matching(mut self) -> Self {
+        self.verbose_matching = true;
+        self
+    }
+    pub(crate) fn with_profile_startup(mut self) -> Self {
+        self.profile_startup = true;
+        self
+    }
+    pub(crate) fn with_check_grab_conflicts(mut self) -> Self {
+        self.check_grab_conflicts = true;
+        self
+    }
+    pub(crate) fn with_force(mut self) -> Self {
+        self.force = true;
+        self
+    }
+    pub(crate) fn with_emit_to_focus_subtree(mut self) -> Self {
+        self.emit_to_focus_subtree = true;
+        self
+    }
+    pub(crate) fn with_no_xwayland_warning(mut self) -> Self {
+        self.no_xwayland_warning = true;
+        self
+    }
+    pub(crate) fn with_quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
+    pub(crate) fn with_group_lock(mut self, group: u32) -> Self {
+        self.group_lock = Some(group);
+        self
+    }
+    pub(crate) fn with_translate_level(mut self, level: u32) -> Self {
+        self.translate_level = Some(level);
+        self
+    }
+    pub(crate) fn with_explain_key(mut self, keycode: u8) -> Self {
+        self.explain_key = Some(keycode);
+        self
+    }
+    pub(crate) fn with_respect_numlock_state(mut self) -> Self {
+        self.respect_numlock_state = true;
+        self
+    }
+    pub(crate) fn with_match_cache(mut self, path: String) -> Self {
+        self.match_cache = Some(path);
+        self
+    }
+    pub(crate) fn with_match_cache_ttl(mut self, seconds: u64) -> Self {
+        self.match_cache_ttl = Some(seconds);
+        self
+    }
+    pub(crate) fn with_deliver_mask(mut self, mask: u32) -> Self {
+        self.deliver_mask = Some(mask);
+        self
+    }
+    pub(crate) fn with_reconnect(mut self) -> Self {
+        self.reconnect = true;
+        self
+    }
+    pub(crate) fn with_no_reconnect(mut self) -> Self {
+        self.no_reconnect = true;
+        self
+    }
+    pub(crate) fn with_only_printable(mut self) -> Self {
+        self.only_printable = true;
+        self
+    }
+    pub(crate) fn with_dump_config(mut self) -> Self {
+        self.dump_config = true;
+        self
+    }
+    pub(crate) fn with_keymap(mut self, keymap: Keymap) -> Self {
+        self.keymap = Some(keymap);
+        self
+    }
+    pub(crate) fn with_pause_on_signal(mut self) -> Self {
+        self.pause_on_signal = true;
+        self
+    }
+    pub(crate) fn with_switch_layout_on_signal(mut self) -> Self {
+        self.switch_layout_on_signal = true;
+        self
+    }
+    pub(crate) fn with_suspend_while_fullscreen(mut self) -> Self {
+        self.suspend_while_fullscreen = true;
+        self
+    }
+    pub(crate) fn with_layout_indicator(mut self) -> Self {
+        self.layout_indicator = true;
+        self
+    }
+    pub(crate) fn with_layout_name_in_title(mut self) -> Self {
+        self.layout_name_in_title = true;
+        self
+    }
+    pub(crate) fn with_ignore_modifiers(mut self, mask: u16) -> Self {
+        self.ignore_modifiers = mask;
+        self
+    }
+    pub(crate) fn with_event_filter(mut self, filter: EventFilter) -> Self {
+        self.event_filter = Some(filter);
+        self
+    }
+    pub(crate) fn with_send_mode(mut self, send_mode: SendMode) -> Self {
+        self.send_mode = send_mode;
+        self
+    }
+    pub(crate) fn with_exclude_wm_frames(mut self) -> Self {
+        self.exclude_wm_frames = true;
+        self
+    }
+    pub(crate) fn with_managed_only(mut self) -> Self {
+        self.managed_only = true;
+        self
+    }
+    pub(crate) fn with_translate_dead_keys(mut self) -> Self {
+        self.translate_dead_keys = true;
+        self
+    }
+    pub(crate) fn with_translate_compose(mut self) -> Self {
+        self.translate_compose = true;
+        self
+    }
+    pub(crate) fn with_translate_buffer(mut self, size: usize) -> Self {
+        self.translate_buffer = Some(size);
+        self
+    }
+    pub(crate) fn with_json_schema(mut self) -> Self {
+        self.json_schema = true;
+        self
+    }
+    pub(crate) fn with_report_unmapped_keysyms(mut self) -> Self {
+        self.report_unmapped_keysyms = true;
+        self
+    }
+    pub(crate) fn with_match_hook(mut self, command: String) -> Self {
+        self.match_hook = Some(command);
+        self
+    }
+    pub(crate) fn with_strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+    pub(crate) fn with_class_separator(mut self, separator: String) -> Self {
+        self.class_separator = Some(separator);
+        self
+    }
+    pub(crate) fn with_layout_names(mut self, specs: Vec<LayoutSpec>) -> Self {
+        self.layout_names = specs;
+        self
+    }
+    pub(crate) fn with_layout_locale(mut self, lang: String) -> Self {
+        self.layout_locale = Some(lang);
+        self
+    }
+    pub(crate) fn with_dedup_windows(mut self, by: DedupBy) -> Self {
+        self.dedup_windows = Some(by);
+        self
+    }
+    pub(crate) fn with_x_error_mode(mut self, mode: XErrorMode) -> Self {
+        self.x_error_mode = mode;
+        self
+    }
+    pub(crate) fn with_combine(mut self, combine: Combine) -> Self {
+        self.combine = Some(combine);
+        self
+    }
+    pub(crate) fn with_translate_only_when_grab_active(mut self) -> Self {
+        self.translate_only_when_grab_active = true;
+        self
+    }
+    pub(crate) fn with_metrics(mut self, path: String) -> Self {
+        self.metrics = Some(path);
+        self
+    }
+    pub(crate) fn with_socket(mut self, path: String) -> Self {
+        self.socket = Some(path);
+        self
+    }
+    pub(crate) fn with_state_file(mut self, path: String) -> Self {
+        self.state_file = Some(path);
+        self
+    }
+    pub(crate) fn with_screenshot_dir(mut self, dir: String) -> Self {
+        self.screenshot_dir = Some(dir);
+        self
+    }
+}
+
+// the value following a flag, which can't be missing or be the next flag
+pub(crate) fn flag_value<'a>(flag: &str, next: Option<&&'a String>) -> Result<&'a String, String> {
+    match next {
+        Some(next) if !next.starts_with('-') => Ok(next),
+        _ => Err(format!("`{flag}` requires a value")),
+    }
+}
+
+// the short options, the ones which take a value can only come last in a bundle
+pub(crate) const SHORT_OPTIONS: &str = "hVaq";
+
+pub(crate) const SHORT_VALUE_OPTIONS: &str = "wcpnl";
+
+// turns `--option=value` into `--option value` and `-abc` into `-a -b -c`
+pub(crate) fn split_args(input: Vec<String>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut ret = vec![];
+    for arg in input {
+        match arg.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => {
+                ret.extend([option.to_string(), value.to_string()]);
+            }
+            _ if arg.len() > 2 && arg.starts_with('-') && !arg.starts_with("--") => {
+                let bundle: Vec<char> = arg.chars().skip(1).collect();
+                for (i, option) in bundle.iter().enumerate() {
+                    if SHORT_VALUE_OPTIONS.contains(*option) {
+                        if i + 1 < bundle.len() {
+                            return Err(format!(
+                                "`-{option}` takes a value, so it has to come last in `{arg}`"
+                            )
+                            .into());
+                        }
+                    } else if !SHORT_OPTIONS.contains(*option) {
+                        return Err(format!("unknown option `-{option}` in `{arg}`").into());
+                    }
+                    ret.push(format!("-{option}"));
+                }
+            }
+            _ => ret.push(arg),
+        }
+    }
+
+    Ok(ret)
+}
+
+pub(crate) fn parse_grab_mode(input: &str) -> Result<GrabMode, String> {
+    match input {
+        "sync" => Ok(GrabMode::SYNC),
+        "async" => Ok(GrabMode::ASYNC),
+        _ => Err(format!(
+            "unknown grab mode `{input}`, expected `sync` or `async`"
+        )),
+    }
+}
+
+pub(crate) fn parse_wid(input: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    match input.strip_prefix("0x") {
+        Some(hex) => Ok(u32::from_str_radix(hex, 16)?),
+        None => Ok(input.parse()?),
+    }
+}
+
+pub(crate) fn modifier_mask(name: &str) -> Option<u16> {
+    let mask = match &name.to_lowercase()[..] {
+        "shift" => ModMask::SHIFT,
+        "lock" => ModMask::LOCK,
+        "control" | "ctrl" => ModMask::CONTROL,
+        "mod1" | "alt" => ModMask::M1,
+        "mod2" => ModMask::M2,
+        "mod3" => ModMask::M3,
+        "mod4" | "super" => ModMask::M4,
+        "mod5" => ModMask::M5,
+        _ => return None,
+    };
+
+    Some(mask.into())
+}
+
+pub(crate) fn parse_event_mask(input: &str) -> Result<u32, Box<dyn std::error::Error>> {
+    if input.starts_with(|c: char| c.is_ascii_digit()) {
+        return parse_wid(input);
+    }
+
+    let mut ret = 0u32;
+    for name in input.split(',') {
+        let mask = match &name.trim().to_lowercase()[..] {
+            "none" => EventMask::NO_EVENT,
+            "key-press" => EventMask::KEY_PRESS,
+            "key-release" => EventMask::KEY_RELEASE,
+            "button-press" => EventMask::BUTTON_PRESS,
+            "button-release" => EventMask::BUTTON_RELEASE,
+            "enter-window" => EventMask::ENTER_WINDOW,
+            "leave-window" => EventMask::LEAVE_WINDOW,
+            "pointer-motion" => EventMask::POINTER_MOTION,
+            "exposure" => EventMask::EXPOSURE,
+            "structure-notify" => EventMask::STRUCTURE_NOTIFY,
+            "focus-change" => EventMask::FOCUS_CHANGE,
+            "property-change" => EventMask::PROPERTY_CHANGE,
+            _ => return Err(format!("unknown event mask `{name}`").into()),
+        };
+        ret |= u32::from(mask);
+    }
+
+    Ok(ret)
+}
+
+pub(crate) fn parse_modifiers(input: &str) -> Result<u16, Box<dyn std::error::Error>> {
+    let mut ret = 0;
+    for name in input.split(',').map(str::trim) {
+        ret |= modifier_mask(name).ok_or_else(|| format!("unknown modifier `{name}`"))?;
+    }
+
+    Ok(ret)
+}
+
+pub(crate) fn parse_combos(input: &str) -> Result<Vec<(Keysym, u16)>, Box<dyn std::error::Error>> {
+    let mut ret = vec![];
+    for combo in input.split(',') {
+        // the key itself always comes last, e.g. `Control+Shift+c`
+        let mut parts: Vec<&str> = combo.split('+').map(str::trim).collect();
+        let key = parts.pop().unwrap_or_default();
+
+        let mut mods = 0;
+        for part in parts {
+            mods |= modifier_mask(part).ok_or_else(|| format!("unknown modifier `{part}`"))?;
+        }
+        ret.push((parse_keysym(key)?, mods));
+    }
+
+    Ok(ret)
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut ret = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+
+    ret
+}
+
+pub(crate) fn modifier_names(mask: u16) -> Vec<&'static str> {
+    [
+        "shift", "lock", "control", "mod1", "mod2", "mod3", "mod4", "mod5",
+    ]
+    .into_iter()
+    .filter(|name| modifier_mask(name).is_some_and(|bit| mask & bit != 0))
+    .collect()
+}
+
+// the other way around of parse_combos, e.g. `control+c`
+pub(crate) fn combo_name(keysym: Keysym, mods: u16) -> String {
+    let mut parts = modifier_names(mods);
+    let name = keysym_name(keysym);
+    parts.push(&name);
+    parts.join("+")
+}
+
+// the settings which differ from the defaults, as `key = value` pairs named after the long
+// options, the same format --criteria-file reads
+pub(crate) fn config_entries(config: &Config) -> Vec<(&'static str, String)> {
+    let mut ret = vec![];
+    let mut set = |key, value: Option<String>| {
+        if let Some(value) = value {
+            ret.push((key, value));
+        }
+    };
+    let flag = |value: bool| value.then(|| "true".to_string());
+    let string = |value: &Option<String>| value.as_deref().map(json_string);
+
+    set(
+        "window",
+        config.wid.map(|wid| json_string(&format!("0x{wid:08x}"))),
+    );
+    set("window-ids-file", string(&config.window_ids_file));
+    set("class", string(&config.class));
+    set("class-separator", string(&config.class_separator));
+    set("instance", string(&config.instance));
+    set(
+        "instance-regex",
+        config
+            .instance_regex
+            .as_ref()
+            .map(|regex| json_string(regex.as_str())),
+    );
+    set("role", string(&config.role));
+    set(
+        "window-type",
+        (!config.window_types.is_empty()).then(|| json_string(&config.window_types.join(","))),
+    );
+    set("pid", config.pid.map(|pid| pid.to_string()));
+    set("name", string(&config.name));
+    set(
+        "name-regex",
+        config
+            .name_regex
+            .as_ref()
+            .map(|regex| json_string(regex.as_str())),
+    );
+    set("ignore-case", flag(config.ignore_case));
+    set("contains", flag(config.contains));
+    set(
+        "layout",
+        if config.layout_toggle {
+            Some(json_string("auto-toggle"))
+        } else {
+            (!config.layout.is_empty()).then(|| {
+                let layouts: Vec<String> = config.layout.iter().map(usize::to_string).collect();
+                json_string(&layouts.join(","))
+            })
+        },
+    );
+    set("all", flag(config.all_windows));
+    set(
+        "combine",
+        config.combine.map(|combine| {
+            json_string(match combine {
+                Combine::Or => "or",
+                Combine::And => "and",
+            })
+        }),
+    );
+    set("expand-children", flag(config.expand_children));
+    set(
+        "dedup-windows",
+        config.dedup_windows.map(|by| {
+            json_string(match by {
+                DedupBy::Pid => "pid",
+                DedupBy::Class => "class",
+            })
+        }),
+    );
+    set("remember-layouts", string(&config.remember_layouts));
+    set("detect-layout-change", flag(config.detect_layout_change));
+    set("dry-run", flag(config.dry_run));
+    set("list-windows", flag(config.list_windows));
+    set("test-window", flag(config.test_window));
+    set("select", flag(config.select));
+    set("follow-class", flag(config.follow_class));
+    set("max-tracked", config.max_tracked.map(|max| max.to_string()));
+    set("watch", flag(config.watch));
+    set("require-focus", flag(config.require_focus));
+    set("grab-keyboard", flag(config.grab_keyboard));
+    set(
+        "grab-keysym-combos",
+        (!config.grab_combos.is_empty()).then(|| {
+            let combos: Vec<String> = config
+                .grab_combos
+                .iter()
+                .map(|(keysym, mods)| combo_name(*keysym, *mods))
+                .collect();
+            json_string(&combos.join(","))
+        }),
+    );
+    set(
+        "toggle-key",
+        config
+            .toggle_key
+            .map(|(keysym, mods)| json_string(&combo_name(keysym, mods))),
+    );
+    set("debounce", config.debounce.map(|ms| ms.to_string()));
+    set("criteria-file", string(&config.criteria_file));
+    set(
+        "output",
+        (config.output != Output::default()).then(|| json_string("json")),
+    );
+    set("key-hook", string(&config.key_hook));
+    set("output-on-match", string(&config.match_hook));
+    set(
+        "match-mode",
+        (config.match_mode != MatchMode::default()).then(|| json_string("live")),
+    );
+    for (src, dst) in config.remaps.iter() {
+        let remap = format!("{}={}", keysym_name(*src), keysym_name(*dst));
+        set("remap", Some(json_string(&remap)));
+    }
+    set("record", string(&config.record));
+    set(
+        "min-keycode",
+        config.min_keycode.map(|keycode| keycode.to_string()),
+    );
+    set(
+        "max-keycode",
+        config.max_keycode.map(|keycode| keycode.to_string()),
+    );
+    set("drain-before-grab", flag(config.drain_before_grab));
+    let grab_mode = |mode: Option<GrabMode>| {
+        mode.map(|mode| {
+            json_string(if mode == GrabMode::SYNC {
+                "sync"
+            } else {
+                "async"
+            })
+        })
+    };
+    set("pointer-mode", grab_mode(config.pointer_mode));
+    set("keyboard-mode", grab_mode(config.keyboard_mode));
+    set("verbose-matching", flag(config.verbose_matching));
+    set("profile-startup", flag(config.profile_startup));
+    set("check-grab-conflicts", flag(config.check_grab_conflicts));
+    set("force", flag(config.force));
+    set("emit-to-focus-subtree", flag(config.emit_to_focus_subtree));
+    set("no-xwayland-warning", flag(config.no_xwayland_warning));
+    set("quiet", flag(config.quiet));
+    set(
+        "x-error-mode",
+        Some(json_string(match config.x_error_mode {
+            XErrorMode::Strict => "strict",
+            XErrorMode::Lenient => "lenient",
+        })),
+    );
+    set("strict", flag(config.strict));
+    set(
+        "group-lock",
+        config.group_lock.map(|group| group.to_string()),
+    );
+    set(
+        "translate-level",
+        config.translate_level.map(|level| level.to_string()),
+    );
+    set("respect-numlock-state", flag(config.respect_numlock_state));
+    set("match-cache", string(&config.match_cache));
+    set(
+        "match-cache-ttl",
+        config.match_cache_ttl.map(|ttl| ttl.to_string()),
+    );
+    set(
+        "deliver-mask",
+        config.deliver_mask.map(|mask| mask.to_string()),
+    );
+    set("only-printable", flag(config.only_printable));
+    set("reconnect", flag(config.reconnect));
+    set("no-reconnect", flag(config.no_reconnect));
+    set("pause-on-signal", flag(config.pause_on_signal));
+    set(
+        "switch-layout-on-signal",
+        flag(config.switch_layout_on_signal),
+    );
+    set(
+        "suspend-while-fullscreen",
+        flag(config.suspend_while_fullscreen),
+    );
+    set("layout-indicator", flag(config.layout_indicator));
+    set("layout-name-in-title", flag(config.layout_name_in_title));
+    set("dry-run-keys", flag(config.send_mode == SendMode::Print));
+    set("exclude-wm-frames", flag(config.exclude_wm_frames));
+    set("managed-only", flag(config.managed_only));
+    set("translate-dead-keys", flag(config.translate_dead_keys));
+    set("translate-compose", flag(config.translate_compose));
+    set(
+        "translate-buffer",
+        config.translate_buffer.map(|size| size.to_string()),
+    );
+    set(
+        "report-unmapped-keysyms",
+        flag(config.report_unmapped_keysyms),
+    );
+    set("emit-metrics", string(&config.metrics));
+    set("socket", string(&config.socket));
+    set("persist-state", string(&config.state_file));
+    set(
+        "capture-screenshot-on-match",
+        string(&config.screenshot_dir),
+    );
+    set(
+        "translate-only-when-grab-active",
+        flag(config.translate_only_when_grab_active),
+    );
+    set(
+        "ignore-modifiers",
+        (config.ignore_modifiers != 0)
+            .then(|| json_string(&modifier_names(config.ignore_modifiers).join(","))),
+    );
+    set(
+        "event-filter",
+        config
+            .event_filter
+            .as_ref()
+            .map(|filter| json_string(&filter.source)),
+    );
+    set(
+        "keymap-string",
+        config
+            .keymap
+            .as_ref()
+            .map(|keymap| json_string(&keymap.source)),
+    );
+
+    ret
+}
+
+// the keys a [[rule]] of --criteria-file may set, the ones `Config::merge_rule` takes from it, with
+// their json types and allowed values. the rule files are checked against it and --json-schema is
+// printed from it
+pub(crate) const RULE_FILE_KEYS: [(&str, &[&str], &[&str]); 21] = [
+    ("window", &["integer", "string"], &[]),
+    ("window-ids-file", &["string"], &[]),
+    ("window-from-env", &["string"], &[]),
+    ("class", &["string"], &[]),
+    ("instance", &["string"], &[]),
+    ("instance-regex", &["string"], &[]),
+    ("role", &["string"], &[]),
+    ("window-type", &["string"], &[]),
+    ("pid", &["integer"], &[]),
+    ("name", &["string"], &[]),
+    ("name-regex", &["string"], &[]),
+    ("ignore-case", &["boolean"], &[]),
+    ("contains", &["boolean"], &[]),
+    ("layout", &["integer", "string"], &[]),
+    ("layout-by-locale", &["string"], &[]),
+    ("all", &["boolean"], &[]),
+    ("expand-children", &["boolean"], &[]),
+    ("dedup-windows", &["string"], &["pid", "class"]),
+    ("combine", &["string"], &["or", "and"]),
+    ("exclude-wm-frames", &["boolean"], &[]),
+    ("managed-only", &["boolean"], &[]),
+];
+
+// whether a [[rule]] may set `key`
+pub(crate) fn is_rule_key(key: &str) -> bool {
+    RULE_FILE_KEYS.iter().any(|(name, _, _)| *name == key)
+}
+
+// the schema of a rule file, the rules go in [[rule]] tables or a `rules = [...]` array
+pub(crate) fn json_schema() -> String {
+    let mut properties = vec![];
+    for (key, kinds, values) in RULE_FILE_KEYS {
+        let kinds: Vec<String> = kinds.iter().map(|kind| json_string(kind)).collect();
+        let mut property = match &kinds[..] {
+            [kind] => format!("\"type\": {kind}"),
+            kinds => format!("\"type\": [{}]", kinds.join(", ")),
+        };
+        if !values.is_empty() {
+            let values: Vec<String> = values.iter().map(|value| json_string(value)).collect();
+            property.push_str(&format!(", \"enum\": [{}]", values.join(", ")));
+        }
+        // `name_regex` is read as well as `name-regex`
+        let mut names = vec![key.to_string()];
+        if key.contains('-') {
+            names.push(key.replace('-', "_"));
+        }
+        for name in names {
+            properties.push(format!("        {}: {{ {property} }}", json_string(&name)));
+        }
+    }
+
+    let rules = "{ \"type\": \"array\", \"items\": { \"$ref\": \"#/$defs/rule\" } }";
+    [
+        "{".to_string(),
+        "  \"$schema\": \"https://json-schema.org/draft/2020-12/schema\",".to_string(),
+        "  \"title\": \"mmk criteria file\",".to_string(),
+        "  \"type\": \"object\",".to_string(),
+        "  \"properties\": {".to_string(),
+        format!("    \"rule\": {rules},"),
+        format!("    \"rules\": {rules}"),
+        "  },".to_string(),
+        "  \"additionalProperties\": false,".to_string(),
+        "  \"$defs\": {".to_string(),
+        "    \"rule\": {".to_string(),
+        "      \"type\": \"object\",".to_string(),
+        "      \"properties\": {".to_string(),
+        properties.join(",\n"),
+        "      },".to_string(),
+        "      \"additionalProperties\": false".to_string(),
+        "    }".to_string(),
+        "  }".to_string(),
+        "}".to_string(),
+    ]
+    .join("\n")
+}
+
+pub(crate) fn print_json_schema() {
+    println!("{}", json_schema());
+}
+
+pub(crate) fn dump_config(config: &Config, rules: &[Config]) {
+    print!("{}", config_dump(config, rules));
+}
+
+// the settings after merging the rules with the command line, the way --dump-config prints them
+fn config_dump(config: &Config, rules: &[Config]) -> String {
+    let mut ret = String::from("# command line\n");
+    for (key, value) in config_entries(config) {
+        ret.push_str(&format!("{key} = {value}\n"));
+    }
+
+    if config.criteria_file.is_none() {
+        return ret;
+    }
+    // only the settings a rule has of its own are printed, see `Config::merge_rule` for what the
+    // command line adds to them
+    for rule in rules {
+        ret.push_str("\n[[rule]]\n");
+        for (key, value) in config_entries(rule) {
+            if is_rule_key(key) {
+                ret.push_str(&format!("{key} = {value}\n"));
+            }
+        }
+    }
+
+    ret
+}
+
+// the _NET_WM_WINDOW_TYPE names without their prefix, like `dock` for _NET_WM_WINDOW_TYPE_DOCK
+pub(crate) const WINDOW_TYPES: [&str; 14] = [
+    "desktop",
+    "dock",
+    "toolbar",
+    "menu",
+    "utility",
+    "splash",
+    "dialog",
+    "dropdown_menu",
+    "popup_menu",
+    "tooltip",
+    "notification",
+    "combo",
+    "dnd",
+    "normal",
+];
+
+pub(crate) fn parse_window_types(input: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut ret = vec![];
+    for name in input.split(',') {
+        // `popup-menu` is as good as `popup_menu`
+        let name = name.trim().to_lowercase().replace('-', "_");
+        if !WINDOW_TYPES.contains(&&name[..]) {
+            return Err(format!(
+                "unknown window type `{name}`, the types are `{}`",
+                WINDOW_TYPES.join(", ")
+            )
+            .into());
+        }
+        ret.push(name);
+    }
+
+    Ok(ret)
+}
+
+pub(crate) fn load_criteria_file(path: &str) -> Result<Vec<Config>, Box<dyn std::error::Error>> {
+    parse_criteria(path, &std::fs::read_to_string(path)?)
+}
+
+// the rules of a rule file, `path` only names it in errors
+pub(crate) fn parse_criteria(
+    path: &str,
+    contents: &str,
+) -> Result<Vec<Config>, Box<dyn std::error::Error>> {
+    let rules = RuleFileParser {
+        path,
+        chars: contents.chars().collect(),
+        pos: 0,
+        line: 1,
+    }
+    .parse()?;
+
+    rules.into_iter().map(Config::from_args).collect()
+}
+
+// a value in a rule file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RuleValue {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+}
+
+impl RuleValue {
+    // the json type of the value, like in RULE_FILE_KEYS
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::String(_) => "string",
+            Self::Integer(_) => "integer",
+            Self::Boolean(_) => "boolean",
+        }
+    }
+}
+
+// reads the part of TOML a rule file is written in: [[rule]] tables, or a `rules = [...]` array of
+// inline tables before the first of them. keys are bare, values are "basic strings" with the
+// escapes of TOML, 'literal strings', decimal or 0x integers and true or false. `#` starts a
+// comment outside of a string. the array may span lines, an inline table has to fit on one
+struct RuleFileParser<'a> {
+    path: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl RuleFileParser<'_> {
+    // every rule as the command line arguments it stands for
+    fn parse(mut self) -> Result<Vec<Vec<String>>, Box<dyn std::error::Error>> {
+        let mut rules: Vec<Vec<String>> = vec![];
+        let mut in_table = false;
+        loop {
+            self.skip_space(true);
+            match self.peek() {
+                None => return Ok(rules),
+                Some('[') => {
+                    self.expect("[[")?;
+                    self.skip_space(false);
+                    let name = self.key()?;
+                    self.skip_space(false);
+                    self.expect("]]")?;
+                    if name != "rule" {
+                        return Err(self.error(format!("unknown table `[[{name}]]`")));
+                    }
+                    self.end_of_line()?;
+                    rules.push(vec![]);
+                    in_table = true;
+                }
+                Some(_) => {
+                    let key = self.key()?;
+                    self.skip_space(false);
+                    self.expect("=")?;
+                    self.skip_space(false);
+                    if self.peek() == Some('[') {
+                        if key != "rules" || in_table {
+                            return Err(self.error(format!(
+                                "`{key}` is an array, only a `rules` array before the first [[rule]] is read"
+                            )));
+                        }
+                        self.pos += 1;
+                        self.rules_array(&mut rules)?;
+                    } else {
+                        let value = self.value()?;
+                        let line = self.line;
+                        let rule = match rules.last_mut() {
+                            Some(rule) if in_table => rule,
+                            _ => {
+                                return Err(self.error(format!("`{key}` is outside of a [[rule]]")))
+                            }
+                        };
+                        push_rule_entry(rule, &key, &value)
+                            .map_err(|e| format!("{}:{line}: {e}", self.path))?;
+                    }
+                    self.end_of_line()?;
+                }
+            }
+        }
+    }
+
+    // the inline tables of `rules = [...]`, after the `[`
+    fn rules_array(
+        &mut self,
+        rules: &mut Vec<Vec<String>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            self.skip_space(true);
+            if self.peek() == Some(']') {
+                self.pos += 1;
+                return Ok(());
+            }
+            rules.push(self.inline_table()?);
+            self.skip_space(true);
+            match self.bump() {
+                Some(',') => (),
+                Some(']') => return Ok(()),
+                _ => return Err(self.error("expected `,` or `]` after a rule".into())),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        self.expect("{")?;
+        let mut rule = vec![];
+        self.skip_space(false);
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(rule);
+        }
+        loop {
+            self.skip_space(false);
+            let key = self.key()?;
+            self.skip_space(false);
+            self.expect("=")?;
+            self.skip_space(false);
+            let value = self.value()?;
+            push_rule_entry(&mut rule, &key, &value).map_err(|e| self.error(e.to_string()))?;
+            self.skip_space(false);
+            match self.bump() {
+                Some(',') => (),
+                Some('}') => return Ok(rule),
+                _ => return Err(self.error("expected `,` or `}` in a rule".into())),
+            }
+        }
+    }
+
+    fn key(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+        let key = self.word();
+        if key.is_empty() {
+            return Err(self.error("expected a key".into()));
+        }
+        Ok(key)
+    }
+
+    fn value(&mut self) -> Result<RuleValue, Box<dyn std::error::Error>> {
+        match self.peek() {
+            Some('"') => {
+                self.pos += 1;
+                self.basic_string().map(RuleValue::String)
+            }
+            Some('\'') => {
+                self.pos += 1;
+                let mut ret = String::new();
+                loop {
+                    match self.bump() {
+                        Some('\'') => return Ok(RuleValue::String(ret)),
+                        Some('\n') | None => return Err(self.error("unterminated string".into())),
+                        Some(c) => ret.push(c),
+                    }
+                }
+            }
+            _ => {
+                let word = self.word();
+                let number = word.replace('_', "");
+                let integer = match number.strip_prefix("0x") {
+                    Some(hex) => i64::from_str_radix(hex, 16).ok(),
+                    None => number.parse().ok(),
+                };
+                match (&word[..], integer) {
+                    ("true", _) => Ok(RuleValue::Boolean(true)),
+                    ("false", _) => Ok(RuleValue::Boolean(false)),
+                    (_, Some(integer)) => Ok(RuleValue::Integer(integer)),
+                    _ => Err(self.error(format!("`{word}` isn't a string, integer or boolean"))),
+                }
+            }
+        }
+    }
+
+    // a "basic string" after its opening quote
+    fn basic_string(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut ret = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(ret),
+                Some('\\') => {
+                    let escaped = match self.bump() {
+                        Some('b') => '\u{8}',
+                        Some('t') => '\t',
+                        Some('n') => '\n',
+                        Some('f') => '\u{c}',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some(c @ ('u' | 'U')) => {
+                            let len = if c == 'u' { 4 } else { 8 };
+                            let digits: String =
+                                self.chars.iter().skip(self.pos).take(len).collect();
+                            self.pos += digits.chars().count();
+                            u32::from_str_radix(&digits, 16)
+                                .ok()
+                                .filter(|_| digits.len() == len)
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| {
+                                    self.error(format!("`\\{c}{digits}` isn't a character"))
+                                })?
+                        }
+                        c => {
+                            let c = c.map(String::from).unwrap_or_default();
+                            return Err(self.error(format!("unknown escape `\\{c}`")));
+                        }
+                    };
+                    ret.push(escaped);
+                }
+                Some('\n') | None => return Err(self.error("unterminated string".into())),
+                Some(c) => ret.push(c),
+            }
+        }
+    }
+
+    // a bare key, or an integer or boolean
+    fn word(&mut self) -> String {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+'))
+        {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    // spaces and comments, and with `newlines` empty lines
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '\n' if newlines => {
+                    self.pos += 1;
+                    self.line += 1;
+                }
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.skip_space(false);
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some(c) => Err(self.error(format!("unexpected `{c}` after the value"))),
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), Box<dyn std::error::Error>> {
+        for c in expected.chars() {
+            if self.peek() != Some(c) {
+                return Err(self.error(format!("expected `{expected}`")));
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    // the next character. values and inline tables end before a newline, skip_space counts them
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn error(&self, message: String) -> Box<dyn std::error::Error> {
+        format!("{}:{}: {message}", self.path, self.line).into()
+    }
+}
+
+// a `key = value` of a rule as the command line arguments it stands for, if RULE_FILE_KEYS has
+// the key with the type of the value
+pub(crate) fn push_rule_entry(
+    rule: &mut Vec<String>,
+    key: &str,
+    value: &RuleValue,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // `name_regex` is as good as `name-regex`
+    let key = key.replace('_', "-");
+    let (_, kinds, values) = RULE_FILE_KEYS
+        .iter()
+        .find(|(name, _, _)| *name == key)
+        .ok_or_else(|| {
+            format!("unknown key `{key}`, a rule only picks windows and the layout to use")
+        })?;
+    let kind = value.kind();
+    if !kinds.contains(&kind) {
+        return Err(format!("`{key}` takes {}, not {kind}", kinds.join(" or ")).into());
+    }
+
+    match value {
+        RuleValue::Boolean(true) => rule.push(format!("--{key}")),
+        RuleValue::Boolean(false) => (),
+        RuleValue::Integer(n) => {
+            rule.push(format!("--{key}"));
+            rule.push(n.to_string());
+        }
+        RuleValue::String(s) => {
+            if !values.is_empty() && !values.contains(&&s[..]) {
+                return Err(
+                    format!("`{key}` is one of `{}`, not `{s}`", values.join("`, `")).into(),
+                );
+            }
+            rule.push(format!("--{key}"));
+            rule.push(s.clone());
+        }
+    }
+    Ok(())
+}
+
+// ~/.config/mmk/rules.toml, or the same below $XDG_CONFIG_HOME, if it exists
+pub(crate) fn default_rules_file() -> Option<String> {
+    let config_home = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => dir,
+        _ => format!("{}/.config", std::env::var("HOME").ok()?),
+    };
+    let path = format!("{config_home}/mmk/rules.toml");
+
+    std::path::Path::new(&path).is_file().then_some(path)
+}
+
+// whether the command line picks any windows on its own
+pub(crate) fn has_criteria(config: &Config) -> bool {
+    config.wid.is_some()
+        || config.window_ids_file.is_some()
+        || config.class.is_some()
+        || config.instance.is_some()
+        || config.instance_regex.is_some()
+        || config.role.is_some()
+        || !config.window_types.is_empty()
+        || config.pid.is_some()
+        || config.name.is_some()
+        || config.name_regex.is_some()
+        || config.follow_class
+        || config.test_window
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rule_files_follow_the_schema() {
+        let schema = json_schema();
+        assert!(schema.contains("\"rules\": { \"type\": \"array\""));
+        assert!(schema.contains("\"layout\": { \"type\": [\"integer\", \"string\"] }"));
+        assert!(
+            schema.contains("\"combine\": { \"type\": \"string\", \"enum\": [\"or\", \"and\"] }")
+        );
+        // the settings of the whole run have no place in a rule
+        assert!(!schema.contains("\"debounce\""));
+
+        // a sample of both forms, with the keys and values the schema allows
+        let rules = parse_criteria(
+            "rules.toml",
+            r#"
+[[rule]]
+class = "Emacs"
+layout = 1
+all = true
+
+[[rule]]
+name_regex = ".*vim.*"
+layout = "us"
+combine = "and"
+"#,
+        )
+        .unwrap();
+        assert_eq!(rules[0].class.as_deref(), Some("Emacs"));
+        assert_eq!(rules[0].layout, [1]);
+        assert!(rules[0].all_windows);
+        assert_eq!(rules[1].layout_names, [LayoutSpec::Name("us".into())]);
+        assert_eq!(rules[1].combine, Some(Combine::And));
+        let rules = parse_criteria(
+            "rules.toml",
+            "rules = [\n  { class = \"Emacs\", layout = 1 },\n  { pid = 42 },\n]\n",
+        )
+        .unwrap();
+        assert_eq!(rules[1].pid, Some(42));
+
+        // what the schema rejects doesn't load either
+        for (line, error) in [
+            ("colour = \"red\"", "unknown key `colour`"),
+            (
+                "layout = true",
+                "`layout` takes integer or string, not boolean",
+            ),
+            ("all = \"yes\"", "`all` takes boolean, not string"),
+            ("combine = \"sometimes\"", "`combine` is one of"),
+            ("debounce = 50", "unknown key `debounce`"),
+        ] {
+            let err = parse_criteria("rules.toml", &format!("[[rule]]\n{line}\n")).unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with(&format!("rules.toml:2: {error}")),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn rule_files_read_strings_comments_and_arrays() {
+        let rules = parse_criteria(
+            "rules.toml",
+            r#"
+# a comment
+rules = [ # the rules as inline tables
+    { name = "say \"hi\" # not a comment", layout = 1 },
+
+    { class = 'C:\path', pid = 0x2a }, # a trailing comma and a comment
+]
+
+[[rule]]   # a table after the array
+name-regex = "tab\tand \u00e9" # inline comment
+all = false
+"#,
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].name.as_deref(), Some("say \"hi\" # not a comment"));
+        assert_eq!(rules[1].class.as_deref(), Some("C:\\path"));
+        assert_eq!(rules[1].pid, Some(42));
+        assert_eq!(
+            rules[2].name_regex.as_ref().map(|regex| regex.as_str()),
+            Some("tab\tand \u{e9}")
+        );
+        assert!(!rules[2].all_windows);
+
+        for (contents, error) in [
+            (
+                "[[rule]]\nlayout = [1, 2]\n",
+                "rules.toml:2: `layout` is an array",
+            ),
+            (
+                "[[rule]]\nrules = [\n]\n",
+                "rules.toml:2: `rules` is an array",
+            ),
+            (
+                "class = \"x\"\n",
+                "rules.toml:1: `class` is outside of a [[rule]]",
+            ),
+            (
+                "[[rule]]\nclass = \"x\n",
+                "rules.toml:2: unterminated string",
+            ),
+            (
+                "[[rule]]\nclass = \"\\q\"\n",
+                "rules.toml:2: unknown escape `\\q`",
+            ),
+            (
+                "[[rule]]\nclass = \"x\" pid = 1\n",
+                "rules.toml:2: unexpected `p`",
+            ),
+            ("[[rules]]\n", "rules.toml:1: unknown table `[[rules]]`"),
+            (
+                "rules = [\n{ pid = 1 }\n{ pid = 2 }]\n",
+                "rules.toml:3: expected `,` or `]`",
+            ),
+            ("rules = [{ pid = 1\n", "rules.toml:1: expected `,` or `}`"),
+            ("[[rule]]\npid = 1x\n", "rules.toml:2: `1x` isn't a string"),
+        ] {
+            let err = parse_criteria("rules.toml", contents).unwrap_err();
+            assert!(err.to_string().starts_with(error), "{contents:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn dumped_rules_read_back() {
+        let rule = Config::from_args(
+            [
+                "mmk",
+                "--class",
+                "a \"quoted\" \\ class",
+                "--name",
+                "tab\tname",
+                "--pid",
+                "7",
+            ]
+            .map(String::from)
+            .to_vec(),
+        )
+        .unwrap();
+        let mut contents = String::from("[[rule]]\n");
+        for (key, value) in config_entries(&rule) {
+            if is_rule_key(key) {
+                contents.push_str(&format!("{key} = {value}\n"));
+            }
+        }
+
+        let read = &parse_criteria("rules.toml", &contents).unwrap()[0];
+        assert_eq!(read.class, rule.class);
+        assert_eq!(read.name, rule.name);
+        assert_eq!(read.pid, rule.pid);
+    }
+
+    #[test]
+    fn dumped_config_has_the_command_line_over_the_rules() {
+        let contents = "[[rule]]\n\
+                        class = \"X\"\n\
+                        [[rule]]\n\
+                        name = \"Y\"\n\
+                        layout = 1\n";
+        let config = Config::from_args(
+            [
+                "mmk",
+                "--layout",
+                "2",
+                "--debounce",
+                "10",
+                "--all",
+                "--criteria-file",
+                "rules.toml",
+            ]
+            .map(String::from)
+            .to_vec(),
+        )
+        .unwrap();
+        let rules: Vec<Config> = parse_criteria("rules.toml", contents)
+            .unwrap()
+            .iter()
+            .map(|rule| config.merge_rule(rule))
+            .collect();
+
+        // the rules run with --debounce of the command line, the second keeps its own layout
+        assert_eq!(rules[0].debounce, Some(10));
+        assert_eq!(rules[0].layout, [2]);
+        assert_eq!(rules[1].layout, [1]);
+        let dump = config_dump(&config, &rules);
+        let (command_line, rules) = dump.split_once("\n\n[[rule]]\n").unwrap();
+        assert!(command_line.starts_with("# command line\n"));
+        assert!(command_line.contains("\ndebounce = 10\n"));
+        let rules: Vec<&str> = rules.split("\n[[rule]]\n").collect();
+        assert_eq!(
+            rules,
+            [
+                "class = \"X\"\nlayout = \"2\"\nall = true\n",
+                "name = \"Y\"\nlayout = \"1\"\nall = true\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn window_ids_are_read_from_the_environment() {
+        let wid = |var: &str| {
+            let args = ["mmk", "--window-from-env", var];
+            Config::from_args(args.map(String::from).to_vec()).map(|config| config.wid)
+        };
+        // names of its own, the tests run in threads of one process
+        std::env::set_var("TEST_HEX_TARGET_WINDOW", "0x3a00007");
+        std::env::set_var("TEST_DECIMAL_TARGET_WINDOW", " 60817415\n");
+        std::env::set_var("TEST_BAD_TARGET_WINDOW", "firefox");
+
+        assert_eq!(wid("TEST_HEX_TARGET_WINDOW").unwrap(), Some(0x3a00007));
+        assert_eq!(wid("TEST_DECIMAL_TARGET_WINDOW").unwrap(), Some(60817415));
+        assert_eq!(
+            wid("TEST_BAD_TARGET_WINDOW").unwrap_err().to_string(),
+            "`firefox` in `TEST_BAD_TARGET_WINDOW` is not a window id"
+        );
+        assert_eq!(
+            wid("TEST_UNSET_TARGET_WINDOW").unwrap_err().to_string(),
+            "the environment variable `TEST_UNSET_TARGET_WINDOW` is not set"
+        );
+    }
+
+    #[test]
+    fn combos_are_modifiers_and_a_keysym() {
+        let (control, shift, super_) = (
+            u16::from(ModMask::CONTROL),
+            u16::from(ModMask::SHIFT),
+            u16::from(ModMask::M4),
+        );
+        assert_eq!(
+            parse_combos("Control+c,Super+space").unwrap(),
+            [(0x63, control), (0x20, super_)]
+        );
+        assert_eq!(
+            parse_combos("ctrl + Shift + Return, F1").unwrap(),
+            [(0xff0d, control | shift), (0xffbe, 0)]
+        );
+
+        assert_eq!(
+            parse_combos("Hyper+c").unwrap_err().to_string(),
+            "unknown modifier `Hyper`"
+        );
+        assert_eq!(
+            parse_combos("Control+nothing").unwrap_err().to_string(),
+            "unknown keysym `nothing`"
+        );
+    }
+}
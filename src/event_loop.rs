@@ -0,0 +1,1511 @@
+//! The event loop forwarding the keys, and the signals and socket talking to it.
+
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
+    fs::OpenOptions,
+    io::{BufRead, BufReader, BufWriter, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    process::{Child, Command},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use signal_hook::{
+    consts::{SIGINT, SIGTERM, SIGUSR1, SIGUSR2},
+    iterator::Signals,
+    low_level::emulate_default_handler,
+};
+use x11rb::{
+    connect,
+    connection::Connection,
+    errors::ReplyError,
+    protocol::{
+        xproto::{
+            AtomEnum, ChangeWindowAttributesAux, ClientMessageEvent, ConnectionExt,
+            CreateWindowAux, EventMask, KeyPressEvent, Keysym, WindowClass,
+        },
+        Event,
+    },
+    CURRENT_TIME, NONE,
+};
+
+use crate::{
+    config::{parse_wid, Config, LayoutSpec, MatchMode, SendMode, XErrorMode},
+    is_connection_lost, is_grab_taken,
+    keymap::{keysym_name, locked_modifiers, ServerKeymap},
+    layout::{
+        load_layout_memory, other_group, publish_layout, remap_layouts, resolve_layout,
+        resolve_layout_names, save_layout_memory, shifted_layout, xkb_layouts,
+    },
+    matching::{
+        find_targets, focus_subtree_target, follow_class, has_focus, is_fullscreen, window_class,
+        window_exists, window_names, window_pid, Atoms, MatchCache, Target,
+    },
+    profile_phase, skip_x_error,
+    translate::{is_bounce, lookup, outgoing_event, Compose, Delivery, Lookup, Translated},
+    warn, warn_or_fail,
+    window::{
+        capture_screenshot, events_to_allow, evict_windows, grab_check_due, grab_conflicts,
+        grab_held, grab_toggle_key, grab_window, refresh_title, release_window,
+        show_layout_in_title, Tracked,
+    },
+    GrabTaken,
+};
+
+// what the event loop shares with the threads of the signals and the --socket, along with the
+// counters of the whole run
+#[derive(Default)]
+pub(crate) struct Shared {
+    pub(crate) paused: AtomicBool,
+    pub(crate) translated_keys: AtomicU64,
+    pub(crate) untouched_keys: AtomicU64,
+    // how many layouts --switch-layout-on-signal moved forward so far, negative for backwards
+    pub(crate) layout_shift: AtomicI64,
+    pub(crate) started_at: u64,
+    // how many windows the event loop runs on, for --emit-metrics
+    pub(crate) grabbed_windows: AtomicUsize,
+    // how often each keysym was sent, for --persist-state
+    pub(crate) translated_keysyms: Mutex<BTreeMap<Keysym, u64>>,
+    // collected for --report-unmapped-keysyms
+    pub(crate) unmapped_keysyms: Mutex<BTreeSet<Keysym>>,
+    // an input only window of ours, a client message to it wakes up the event loop
+    pub(crate) wake_window: AtomicU32,
+    pub(crate) quit: AtomicBool,
+    // lines read from the --socket, waiting for the event loop along with where its answer goes
+    pub(crate) control_commands: Mutex<Vec<(String, Sender<String>)>>,
+}
+
+pub(crate) fn report_unmapped_keysyms(shared: &Shared) {
+    if let Some(report) = unmapped_keysyms_report(shared) {
+        eprint!("{report}");
+    }
+}
+
+// the keysyms --report-unmapped-keysyms lists on exit, `None` if every key could be typed
+fn unmapped_keysyms_report(shared: &Shared) -> Option<String> {
+    let unmapped = shared.unmapped_keysyms.lock().ok()?;
+    if unmapped.is_empty() {
+        return None;
+    }
+
+    let mut ret =
+        String::from("mmk: these keysyms have no keycode in the keymap and were left alone:\n");
+    for keysym in unmapped.iter() {
+        ret.push_str(&format!("    {} ({keysym:#x})\n", keysym_name(*keysym)));
+    }
+    Some(ret)
+}
+
+// collects the keysyms of the window layouts no key of the keymap types, for --report-unmapped-keysyms
+fn note_unmapped_keysym(config: &Config, shared: &Shared, trace: &Lookup) {
+    if config.report_unmapped_keysyms && trace.keycode == 0 && trace.layout_keysym != 0 {
+        if let Ok(mut unmapped) = shared.unmapped_keysyms.lock() {
+            unmapped.insert(trace.layout_keysym);
+        }
+    }
+}
+
+pub(crate) fn metrics(shared: &Shared, windows: usize) -> String {
+    let metrics = [
+        (
+            "mmk_keys_translated_total",
+            "counter",
+            "Keys sent with the layout of their window.",
+            shared.translated_keys.load(Ordering::Relaxed),
+        ),
+        (
+            "mmk_keys_untouched_total",
+            "counter",
+            "Keys left alone because they couldn't be translated.",
+            shared.untouched_keys.load(Ordering::Relaxed),
+        ),
+        (
+            "mmk_windows_grabbed",
+            "gauge",
+            "Windows whose keys are translated.",
+            windows as u64,
+        ),
+        (
+            "mmk_start_time_seconds",
+            "gauge",
+            "Start time of mmk since the unix epoch in seconds.",
+            shared.started_at,
+        ),
+    ];
+
+    let mut ret = String::new();
+    for (name, kind, help, value) in metrics {
+        ret.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        ));
+    }
+
+    ret
+}
+
+// how often --emit-metrics looks for metrics that changed
+const METRICS_INTERVAL: Duration = Duration::from_secs(1);
+
+// keeps the file of --emit-metrics up to date from a thread of its own, so no key waits for the
+// disk. a failed write is warned about once and tried again on the next round
+pub(crate) fn emit_metrics(path: &str, config: &Config, shared: &Arc<Shared>) {
+    // the config stays behind, the keymap of --keymap-string can't go to another thread
+    let (path, quiet, shared) = (path.to_string(), config.quiet, Arc::clone(shared));
+    std::thread::spawn(move || {
+        let mut written = None;
+        let mut failing = false;
+        loop {
+            match refresh_metrics(&path, &shared, &mut written) {
+                Ok(()) => failing = false,
+                Err(e) if !failing => {
+                    if !quiet {
+                        eprintln!("warning: Couldn't write the metrics to {path}: {e}");
+                    }
+                    failing = true;
+                }
+                Err(_) => (),
+            }
+            std::thread::sleep(METRICS_INTERVAL);
+        }
+    });
+}
+
+// writes the metrics unless they are still the `written` ones
+fn refresh_metrics(
+    path: &str,
+    shared: &Shared,
+    written: &mut Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let current = metrics(shared, shared.grabbed_windows.load(Ordering::Relaxed));
+    if written.as_ref() == Some(&current) {
+        return Ok(());
+    }
+    // collectors may read at any time, never let them see a half written file
+    let partial = format!("{path}.partial");
+    std::fs::write(&partial, &current)?;
+    std::fs::rename(&partial, path)?;
+    *written = Some(current);
+
+    Ok(())
+}
+
+pub(crate) fn handle_signals(
+    config: &Config,
+    shared: &Arc<Shared>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wanted = vec![];
+    if config.pause_on_signal || config.switch_layout_on_signal {
+        wanted.extend([SIGUSR1, SIGUSR2]);
+    }
+    let switch_layout = config.switch_layout_on_signal;
+    // the grabbed windows have to be given back before the default handler kills the process
+    wanted.extend([SIGINT, SIGTERM]);
+    let report = config.report_unmapped_keysyms && !config.quiet;
+
+    let mut signals = Signals::new(wanted)?;
+    let shared = Arc::clone(shared);
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGINT | SIGTERM => {
+                    shared.quit.store(true, Ordering::Relaxed);
+                    // only the connection that grabbed the windows can restore them, wake it up
+                    if wake_event_loop(&shared).is_err() {
+                        if report {
+                            report_unmapped_keysyms(&shared);
+                        }
+                        // dies of the signal the way it would have without the handler
+                        let _ = emulate_default_handler(signal);
+                    }
+                }
+                SIGUSR1 | SIGUSR2 if switch_layout => {
+                    shared
+                        .layout_shift
+                        .fetch_add(if signal == SIGUSR1 { 1 } else { -1 }, Ordering::Relaxed);
+                    // before the grab there is nothing to wake, the first round of the loop applies it
+                    let _ = wake_event_loop(&shared);
+                }
+                SIGUSR1 | SIGUSR2 => eprintln!("{}", pause_signal(&shared, signal)),
+                _ => (),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// --pause-on-signal, SIGUSR1 flips whether translation is paused and SIGUSR2 only reports.
+// returns the line telling what happened
+fn pause_signal(shared: &Shared, signal: i32) -> String {
+    if signal == SIGUSR1 {
+        let paused = !shared.paused.fetch_xor(true, Ordering::Relaxed);
+        return format!(
+            "mmk: translation {}",
+            if paused { "paused" } else { "resumed" }
+        );
+    }
+
+    format!(
+        "mmk: translation {}, {} keys translated, {} left alone",
+        if shared.paused.load(Ordering::Relaxed) {
+            "paused"
+        } else {
+            "running"
+        },
+        shared.translated_keys.load(Ordering::Relaxed),
+        shared.untouched_keys.load(Ordering::Relaxed),
+    )
+}
+
+// keys pressed while this many hooks are still running don't start another one
+pub(crate) const MAX_RUNNING_HOOKS: usize = 8;
+
+pub(crate) fn wake_event_loop(shared: &Shared) -> Result<(), Box<dyn std::error::Error>> {
+    let window = shared.wake_window.load(Ordering::Relaxed);
+    if window == 0 {
+        return Err("nothing is grabbed yet".into());
+    }
+    let (conn, _) = connect(None)?;
+    let event = ClientMessageEvent::new(32, window, AtomEnum::NONE, [0u32; 5]);
+    conn.send_event(false, window, EventMask::NO_EVENT, event)?;
+    conn.flush()?;
+    Ok(())
+}
+
+pub(crate) fn listen_on_socket(
+    path: &str,
+    shared: &Arc<Shared>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // a socket left behind by a previous run would fail the bind
+    if std::fs::metadata(path).is_ok() && UnixStream::connect(path).is_err() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let shared = Arc::clone(shared);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || serve_socket_client(stream, &shared));
+        }
+    });
+
+    Ok(())
+}
+
+// only the event loop may touch the grabbed windows, so every command is handed to it
+pub(crate) fn serve_socket_client(mut stream: UnixStream, shared: &Shared) {
+    let Ok(reader) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(reader).lines().map_while(Result::ok) {
+        let answer = if shared.wake_window.load(Ordering::Relaxed) == 0 {
+            "error: nothing is grabbed yet".to_string()
+        } else {
+            let (sender, receiver) = mpsc::channel();
+            if let Ok(mut commands) = shared.control_commands.lock() {
+                commands.push((line, sender));
+            }
+            let _ = wake_event_loop(shared);
+            receiver
+                .recv()
+                .unwrap_or_else(|_| "error: mmk is exiting".to_string())
+        };
+        if writeln!(stream, "{answer}\n").is_err() {
+            return;
+        }
+    }
+}
+
+// runs a line of --socket, the answer is sent back to the client
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn control_command(
+    conn: &impl Connection,
+    server_keymap: &ServerKeymap,
+    root: u32,
+    atoms: &Atoms,
+    config: &Config,
+    shared: &Shared,
+    line: &str,
+    tracked: &mut HashMap<u32, Tracked>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let (command, argument) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+    let argument = argument.trim();
+    match command {
+        "layout" => {
+            let spec: LayoutSpec = argument.parse()?;
+            let layout = resolve_layout_names(conn, root, atoms, &[spec])?[0];
+            for record in tracked.values_mut() {
+                record.layout = layout;
+            }
+            Ok("ok".to_string())
+        }
+        "add-window" => {
+            let window = parse_wid(argument)?;
+            if let Entry::Vacant(entry) = tracked.entry(window) {
+                let mut record = grab_window(conn, server_keymap, config, window)?;
+                record.layout = config.layout.first().copied().unwrap_or(0);
+                entry.insert(record);
+            }
+            Ok("ok".to_string())
+        }
+        "remove-window" => {
+            let window = parse_wid(argument)?;
+            let record = tracked
+                .remove(&window)
+                .ok_or_else(|| format!("not running on 0x{window:08x}"))?;
+            release_window(conn, Some(atoms), window, &record)?;
+            conn.flush()?;
+            Ok("ok".to_string())
+        }
+        "status" => {
+            let known = xkb_layouts(conn, root, atoms)?;
+            let mut windows: Vec<(u32, usize)> = tracked
+                .iter()
+                .map(|(window, record)| (*window, record.layout))
+                .collect();
+            windows.sort_unstable();
+            let lines: Vec<String> = windows
+                .into_iter()
+                .map(|(window, index)| {
+                    let name = known
+                        .iter()
+                        .find(|layout| layout.index == index)
+                        .map(|layout| layout.layout.as_str())
+                        .unwrap_or_default();
+                    format!("0x{window:08x} {index}:{name}")
+                })
+                .collect();
+            Ok(lines.join("\n"))
+        }
+        "metrics" => Ok(metrics(shared, tracked.len()).trim_end().to_string()),
+        _ => Err(format!("unknown command `{command}`").into()),
+    }
+}
+
+pub(crate) fn run_key_hook(
+    command: &str,
+    running: &mut Vec<Child>,
+    press: bool,
+    original: &KeyPressEvent,
+    translated: &Translated,
+) -> Result<(), Box<dyn std::error::Error>> {
+    running.retain_mut(|child| !matches!(child.try_wait(), Ok(Some(_))));
+    if running.len() >= MAX_RUNNING_HOOKS {
+        return Ok(());
+    }
+
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("MMK_EVENT", if press { "press" } else { "release" })
+        .env("MMK_WINDOW", format!("0x{:08x}", original.event))
+        .env("MMK_ORIGINAL_KEYCODE", original.detail.to_string())
+        .env("MMK_ORIGINAL_STATE", original.state.to_string())
+        .env("MMK_KEYCODE", translated.detail.to_string())
+        .env("MMK_STATE", translated.state.to_string())
+        .env("MMK_KEYSYM", keysym_name(translated.keysym))
+        .spawn()?;
+    running.push(child);
+
+    Ok(())
+}
+
+pub(crate) fn run_match_hook(
+    conn: &impl Connection,
+    atoms: &Atoms,
+    config: &Config,
+    command: &str,
+    running: &mut Vec<Child>,
+    window: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    running.retain_mut(|child| !matches!(child.try_wait(), Ok(Some(_))));
+    if running.len() >= MAX_RUNNING_HOOKS {
+        return Ok(());
+    }
+
+    let (net_name, name) = window_names(conn, window, atoms)?;
+    let class = window_class(conn, config, window)?.unwrap_or_default();
+    let pid = window_pid(conn, window, atoms)?;
+    let name = if net_name.is_empty() { name } else { net_name };
+    running.push(spawn_match_hook(command, window, &class, pid, &name)?);
+
+    Ok(())
+}
+
+fn spawn_match_hook(
+    command: &str,
+    window: u32,
+    class: &str,
+    pid: u32,
+    name: &str,
+) -> std::io::Result<Child> {
+    Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("MMK_WINDOW", format!("0x{window:08x}"))
+        .env("MMK_CLASS", class)
+        .env("MMK_PID", pid.to_string())
+        .env("MMK_NAME", name)
+        .spawn()
+}
+
+// discards the events `poll` has ready without blocking, returns how many there were
+fn drain_events<T, E>(mut poll: impl FnMut() -> Result<Option<T>, E>) -> Result<usize, E> {
+    let mut drained = 0;
+    while poll()?.is_some() {
+        drained += 1;
+    }
+
+    Ok(drained)
+}
+
+pub(crate) fn record_event(
+    record: &mut impl Write,
+    press: bool,
+    original: &KeyPressEvent,
+    sent: &KeyPressEvent,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    writeln!(
+        record,
+        "{{\"time\":{time},\"event\":\"{}\",\"window\":{},\"original_detail\":{},\"original_state\":{},\"detail\":{},\"state\":{}}}",
+        if press { "press" } else { "release" },
+        original.event,
+        original.detail,
+        original.state,
+        sent.detail,
+        sent.state
+    )?;
+
+    Ok(())
+}
+
+// adds the counters of earlier runs to the ones of this run
+pub(crate) fn load_state(path: &str, shared: &Shared) -> Result<(), Box<dyn std::error::Error>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut keysyms = shared.translated_keysyms.lock().unwrap();
+    for (number, line) in contents.lines().enumerate() {
+        // each line is `translated\t<count>`, `untouched\t<count>` or `<keysym>\t<count>`
+        let (key, count) = line
+            .split_once('\t')
+            .ok_or_else(|| format!("{path}:{}: expected `<key>\t<count>`", number + 1))?;
+        let count: u64 = count.trim().parse()?;
+        match key {
+            "translated" => {
+                shared.translated_keys.fetch_add(count, Ordering::Relaxed);
+            }
+            "untouched" => {
+                shared.untouched_keys.fetch_add(count, Ordering::Relaxed);
+            }
+            _ => {
+                let keysym = key.strip_prefix("0x").unwrap_or(key);
+                *keysyms
+                    .entry(Keysym::from_str_radix(keysym, 16)?)
+                    .or_default() += count;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn save_state(path: &str, shared: &Shared) -> Result<(), Box<dyn std::error::Error>> {
+    let mut contents = format!(
+        "translated\t{}\nuntouched\t{}\n",
+        shared.translated_keys.load(Ordering::Relaxed),
+        shared.untouched_keys.load(Ordering::Relaxed)
+    );
+    for (keysym, count) in shared.translated_keysyms.lock().unwrap().iter() {
+        contents.push_str(&format!("{keysym:#x}\t{count}\n"));
+    }
+    std::fs::write(path, contents)?;
+
+    Ok(())
+}
+
+// grabs the targets and forwards their keys until the connection fails, the grabbed
+// windows go into `tracked` so main can restore them afterwards
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run(
+    conn: &impl Connection,
+    server_keymap: &mut ServerKeymap,
+    root: u32,
+    atoms: &Atoms,
+    config: &Config,
+    rules: &[Config],
+    shared: &Shared,
+    tracked: &mut HashMap<u32, Tracked>,
+    mut targets: Vec<Target>,
+    mut started: Option<Instant>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // when each window was grabbed or last sent a key, counted in events, for --max-tracked
+    let mut last_seen: HashMap<u32, u64> = HashMap::new();
+    let mut tick = 0;
+    let wake = conn.generate_id()?;
+    conn.create_window(
+        0,
+        wake,
+        root,
+        0,
+        0,
+        1,
+        1,
+        0,
+        WindowClass::INPUT_ONLY,
+        0,
+        &CreateWindowAux::new(),
+    )?;
+    shared.wake_window.store(wake, Ordering::Relaxed);
+    let mut match_hooks: Vec<Child> = vec![];
+    let mut layout_memory = match &config.remember_layouts {
+        Some(path) => load_layout_memory(path)?,
+        None => HashMap::new(),
+    };
+
+    if config.check_grab_conflicts {
+        let mut conflicted = false;
+        let mut unusable = vec![];
+        for (window, _) in targets.iter() {
+            let (attempted, conflicts) = grab_conflicts(conn, server_keymap, config, *window)?;
+            if !conflicts.is_empty() {
+                conflicted = true;
+                warn_or_fail(
+                    config,
+                    &format!(
+                        "Another client already grabs {} on window 0x{window:08x}.",
+                        conflicts.join(", ")
+                    ),
+                )?;
+            }
+            if conflicts.len() == attempted {
+                unusable.push(*window);
+            }
+        }
+
+        if conflicted && !config.force {
+            return Err("Some keys are already grabbed by another client, usually the window manager or another mmk.\n       Free the keys or use --force to run with the grabs that succeeded.".into());
+        }
+        targets.retain(|(window, _)| !unusable.contains(window));
+    }
+
+    let root_mask = root_event_mask(config);
+    let mut followed_class = None;
+    if config.follow_class {
+        if let Some(windows) = follow_class(conn, root, config, atoms, &mut followed_class)? {
+            let layout = config.layout.first().copied();
+            targets.extend(windows.into_iter().map(|window| (window, layout)));
+        }
+    }
+    // setxkbmap announces new layouts on the root
+    let mut known_layouts = vec![];
+    if config.detect_layout_change {
+        known_layouts = xkb_layouts(conn, root, atoms)?;
+    }
+    if root_mask != u32::from(EventMask::NO_EVENT) {
+        conn.change_window_attributes(
+            root,
+            &ChangeWindowAttributesAux::new().event_mask(root_mask),
+        )?;
+    }
+
+    if !targets.is_empty() || config.match_mode == MatchMode::Live || config.follow_class {
+        for (window, layout) in targets {
+            let grabbed = match grab_window(conn, server_keymap, config, window) {
+                Err(e) if is_grab_taken(&*e) && config.all_windows => {
+                    warn_or_fail(
+                        config,
+                        &format!("Another client already grabbed the keyboard of 0x{window:08x}, is mmk already running? Skipping it."),
+                    )?;
+                    continue;
+                }
+                Err(e) if is_grab_taken(&*e) => return Err(GrabTaken { window }.into()),
+                grabbed => grabbed,
+            };
+            let Some(mut record) = skip_x_error(config, grabbed)? else {
+                warn(
+                    config,
+                    &format!("Couldn't grab window 0x{window:08x}, skipping it."),
+                );
+                continue;
+            };
+            record.layout = resolve_layout(conn, config, window, layout, &mut layout_memory)?;
+            tracked.insert(window, record);
+            tick += 1;
+            last_seen.insert(window, tick);
+            if let Some(command) = &config.match_hook {
+                run_match_hook(conn, atoms, config, command, &mut match_hooks, window)?;
+            }
+            if let Some(dir) = &config.screenshot_dir {
+                capture_screenshot(conn, config, dir, window)?;
+            }
+        }
+        // nothing else knows about the windows yet
+        evict_windows(conn, atoms, config, tracked, &mut last_seen)?;
+
+        if let Some(path) = &config.remember_layouts {
+            save_layout_memory(path, &layout_memory)?;
+        }
+    } else {
+        return Err("No window for the given specifications found.".into());
+    }
+    if let Some(combo) = config.toggle_key {
+        grab_toggle_key(conn, server_keymap, root, combo)?;
+    }
+    profile_phase("grabbing the windows", &mut started);
+
+    if config.drain_before_grab {
+        // a round trip makes sure everything the server queued before the grab has arrived
+        conn.get_input_focus()?.reply()?;
+        drain_events(|| conn.poll_for_event())?;
+    }
+
+    let mut last_press: HashMap<u8, u32> = HashMap::new();
+    let mut compose = if config.translate_compose {
+        Some(Compose::new()?)
+    } else {
+        None
+    };
+    let mut published_layout = None;
+    let mut lost_grabs: Vec<u32> = vec![];
+    // when the grab on each window was last checked, in server time
+    let mut grab_checks: HashMap<u32, u32> = HashMap::new();
+    // whether each window is fullscreen, read again on its next key after _NET_WM_STATE changes
+    let mut fullscreen: HashMap<u32, bool> = HashMap::new();
+    let mut xkb_layout_names = if config.layout_indicator || config.layout_name_in_title {
+        xkb_layouts(conn, root, atoms)?
+    } else {
+        vec![]
+    };
+    let mut hooks: Vec<Child> = vec![];
+    let mut record = match &config.record {
+        Some(path) => Some(BufWriter::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => None,
+    };
+
+    // translated keys sent since the last flush, see --translate-buffer
+    let mut buffered = 0;
+    // the part of the layout shift of the signals already applied to the tracked windows
+    let mut layout_shift = 0;
+    loop {
+        // write out what was recorded or buffered before blocking for the next batch of events
+        if let Some(record) = &mut record {
+            record.flush()?;
+        }
+        if buffered > 0 {
+            conn.flush()?;
+            buffered = 0;
+        }
+        let commands = shared
+            .control_commands
+            .lock()
+            .map(|mut commands| std::mem::take(&mut *commands))
+            .unwrap_or_default();
+        for (line, sender) in commands {
+            let answer = control_command(
+                conn,
+                server_keymap,
+                root,
+                atoms,
+                config,
+                shared,
+                &line,
+                tracked,
+            );
+            let answer = match answer {
+                Ok(answer) => answer,
+                Err(e) if is_connection_lost(&*e) => return Err(e),
+                Err(e) => format!("error: {e}"),
+            };
+            let _ = sender.send(answer);
+        }
+        let shift = shared.layout_shift.load(Ordering::Relaxed);
+        if shift != layout_shift {
+            let known = xkb_layouts(conn, root, atoms)?;
+            for record in tracked.values_mut() {
+                record.layout = shifted_layout(record.layout, shift - layout_shift, known.len());
+            }
+            layout_shift = shift;
+            let switched: BTreeSet<usize> = tracked.values().map(|record| record.layout).collect();
+            for index in switched {
+                let name = known
+                    .iter()
+                    .find(|layout| layout.index == index)
+                    .map(|layout| layout.layout.as_str())
+                    .unwrap_or_default();
+                eprintln!("mmk: switched to layout {index}:{name}");
+            }
+        }
+        if config.layout_name_in_title {
+            for (window, tracked_window) in tracked.iter_mut() {
+                // with auto-toggle the keys tell which layout is in use, see below
+                if config.layout_toggle && tracked_window.title.is_some() {
+                    continue;
+                }
+                let layout = tracked_window.layout;
+                let shown = show_layout_in_title(
+                    conn,
+                    atoms,
+                    &xkb_layout_names,
+                    *window,
+                    tracked_window,
+                    layout,
+                );
+                skip_x_error(config, shown)?;
+            }
+        }
+        shared
+            .grabbed_windows
+            .store(tracked.len(), Ordering::Relaxed);
+        let event = conn.wait_for_event()?;
+        let mut event_opt = Some(event);
+        // whether windows were mapped or destroyed, matched again once the batch is handled
+        let mut rematch = false;
+        while let Some(event) = event_opt {
+            let key = matches!(event, Event::KeyPress(_) | Event::KeyRelease(_));
+            match event {
+                Event::KeyPress(e) if config.toggle_key.is_some() && e.event == root => {
+                    let paused = !shared.paused.fetch_xor(true, Ordering::Relaxed);
+                    eprintln!("mmk: mimic {}", if paused { "disabled" } else { "enabled" });
+                }
+                // keys still queued for a window that is gone by now
+                Event::KeyPress(e) | Event::KeyRelease(e) if !tracked.contains_key(&e.event) => (),
+                Event::KeyPress(e) | Event::KeyRelease(e)
+                    if !event.sent_event()
+                        && (!config.require_focus || has_focus(conn, e.event)?) =>
+                {
+                    let press = matches!(event, Event::KeyPress(_));
+                    tick += 1;
+                    last_seen.insert(e.event, tick);
+                    let bounce = press
+                        && config
+                            .debounce
+                            .is_some_and(|ms| is_bounce(&mut last_press, e.detail, e.time, ms));
+
+                    let held = !config.translate_only_when_grab_active
+                        || if grab_check_due(&mut grab_checks, e.event, e.time) {
+                            grab_held(conn, server_keymap, config, e.event, &mut lost_grabs)?
+                        } else {
+                            !lost_grabs.contains(&e.event)
+                        };
+                    let suspended =
+                        suspended_for_fullscreen(config, &mut fullscreen, e.event, || {
+                            Ok(skip_x_error(config, is_fullscreen(conn, atoms, e.event))?
+                                .unwrap_or(false))
+                        })?;
+                    let paused = shared.paused.load(Ordering::Relaxed);
+                    let delivery = if let Some(delivery) = passed_over(held, paused, suspended) {
+                        delivery
+                    } else {
+                        // the group a key is pressed in comes with it, no need to ask the server
+                        let layout = if config.layout_toggle {
+                            other_group(((e.state >> 13) & 0x3).into())
+                        } else {
+                            tracked[&e.event].layout
+                        };
+                        if config.layout_indicator && published_layout != Some(layout) {
+                            publish_layout(conn, root, atoms, &xkb_layout_names, layout)?;
+                            published_layout = Some(layout);
+                        }
+                        if config.layout_name_in_title {
+                            if let Some(tracked_window) = tracked.get_mut(&e.event) {
+                                let shown = show_layout_in_title(
+                                    conn,
+                                    atoms,
+                                    &xkb_layout_names,
+                                    e.event,
+                                    tracked_window,
+                                    layout,
+                                );
+                                skip_x_error(config, shown)?;
+                            }
+                        }
+                        let locked = if config.respect_numlock_state {
+                            locked_modifiers(conn, server_keymap)
+                        } else {
+                            0
+                        };
+                        let trace = lookup(server_keymap, &e, layout, locked, config);
+                        note_unmapped_keysym(config, shared, &trace);
+                        let delivery = Delivery::from(trace.translated);
+                        let delivery = match &mut compose {
+                            Some(compose) => compose.feed(server_keymap, press, e.detail, delivery),
+                            None => delivery,
+                        };
+                        match delivery {
+                            Delivery::Translated(translated) => {
+                                shared.translated_keys.fetch_add(1, Ordering::Relaxed);
+                                *shared
+                                    .translated_keysyms
+                                    .lock()
+                                    .unwrap()
+                                    .entry(translated.keysym)
+                                    .or_default() += 1;
+                            }
+                            Delivery::Untouched | Delivery::Dropped => {
+                                shared.untouched_keys.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        delivery
+                    };
+                    let delivery = if bounce { Delivery::Dropped } else { delivery };
+                    if let Some(mut sent) = outgoing_event(&e, delivery) {
+                        if config.emit_to_focus_subtree {
+                            let target = focus_subtree_target(conn, e.event)?;
+                            if target != e.event {
+                                sent.event = target;
+                                sent.child = NONE;
+                            }
+                        }
+                        match config.send_mode {
+                            SendMode::Core => {
+                                let mask = tracked[&e.event].delivery_mask(config);
+                                conn.send_event(true, sent.event, mask, sent)?;
+                            }
+                            SendMode::Print => eprintln!(
+                                "0x{:08x}: ({}, {:#x}) -> ({}, {:#x})",
+                                e.event, e.detail, e.state, sent.detail, sent.state
+                            ),
+                        }
+                        buffered += 1;
+                        if buffered >= config.translate_buffer.unwrap_or(1) {
+                            conn.flush()?;
+                            buffered = 0;
+                        }
+
+                        if let Some(record) = &mut record {
+                            record_event(record, press, &e, &sent)?;
+                        }
+                        if let (Some(command), Delivery::Translated(translated)) =
+                            (&config.key_hook, delivery)
+                        {
+                            run_key_hook(command, &mut hooks, press, &e, &translated)?;
+                        }
+                    }
+                }
+                Event::MapNotify(_) if config.match_mode == MatchMode::Live => rematch = true,
+                Event::DestroyNotify(e) => {
+                    let mut gone = vec![];
+                    if tracked.contains_key(&e.window) {
+                        gone.push(e.window);
+                    } else if config.match_mode == MatchMode::Live {
+                        // the destroyed window may be a frame around one of ours, check all of them
+                        for window in tracked.keys() {
+                            if !window_exists(conn, *window)? {
+                                gone.push(*window);
+                            }
+                        }
+                    }
+                    // without --all the next matching window takes the place of a destroyed one
+                    rematch |= config.match_mode == MatchMode::Live && !gone.is_empty();
+                    for window in gone {
+                        tracked.remove(&window);
+                        last_seen.remove(&window);
+                        fullscreen.remove(&window);
+                        grab_checks.remove(&window);
+                        lost_grabs.retain(|lost| *lost != window);
+                    }
+
+                    // live matching and --follow-class wait for new windows instead
+                    if tracked.is_empty()
+                        && config.match_mode != MatchMode::Live
+                        && !config.follow_class
+                    {
+                        if !config.quiet {
+                            eprintln!("mmk: all windows are gone, exiting");
+                        }
+                        return Ok(());
+                    }
+                }
+                Event::PropertyNotify(e)
+                    if config.layout_name_in_title
+                        && (e.atom == atoms.net_wm_name
+                            || e.atom == u32::from(AtomEnum::WM_NAME)) =>
+                {
+                    if let Some(tracked_window) = tracked.get_mut(&e.window) {
+                        // put back the layout on the next round of the loop
+                        skip_x_error(config, refresh_title(conn, atoms, e.window, tracked_window))?;
+                    }
+                }
+                Event::PropertyNotify(e)
+                    if config.suspend_while_fullscreen && e.atom == atoms.net_wm_state =>
+                {
+                    fullscreen.remove(&e.window);
+                }
+                Event::PropertyNotify(e)
+                    if config.detect_layout_change && e.atom == atoms.xkb_rules_names =>
+                {
+                    let current = xkb_layouts(conn, root, atoms)?;
+                    if current != known_layouts {
+                        *server_keymap = ServerKeymap::new(conn)?;
+                        let named = std::iter::once(config)
+                            .chain(rules)
+                            .any(|rule| !rule.layout_names.is_empty());
+                        if named {
+                            for window in remap_layouts(&known_layouts, &current, tracked) {
+                                warn(
+                                    config,
+                                    &format!("The layout of 0x{window:08x} isn't configured anymore, keeping its index."),
+                                );
+                            }
+                        } else {
+                            warn(
+                                config,
+                                "The layouts were changed, --layout may now point at a different layout.",
+                            );
+                        }
+                        if config.layout_indicator || config.layout_name_in_title {
+                            xkb_layout_names = xkb_layouts(conn, root, atoms)?;
+                            published_layout = None;
+                            // the same index may have a different name now
+                            for record in tracked.values_mut() {
+                                record.title = None;
+                            }
+                        }
+                        known_layouts = current;
+                    }
+                }
+                Event::PropertyNotify(e)
+                    if config.follow_class && e.atom == atoms.net_active_window =>
+                {
+                    if let Some(windows) =
+                        follow_class(conn, root, config, atoms, &mut followed_class)?
+                    {
+                        // let go of the previous app
+                        for (window, record) in tracked.iter() {
+                            release_window(conn, Some(atoms), *window, record)?;
+                        }
+                        tracked.clear();
+                        last_seen.clear();
+
+                        for window in windows {
+                            let Some(mut record) = skip_x_error(
+                                config,
+                                grab_window(conn, server_keymap, config, window),
+                            )?
+                            else {
+                                continue;
+                            };
+                            let layout = config.layout.first().copied();
+                            record.layout =
+                                resolve_layout(conn, config, window, layout, &mut layout_memory)?;
+                            tracked.insert(window, record);
+                            tick += 1;
+                            last_seen.insert(window, tick);
+                            if let Some(command) = &config.match_hook {
+                                run_match_hook(
+                                    conn,
+                                    atoms,
+                                    config,
+                                    command,
+                                    &mut match_hooks,
+                                    window,
+                                )?;
+                            }
+                        }
+                        for window in evict_windows(conn, atoms, config, tracked, &mut last_seen)? {
+                            fullscreen.remove(&window);
+                            grab_checks.remove(&window);
+                            lost_grabs.retain(|lost| *lost != window);
+                        }
+                    }
+                }
+                Event::ClientMessage(e)
+                    if e.window == wake && shared.quit.load(Ordering::Relaxed) =>
+                {
+                    return Ok(());
+                }
+                // errors of requests without a reply, like sending to a window which just went away
+                Event::Error(e) if config.x_error_mode == XErrorMode::Strict => {
+                    return Err(ReplyError::X11Error(e).into());
+                }
+                _ => (),
+            };
+            // a sync grab froze the devices until the key is handled
+            if key {
+                for allow in events_to_allow(config) {
+                    conn.allow_events(allow, CURRENT_TIME)?;
+                    conn.flush()?;
+                }
+            }
+            event_opt = conn.poll_for_event()?;
+        }
+        if rematch {
+            // windows mapped while running are always read fresh
+            let mut cache = MatchCache::default();
+            let found = find_targets(conn, root, rules, atoms, &mut cache, &mut None)?;
+            for (window, layout) in untracked(found, tracked) {
+                let grabbed = grab_window(conn, server_keymap, config, window);
+                let Some(mut record) = skip_x_error(config, grabbed)? else {
+                    continue;
+                };
+                record.layout = resolve_layout(conn, config, window, layout, &mut layout_memory)?;
+                tracked.insert(window, record);
+                tick += 1;
+                last_seen.insert(window, tick);
+                if let Some(command) = &config.match_hook {
+                    run_match_hook(conn, atoms, config, command, &mut match_hooks, window)?;
+                }
+                if let Some(dir) = &config.screenshot_dir {
+                    capture_screenshot(conn, config, dir, window)?;
+                }
+            }
+            for window in evict_windows(conn, atoms, config, tracked, &mut last_seen)? {
+                fullscreen.remove(&window);
+                grab_checks.remove(&window);
+                lost_grabs.retain(|lost| *lost != window);
+            }
+            if let Some(path) = &config.remember_layouts {
+                save_layout_memory(path, &layout_memory)?;
+            }
+        }
+    }
+}
+
+// how a key which isn't translated goes out, `None` for one to translate. without the grab the
+// window gets the key from the server anyway and another copy would type it twice, while paused
+// or suspended it goes out the way it came in
+fn passed_over(held: bool, paused: bool, suspended: bool) -> Option<Delivery> {
+    if !held {
+        Some(Delivery::Dropped)
+    } else if paused || suspended {
+        Some(Delivery::Untouched)
+    } else {
+        None
+    }
+}
+
+// whether --suspend-while-fullscreen holds back the keys of `window`, `is_fullscreen` is only
+// asked again once a change of _NET_WM_STATE took the window out of `known`
+fn suspended_for_fullscreen(
+    config: &Config,
+    known: &mut HashMap<u32, bool>,
+    window: u32,
+    is_fullscreen: impl FnOnce() -> Result<bool, Box<dyn std::error::Error>>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if !config.suspend_while_fullscreen {
+        return Ok(false);
+    }
+    Ok(match known.entry(window) {
+        Entry::Occupied(entry) => *entry.get(),
+        Entry::Vacant(entry) => *entry.insert(is_fullscreen()?),
+    })
+}
+
+// the windows matched again while running which aren't grabbed yet
+fn untracked<V>(found: Vec<Target>, tracked: &HashMap<u32, V>) -> Vec<Target> {
+    found
+        .into_iter()
+        .filter(|(window, _)| !tracked.contains_key(window))
+        .collect()
+}
+
+// the events of the root window the options need, from the window manager and setxkbmap
+pub(crate) fn root_event_mask(config: &Config) -> u32 {
+    let mut mask = u32::from(EventMask::NO_EVENT);
+    // windows created and destroyed while running
+    if config.match_mode == MatchMode::Live {
+        mask |= u32::from(EventMask::SUBSTRUCTURE_NOTIFY);
+    }
+    // the window manager announces the active window on the root, setxkbmap new layouts
+    if config.follow_class || config.detect_layout_change {
+        mask |= u32::from(EventMask::PROPERTY_CHANGE);
+    }
+
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use x11rb::protocol::xproto::ModMask;
+
+    use super::*;
+    use crate::{
+        testing::{atoms, atoms_reply, config, press, server_keymap, FakeServer, TestKeymap},
+        translate::{translate, KeyEvent},
+    };
+
+    #[test]
+    fn keys_without_the_grab_are_not_sent_again() {
+        // the window has the key already
+        assert_eq!(passed_over(false, false, false), Some(Delivery::Dropped));
+        assert_eq!(passed_over(false, true, true), Some(Delivery::Dropped));
+        // the grab keeps it from the window, so it has to be sent as it is
+        assert_eq!(passed_over(true, true, false), Some(Delivery::Untouched));
+        assert_eq!(passed_over(true, false, true), Some(Delivery::Untouched));
+        assert_eq!(passed_over(true, false, false), None);
+    }
+
+    #[test]
+    fn fullscreen_windows_suspend_the_translation() {
+        let atoms = atoms();
+        let server = FakeServer::new(vec![
+            atoms_reply(&[
+                atoms.net_wm_state_fullscreen - 1,
+                atoms.net_wm_state_fullscreen,
+            ]),
+            atoms_reply(&[atoms.net_wm_state_fullscreen - 1]),
+        ]);
+        let suspending = config(&["--suspend-while-fullscreen"]);
+        let mut known = HashMap::new();
+        let suspended = |known: &mut HashMap<u32, bool>| {
+            suspended_for_fullscreen(&suspending, known, 2, || is_fullscreen(&server, &atoms, 2))
+                .unwrap()
+        };
+
+        assert!(suspended(&mut known));
+        // the state is read once until _NET_WM_STATE changes
+        assert!(suspended(&mut known));
+        // the window left fullscreen
+        known.remove(&2);
+        assert!(!suspended(&mut known));
+        assert!(!suspended(&mut known));
+        assert_eq!(server.take_requests().len(), 2);
+
+        let never_asked = || unreachable!();
+        assert!(!suspended_for_fullscreen(&config(&[]), &mut known, 3, never_asked).unwrap());
+    }
+
+    // the lines the hooks wrote to `path` once they are done, sorted
+    fn hook_output(running: &mut [Child], path: &std::path::Path) -> Vec<String> {
+        for child in running.iter_mut() {
+            child.wait().unwrap();
+        }
+        let contents = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let mut env: Vec<String> = contents.lines().map(String::from).collect();
+        env.sort();
+        env
+    }
+
+    #[test]
+    fn key_hooks_get_the_key_in_their_environment() {
+        let path = std::env::temp_dir().join(format!("mmk-key-hook-{}", std::process::id()));
+        let command = format!("env | grep ^MMK_ > {}", path.display());
+        let original = press(38, u16::from(ModMask::SHIFT));
+        let translated = Translated {
+            detail: 40,
+            state: u16::from(ModMask::SHIFT),
+            keysym: 0x44,
+        };
+
+        let mut running = vec![];
+        run_key_hook(&command, &mut running, true, &original, &translated).unwrap();
+        assert_eq!(
+            hook_output(&mut running, &path),
+            [
+                "MMK_EVENT=press",
+                "MMK_KEYCODE=40",
+                "MMK_KEYSYM=D",
+                "MMK_ORIGINAL_KEYCODE=38",
+                "MMK_ORIGINAL_STATE=1",
+                "MMK_STATE=1",
+                "MMK_WINDOW=0x00000002",
+            ]
+        );
+    }
+
+    #[test]
+    fn sent_keys_are_recorded_as_json_lines() {
+        // `A` on keycode 8 types `O` of keycode 9 in the second layout, keycode 10 is no key
+        let keymap = TestKeymap::new(&[[[0x61, 0x41], [0x6f, 0x4f]], [[0x6f, 0x4f], [0, 0]]]);
+        let config = config(&["--layout", "1", "--record", "/dev/null"]);
+
+        let mut record = vec![];
+        for (press, event) in [(true, press(8, 1)), (false, press(10, 0))] {
+            let key = if press {
+                KeyEvent::Press(event)
+            } else {
+                KeyEvent::Release(event)
+            };
+            let translated = translate(&keymap, key, 1, 0, &config).unwrap();
+            let sent = outgoing_event(&event, Delivery::from(translated)).unwrap();
+            record_event(&mut record, press, &event, &sent).unwrap();
+        }
+
+        let record = String::from_utf8(record).unwrap();
+        let lines: Vec<&str> = record
+            .lines()
+            .map(|line| line.split_once(',').unwrap().1)
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "\"event\":\"press\",\"window\":2,\"original_detail\":8,\"original_state\":1,\"detail\":9,\"state\":1}",
+                "\"event\":\"release\",\"window\":2,\"original_detail\":10,\"original_state\":0,\"detail\":10,\"state\":0}",
+            ]
+        );
+        assert!(record.lines().all(|line| line.starts_with("{\"time\":")));
+    }
+
+    #[test]
+    fn queued_events_are_drained() {
+        let mut queue: std::collections::VecDeque<_> = [press(8, 0), press(9, 1)].into();
+        assert_eq!(drain_events(|| Ok::<_, ()>(queue.pop_front())), Ok(2));
+        assert!(queue.is_empty());
+        assert_eq!(drain_events(|| Ok::<_, ()>(queue.pop_front())), Ok(0));
+        assert_eq!(drain_events(|| Err::<Option<()>, _>("gone")), Err("gone"));
+    }
+
+    #[test]
+    fn sigusr1_toggles_the_pause_and_sigusr2_reports() {
+        let shared = Shared::default();
+        shared.translated_keys.store(5, Ordering::Relaxed);
+        shared.untouched_keys.store(2, Ordering::Relaxed);
+
+        assert_eq!(
+            pause_signal(&shared, SIGUSR2),
+            "mmk: translation running, 5 keys translated, 2 left alone"
+        );
+        assert_eq!(pause_signal(&shared, SIGUSR1), "mmk: translation paused");
+        assert!(shared.paused.load(Ordering::Relaxed));
+        assert_eq!(
+            pause_signal(&shared, SIGUSR2),
+            "mmk: translation paused, 5 keys translated, 2 left alone"
+        );
+        // reporting leaves the pause alone
+        assert!(shared.paused.load(Ordering::Relaxed));
+        assert_eq!(pause_signal(&shared, SIGUSR1), "mmk: translation resumed");
+        assert!(!shared.paused.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn windows_mapped_later_are_grabbed() {
+        let config = config(&["--grab-on-map", "--class", "X", "--all"]);
+        assert_eq!(config.match_mode, MatchMode::Live);
+        assert_ne!(
+            root_event_mask(&config) & u32::from(EventMask::SUBSTRUCTURE_NOTIFY),
+            0
+        );
+
+        // 2 was grabbed at the start, 5 was mapped since
+        let tracked = HashMap::from([(2, ())]);
+        let found = vec![(2, Some(1)), (5, Some(1))];
+        assert_eq!(untracked(found, &tracked), [(5, Some(1))]);
+        assert!(untracked(vec![(2, None)], &tracked).is_empty());
+    }
+
+    #[test]
+    fn keysyms_without_a_key_are_reported() {
+        // `ä` and `ö` of the second group are on no key of the first
+        let keymap = TestKeymap::new(&[
+            [[0x61, 0x41], [0xe4, 0xc4]],
+            [[0x6f, 0x4f], [0xf6, 0xd6]],
+            [[0x62, 0x42], [0x61, 0x41]],
+        ]);
+        let feed = |config: &Config, shared: &Shared| {
+            for keycode in [8, 9, 10, 8, 11] {
+                let trace = lookup(&keymap, &press(keycode, 0), 1, 0, config);
+                note_unmapped_keysym(config, shared, &trace);
+            }
+        };
+
+        let shared = Shared::default();
+        feed(&config(&["--layout", "1"]), &shared);
+        assert_eq!(unmapped_keysyms_report(&shared), None);
+
+        feed(
+            &config(&["--layout", "1", "--report-unmapped-keysyms"]),
+            &shared,
+        );
+        assert_eq!(
+            *shared.unmapped_keysyms.lock().unwrap(),
+            BTreeSet::from([0xe4, 0xf6])
+        );
+        assert_eq!(
+            unmapped_keysyms_report(&shared).unwrap(),
+            "mmk: these keysyms have no keycode in the keymap and were left alone:\n    \
+             adiaeresis (0xe4)\n    odiaeresis (0xf6)\n"
+        );
+    }
+
+    #[test]
+    fn match_hooks_get_their_window_in_their_environment() {
+        let path = std::env::temp_dir().join(format!("mmk-match-hook-{}", std::process::id()));
+        let command = format!(
+            "echo \"$MMK_WINDOW|$MMK_CLASS|$MMK_PID|$MMK_NAME\" >> {}",
+            path.display()
+        );
+        let windows = [
+            (0x3a00007, "Firefox.Navigator", 42, "a tab"),
+            (0x4c00002, "", 0, ""),
+        ];
+
+        let mut running = vec![];
+        for (window, class, pid, name) in windows {
+            running.push(spawn_match_hook(&command, window, class, pid, name).unwrap());
+        }
+        assert_eq!(
+            hook_output(&mut running, &path),
+            ["0x03a00007|Firefox.Navigator|42|a tab", "0x04c00002||0|"]
+        );
+    }
+
+    #[test]
+    fn metrics_are_in_the_prometheus_text_format() {
+        let shared = Shared {
+            started_at: 1700000000,
+            ..Default::default()
+        };
+        shared.translated_keys.store(12, Ordering::Relaxed);
+        shared.untouched_keys.store(3, Ordering::Relaxed);
+        let metrics = metrics(&shared, 2);
+
+        assert!(metrics.ends_with('\n'));
+        let lines: Vec<&str> = metrics.lines().collect();
+        // each metric is a HELP line, a TYPE line and its sample
+        assert_eq!(lines.len() % 3, 0);
+        let name = regex::Regex::new("^[a-zA-Z_:][a-zA-Z0-9_:]*$").unwrap();
+        let mut samples = vec![];
+        for metric in lines.chunks(3) {
+            let (help, kind, sample) = (metric[0], metric[1], metric[2]);
+            let (metric_name, value) = sample.split_once(' ').unwrap();
+            assert!(name.is_match(metric_name), "{sample}");
+            assert!(value.parse::<f64>().is_ok(), "{sample}");
+            assert!(help.starts_with(&format!("# HELP {metric_name} ")));
+            let kind = kind
+                .strip_prefix(&format!("# TYPE {metric_name} "))
+                .unwrap();
+            assert!(["counter", "gauge"].contains(&kind));
+            if kind == "counter" {
+                assert!(metric_name.ends_with("_total"), "{sample}");
+            }
+            samples.push(sample);
+        }
+        assert_eq!(
+            samples,
+            [
+                "mmk_keys_translated_total 12",
+                "mmk_keys_untouched_total 3",
+                "mmk_windows_grabbed 2",
+                "mmk_start_time_seconds 1700000000"
+            ]
+        );
+    }
+
+    #[test]
+    fn metrics_are_written_once_they_change() {
+        let dir = std::env::temp_dir().join(format!("mmk-metrics-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mmk.prom");
+        let path = path.to_str().unwrap();
+        let shared = Shared::default();
+        let mut written = None;
+
+        refresh_metrics(path, &shared, &mut written).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), metrics(&shared, 0));
+        // the same metrics aren't written again
+        std::fs::remove_file(path).unwrap();
+        refresh_metrics(path, &shared, &mut written).unwrap();
+        assert!(std::fs::metadata(path).is_err());
+
+        shared.translated_keys.store(1, Ordering::Relaxed);
+        shared.grabbed_windows.store(3, Ordering::Relaxed);
+        refresh_metrics(path, &shared, &mut written).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), metrics(&shared, 3));
+
+        // a write that failed is tried again
+        std::fs::remove_dir_all(&dir).unwrap();
+        shared.translated_keys.store(2, Ordering::Relaxed);
+        assert!(refresh_metrics(path, &shared, &mut written).is_err());
+        std::fs::create_dir_all(&dir).unwrap();
+        refresh_metrics(path, &shared, &mut written).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), metrics(&shared, 3));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_socket_answers_with_the_metrics() {
+        let shared = Shared::default();
+        shared.translated_keys.store(5, Ordering::Relaxed);
+        let record = Tracked {
+            send_mask: 0,
+            original_mask: 0,
+            layout: 1,
+            keyboard_grab: false,
+            title: None,
+        };
+        let mut tracked = HashMap::from([(2, record)]);
+
+        let answer = control_command(
+            &FakeServer::new(vec![]),
+            &server_keymap(),
+            1,
+            &atoms(),
+            &config(&[]),
+            &shared,
+            "metrics",
+            &mut tracked,
+        )
+        .unwrap();
+        assert_eq!(answer, metrics(&shared, 1).trim_end());
+        assert!(answer.contains("\nmmk_keys_translated_total 5\n"));
+        assert!(answer.contains("\nmmk_windows_grabbed 1\n"));
+    }
+
+    #[test]
+    fn persisted_counters_add_up_across_runs() {
+        let path = std::env::temp_dir().join(format!("mmk-state-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let shared = Shared::default();
+        // nothing saved yet
+        load_state(path, &shared).unwrap();
+        assert_eq!(shared.translated_keys.load(Ordering::Relaxed), 0);
+
+        shared.translated_keys.store(3, Ordering::Relaxed);
+        shared.untouched_keys.store(1, Ordering::Relaxed);
+        *shared.translated_keysyms.lock().unwrap() = BTreeMap::from([(0x61, 2), (0x6f, 1)]);
+        save_state(path, &shared).unwrap();
+
+        // the next run counts on from there
+        let next = Shared::default();
+        next.translated_keys.store(2, Ordering::Relaxed);
+        next.translated_keysyms.lock().unwrap().insert(0x61, 2);
+        load_state(path, &next).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(next.translated_keys.load(Ordering::Relaxed), 5);
+        assert_eq!(next.untouched_keys.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            *next.translated_keysyms.lock().unwrap(),
+            BTreeMap::from([(0x61, 4), (0x6f, 1)])
+        );
+
+        std::fs::write(path, "translated 3\n").unwrap();
+        let err = load_state(path, &Shared::default()).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("{path}:1: expected `<key>\t<count>`")
+        );
+    }
+
+    #[test]
+    fn live_matching_listens_for_new_windows() {
+        let substructure = u32::from(EventMask::SUBSTRUCTURE_NOTIFY);
+
+        assert_eq!(root_event_mask(&config(&[])), 0);
+        assert_eq!(
+            root_event_mask(&config(&["--match-mode", "snapshot"])) & substructure,
+            0
+        );
+        assert_eq!(
+            root_event_mask(&config(&["--match-mode", "live"])),
+            substructure
+        );
+        assert_eq!(root_event_mask(&config(&["--grab-on-map"])), substructure);
+        assert_eq!(
+            root_event_mask(&config(&["--match-mode", "live", "--detect-layout-change"])),
+            substructure | u32::from(EventMask::PROPERTY_CHANGE)
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! The expressions of `--event-filter`.
+
+use crate::config::{modifier_mask, parse_wid};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FilterOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitAnd,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum FilterExpr {
+    Number(u32),
+    Detail,
+    State,
+    Not(Box<FilterExpr>),
+    Binary(FilterOp, Box<FilterExpr>, Box<FilterExpr>),
+}
+
+impl FilterExpr {
+    pub(crate) fn eval(&self, detail: u8, state: u16) -> u32 {
+        match self {
+            Self::Number(n) => *n,
+            Self::Detail => detail.into(),
+            Self::State => state.into(),
+            Self::Not(expr) => (expr.eval(detail, state) == 0).into(),
+            Self::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(detail, state);
+                // && and || short circuit, the rest need both sides
+                match op {
+                    FilterOp::Or => return (lhs != 0 || rhs.eval(detail, state) != 0).into(),
+                    FilterOp::And => return (lhs != 0 && rhs.eval(detail, state) != 0).into(),
+                    _ => (),
+                }
+                let rhs = rhs.eval(detail, state);
+                match op {
+                    FilterOp::Or | FilterOp::And => unreachable!(),
+                    FilterOp::Eq => (lhs == rhs).into(),
+                    FilterOp::Ne => (lhs != rhs).into(),
+                    FilterOp::Lt => (lhs < rhs).into(),
+                    FilterOp::Le => (lhs <= rhs).into(),
+                    FilterOp::Gt => (lhs > rhs).into(),
+                    FilterOp::Ge => (lhs >= rhs).into(),
+                    FilterOp::BitOr => lhs | rhs,
+                    FilterOp::BitAnd => lhs & rhs,
+                }
+            }
+        }
+    }
+}
+
+// the expression of --event-filter, kept along with its source for --dump-config
+#[derive(Debug, Clone)]
+pub(crate) struct EventFilter {
+    pub(crate) source: String,
+    pub(crate) expr: FilterExpr,
+}
+
+impl EventFilter {
+    pub(crate) fn parse(input: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let tokens = filter_tokens(input)?;
+        // the parser and `eval` recurse along the expression
+        if tokens.len() > MAX_FILTER_TOKENS {
+            return Err(format!(
+                "--event-filter: longer than {MAX_FILTER_TOKENS} operators and operands"
+            )
+            .into());
+        }
+        let mut pos = 0;
+        let expr = parse_filter_binary(&tokens, &mut pos, 0, 0)?;
+        if let Some(token) = tokens.get(pos) {
+            return Err(format!("--event-filter: unexpected `{token}`").into());
+        }
+
+        Ok(Self {
+            source: input.to_string(),
+            expr,
+        })
+    }
+
+    pub(crate) fn matches(&self, detail: u8, state: u16) -> bool {
+        self.expr.eval(detail, state) != 0
+    }
+}
+
+pub(crate) fn filter_tokens(input: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut ret = vec![];
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c.is_ascii_alphanumeric() || c == '_' {
+            let mut word = c.to_string();
+            while let Some(next) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                word.push(next);
+            }
+            ret.push(word);
+            continue;
+        }
+        let token = match (c, chars.peek()) {
+            ('|', Some('|')) | ('&', Some('&')) | ('=', Some('=')) => {
+                format!("{c}{}", chars.next().unwrap_or(c))
+            }
+            ('!' | '<' | '>', Some('=')) => format!("{c}{}", chars.next().unwrap_or(c)),
+            ('|' | '&' | '!' | '<' | '>' | '(' | ')', _) => c.to_string(),
+            _ => return Err(format!("--event-filter: unexpected `{c}`").into()),
+        };
+        ret.push(token);
+    }
+
+    Ok(ret)
+}
+
+// how long an --event-filter and how deeply nested its parentheses and `!` may be
+pub(crate) const MAX_FILTER_TOKENS: usize = 1024;
+pub(crate) const MAX_FILTER_DEPTH: usize = 32;
+
+// the binary operators from the loosest to the tightest binding one, like in Rust
+pub(crate) const FILTER_PRECEDENCE: [&[(&str, FilterOp)]; 5] = [
+    &[("||", FilterOp::Or)],
+    &[("&&", FilterOp::And)],
+    &[
+        ("==", FilterOp::Eq),
+        ("!=", FilterOp::Ne),
+        ("<=", FilterOp::Le),
+        (">=", FilterOp::Ge),
+        ("<", FilterOp::Lt),
+        (">", FilterOp::Gt),
+    ],
+    &[("|", FilterOp::BitOr)],
+    &[("&", FilterOp::BitAnd)],
+];
+
+// `depth` counts the parentheses and `!` around the expression
+pub(crate) fn parse_filter_binary(
+    tokens: &[String],
+    pos: &mut usize,
+    level: usize,
+    depth: usize,
+) -> Result<FilterExpr, Box<dyn std::error::Error>> {
+    let Some(ops) = FILTER_PRECEDENCE.get(level) else {
+        return parse_filter_unary(tokens, pos, depth);
+    };
+
+    let mut lhs = parse_filter_binary(tokens, pos, level + 1, depth)?;
+    while let Some((_, op)) = tokens
+        .get(*pos)
+        .and_then(|token| ops.iter().find(|(name, _)| name == token))
+    {
+        *pos += 1;
+        let rhs = parse_filter_binary(tokens, pos, level + 1, depth)?;
+        lhs = FilterExpr::Binary(*op, Box::new(lhs), Box::new(rhs));
+    }
+
+    Ok(lhs)
+}
+
+pub(crate) fn parse_filter_unary(
+    tokens: &[String],
+    pos: &mut usize,
+    depth: usize,
+) -> Result<FilterExpr, Box<dyn std::error::Error>> {
+    let token = tokens
+        .get(*pos)
+        .ok_or("--event-filter: the expression ends too early")?;
+    *pos += 1;
+    if matches!(&token[..], "!" | "(") && depth == MAX_FILTER_DEPTH {
+        return Err(format!("--event-filter: nested deeper than {MAX_FILTER_DEPTH} levels").into());
+    }
+
+    match &token[..] {
+        "!" => Ok(FilterExpr::Not(Box::new(parse_filter_unary(
+            tokens,
+            pos,
+            depth + 1,
+        )?))),
+        "(" => {
+            let expr = parse_filter_binary(tokens, pos, 0, depth + 1)?;
+            if tokens.get(*pos).map(String::as_str) != Some(")") {
+                return Err("--event-filter: missing `)`".into());
+            }
+            *pos += 1;
+            Ok(expr)
+        }
+        "detail" | "keycode" => Ok(FilterExpr::Detail),
+        "state" => Ok(FilterExpr::State),
+        _ if token.starts_with(|c: char| c.is_ascii_digit()) => parse_wid(token)
+            .map(FilterExpr::Number)
+            .map_err(|_| format!("--event-filter: invalid number `{token}`").into()),
+        _ => match modifier_mask(token) {
+            Some(mask) => Ok(FilterExpr::Number(mask.into())),
+            None => Err(format!("--event-filter: unknown name `{token}`").into()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x11rb::protocol::xproto::ModMask;
+
+    use super::*;
+
+    fn eval(input: &str, detail: u8, state: u16) -> u32 {
+        EventFilter::parse(input).unwrap().expr.eval(detail, state)
+    }
+
+    fn parse_error(input: &str) -> String {
+        EventFilter::parse(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn filters_bind_like_rust() {
+        // & before ==, == before &&, && before ||
+        assert_eq!(eval("state & 4 == 4", 0, 5), 1);
+        assert_eq!(eval("1 | 2 & 0", 0, 0), 1);
+        assert_eq!(eval("detail == 36 || detail == 37 && state", 36, 0), 1);
+        assert_eq!(eval("(detail == 36 || detail == 37) && state", 36, 0), 0);
+        assert_eq!(eval("detail >= 10 && detail < 20", 15, 0), 1);
+        assert_eq!(eval("detail <= 9 || detail > 20", 15, 0), 0);
+        // ! binds to what directly follows it
+        assert_eq!(eval("!detail == 0", 38, 0), 1);
+        assert_eq!(eval("!(detail == 0)", 38, 0), 1);
+        assert_eq!(eval("!!state", 0, 8), 1);
+        assert_eq!(eval("0x10 == 16", 0, 0), 1);
+    }
+
+    #[test]
+    fn filters_name_modifiers_and_fields() {
+        let filter = EventFilter::parse("state & Mod1 && detail != 36").unwrap();
+        assert!(filter.matches(38, u16::from(ModMask::M1)));
+        assert!(!filter.matches(36, u16::from(ModMask::M1)));
+        assert!(!filter.matches(38, 0));
+
+        assert_eq!(eval("shift | lock | control", 0, 0), 7);
+        assert_eq!(eval("state & ctrl", 0, 0xff), 4);
+        assert_eq!(eval("keycode", 42, 0), 42);
+    }
+
+    #[test]
+    fn malformed_filters_are_errors() {
+        for (input, error) in [
+            ("", "the expression ends too early"),
+            ("detail ==", "the expression ends too early"),
+            ("(detail == 1", "missing `)`"),
+            ("detail == 1)", "unexpected `)`"),
+            ("detail 1", "unexpected `1`"),
+            ("detail = 1", "unexpected `=`"),
+            ("detail $ 1", "unexpected `$`"),
+            ("hyper", "unknown name `hyper`"),
+            ("0xzz", "invalid number `0xzz`"),
+        ] {
+            assert_eq!(
+                parse_error(input),
+                format!("--event-filter: {error}"),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn filters_nest_only_so_deep() {
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(eval(&nested(MAX_FILTER_DEPTH), 0, 0), 1);
+        assert!(parse_error(&nested(MAX_FILTER_DEPTH + 1)).contains("nested deeper"));
+        assert!(parse_error(&format!("{}1", "!".repeat(1000))).contains("nested deeper"));
+
+        let long = vec!["1"; MAX_FILTER_TOKENS].join(" & ");
+        assert!(parse_error(&long).contains("longer than"));
+    }
+}
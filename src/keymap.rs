@@ -0,0 +1,479 @@
+//! The keymaps keys are looked up in and the names of keysyms.
+
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xkb::{ConnectionExt as _, Group, KeyModMap, KeySymMap, KeyType, MapPart, ID},
+        xproto::{Keysym, ModMask},
+    },
+};
+#[cfg(feature = "xkbcommon")]
+use xkbcommon::xkb;
+
+// the group of --group-lock, Xkb only has four
+fn group_to_lock(group: u32) -> Result<Group, Box<dyn std::error::Error>> {
+    match u8::try_from(group) {
+        Ok(group) if group < 4 => Ok(Group::from(group)),
+        _ => Err(
+            format!("--group-lock {group} isn't a group, Xkb only has the groups 0 to 3.").into(),
+        ),
+    }
+}
+
+// locks the keyboard to an Xkb group, returns the one which was locked before to put it back
+pub(crate) fn lock_group(
+    conn: &impl Connection,
+    group: u32,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let group = group_to_lock(group)?;
+    let state = conn.xkb_get_state(ID::USE_CORE_KBD.into())?.reply()?;
+    conn.xkb_latch_lock_state(
+        ID::USE_CORE_KBD.into(),
+        0u8,
+        0u8,
+        true,
+        group,
+        0u8,
+        false,
+        0,
+    )?;
+    conn.flush()?;
+
+    Ok(u8::from(state.locked_group).into())
+}
+
+pub(crate) const NUM_LOCK: Keysym = 0xff7f;
+
+/// The lookups a translation needs, kept apart from the X server so they can be swapped out.
+pub trait KeymapSource {
+    /// The keysym at a group and shift level of a key, 0 for a group or level it doesn't have.
+    fn keycode_to_keysym(&self, keycode: u8, group: usize, level: usize) -> Keysym;
+    /// The shift level the modifiers of `state` pick on a key in a group. Without key types only
+    /// Shift picks the second level, like in the core protocol.
+    fn shift_level(&self, _keycode: u8, _group: usize, state: u16) -> usize {
+        usize::from(state & u16::from(ModMask::SHIFT) != 0)
+    }
+    /// The first key which types the keysym, 0 if none does.
+    fn keysym_to_keycode(&self, keysym: Keysym) -> u8;
+    /// The modifiers which reach the keysym on its key.
+    fn keysym_to_mods(&self, keysym: Keysym) -> u32;
+    /// The highest keycode a translation may send.
+    fn max_keycode(&self) -> u8;
+}
+
+/// The X server's keymap, read once with Xkb so a key needs no round trip to translate.
+pub struct ServerKeymap {
+    pub(crate) min_keycode: u8,
+    pub(crate) max_keycode: u8,
+    pub(crate) types: Vec<KeyType>,
+    // the keysyms of every keycode starting at min_keycode
+    pub(crate) keys: Vec<KeySymMap>,
+    // the modifiers each key is bound to, like NumLock to mod2
+    pub(crate) modifier_map: Vec<KeyModMap>,
+}
+
+impl ServerKeymap {
+    /// Reads the keymap of the core keyboard, the Xkb extension has to be set up already.
+    pub fn new(conn: &impl Connection) -> Result<Self, Box<dyn std::error::Error>> {
+        let parts = MapPart::KEY_TYPES | MapPart::KEY_SYMS | MapPart::MODIFIER_MAP;
+        let reply = conn
+            .xkb_get_map(
+                ID::USE_CORE_KBD.into(),
+                parts,
+                0u16,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0u16,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            )?
+            .reply()?;
+
+        Ok(Self {
+            min_keycode: reply.min_key_code,
+            max_keycode: conn.setup().max_keycode,
+            types: reply.map.types_rtrn.unwrap_or_default(),
+            keys: reply.map.syms_rtrn.unwrap_or_default(),
+            modifier_map: reply.map.modmap_rtrn.unwrap_or_default(),
+        })
+    }
+
+    // the locked modifiers a translation takes into account, CapsLock and whichever modifier
+    // NumLock is bound to, it isn't a fixed one like Lock
+    pub(crate) fn lock_mask(&self) -> u16 {
+        let num_lock = self.keysym_to_keycode(NUM_LOCK);
+        self.modifier_map
+            .iter()
+            .filter(|entry| num_lock != 0 && entry.keycode == num_lock)
+            .fold(ModMask::LOCK.into(), |mask, entry| {
+                mask | u16::from(entry.mods)
+            })
+    }
+
+    pub(crate) fn key(&self, keycode: u8) -> Option<&KeySymMap> {
+        self.keys
+            .get(usize::from(keycode.checked_sub(self.min_keycode)?))
+    }
+
+    pub(crate) fn levels(&self, key: &KeySymMap, group: usize) -> usize {
+        self.types
+            .get(usize::from(key.kt_index[group]))
+            .map_or(0, |key_type| key_type.num_levels.into())
+    }
+}
+
+impl KeymapSource for ServerKeymap {
+    // the same as XkbKeycodeToKeysym
+    fn keycode_to_keysym(&self, keycode: u8, group: usize, level: usize) -> Keysym {
+        let Some(key) = self.key(keycode) else {
+            return 0;
+        };
+        if group >= usize::from(key.group_info & 0x0f) {
+            return 0;
+        }
+        let level = if level < self.levels(key, group) {
+            level
+        } else if group < 2 && level == 1 && self.levels(key, group) == 1 {
+            // like the core protocol the first two groups always have two levels
+            0
+        } else {
+            return 0;
+        };
+
+        key.syms
+            .get(group * usize::from(key.width) + level)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // the level the type of the key maps the modifiers to, the same as XkbTranslateKeyCode. the
+    // group in bits 13 and 14 and the modifiers the type doesn't care about don't count
+    fn shift_level(&self, keycode: u8, group: usize, state: u16) -> usize {
+        let key_type = self
+            .key(keycode)
+            .and_then(|key| key.kt_index.get(group))
+            .and_then(|index| self.types.get(usize::from(*index)));
+        let Some(key_type) = key_type else {
+            return 0;
+        };
+        let mods = state as u8 & key_type.mods_mask;
+
+        key_type
+            .map
+            .iter()
+            .find(|entry| entry.active && entry.mods_mask == mods)
+            .map_or(0, |entry| entry.level.into())
+    }
+
+    // the same as XKeysymToKeycode, the lowest level wins over the lowest keycode
+    fn keysym_to_keycode(&self, keysym: Keysym) -> u8 {
+        let width = self
+            .keys
+            .iter()
+            .map(|key| key.syms.len())
+            .max()
+            .unwrap_or(0);
+        (0..width)
+            .find_map(|index| {
+                self.keys
+                    .iter()
+                    .position(|key| key.syms.get(index) == Some(&keysym))
+            })
+            .map_or(0, |position| self.min_keycode + position as u8)
+    }
+
+    // the modifiers of the first entry of the key type which reaches the keysym on the key
+    // keysym_to_keycode picks. XkbKeysymToModifiers ORs together every entry instead, which
+    // can cancel out like Shift and Lock do for a letter
+    fn keysym_to_mods(&self, keysym: Keysym) -> u32 {
+        let keycode = self.keysym_to_keycode(keysym);
+        let Some(key) = self.key(keycode) else {
+            return 0;
+        };
+        let Some(index) = key.syms.iter().position(|sym| *sym == keysym) else {
+            return 0;
+        };
+        let width = usize::from(key.width.max(1));
+        let (group, level) = (index / width, index % width);
+
+        key.kt_index
+            .get(group)
+            .and_then(|kt_index| self.types.get(usize::from(*kt_index)))
+            .and_then(|key_type| {
+                key_type
+                    .map
+                    .iter()
+                    .find(|entry| entry.active && usize::from(entry.level) == level)
+            })
+            .map_or(0, |entry| entry.mods_mask.into())
+    }
+
+    fn max_keycode(&self) -> u8 {
+        self.max_keycode
+    }
+}
+
+// a keymap compiled by xkbcommon, used instead of the server's to pick the keysym to send
+#[derive(Clone)]
+pub(crate) struct Keymap {
+    pub(crate) source: String,
+    #[cfg(feature = "xkbcommon")]
+    pub(crate) keymap: xkb::Keymap,
+}
+
+impl std::fmt::Debug for Keymap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Keymap").field(&self.source).finish()
+    }
+}
+
+#[cfg(feature = "xkbcommon")]
+impl Keymap {
+    pub(crate) fn compile(input: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let keymap = if input.trim_start().starts_with("xkb_keymap") {
+            input.to_string()
+        } else {
+            std::fs::read_to_string(input)?
+        };
+
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_string(
+            &context,
+            keymap,
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .ok_or("failed to compile the keymap given to --keymap-string, `xkbcomp` shows why")?;
+
+        Ok(Self {
+            source: input.to_string(),
+            keymap,
+        })
+    }
+
+    pub(crate) fn keysym(&self, keycode: u8, layout_index: usize, state: u16) -> Keysym {
+        let mut xkb_state = xkb::State::new(&self.keymap);
+        // the eight core modifiers are the first eight of xkb
+        xkb_state.update_mask((state & 0xff) as _, 0, 0, 0, 0, layout_index as _);
+        xkb_state.key_get_one_sym(keycode as _) as _
+    }
+
+    pub(crate) fn keysym_at_level(&self, keycode: u8, layout_index: usize, level: u32) -> Keysym {
+        self.keymap
+            .key_get_syms_by_level(keycode as _, layout_index as _, level)
+            .first()
+            .map_or(0, |keysym| *keysym)
+    }
+}
+
+// without xkbcommon no keymap can be compiled, so the lookups are never reached
+#[cfg(not(feature = "xkbcommon"))]
+impl Keymap {
+    pub(crate) fn compile(_input: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Err("--keymap-string needs mmk to be built with the `xkbcommon` feature".into())
+    }
+
+    pub(crate) fn keysym(&self, _keycode: u8, _layout_index: usize, _state: u16) -> Keysym {
+        0
+    }
+
+    pub(crate) fn keysym_at_level(
+        &self,
+        _keycode: u8,
+        _layout_index: usize,
+        _level: u32,
+    ) -> Keysym {
+        0
+    }
+}
+
+// a keysym by its name like `xkb_keysym_from_name`, or as `U20AC` or `0x20ac`
+pub(crate) fn parse_keysym(name: &str) -> Result<Keysym, Box<dyn std::error::Error>> {
+    let unknown = || format!("unknown keysym `{name}`");
+    if let Some((_, keysym)) = crate::keysyms::KEYSYMS
+        .iter()
+        .find(|(known, _)| *known == name)
+    {
+        return Ok(*keysym);
+    }
+    if let Some(hex) = name.strip_prefix('U').filter(|hex| hex.len() >= 4) {
+        let codepoint = u32::from_str_radix(hex, 16).map_err(|_| unknown())?;
+        if !(0x100..=0x10ffff).contains(&codepoint) {
+            return Err(unknown().into());
+        }
+        return Ok(0x100_0000 + codepoint);
+    }
+    match name
+        .strip_prefix("0x")
+        .map(|hex| Keysym::from_str_radix(hex, 16))
+    {
+        Some(Ok(keysym)) if keysym != 0 => Ok(keysym),
+        _ => Err(unknown().into()),
+    }
+}
+
+// the NumLock and CapsLock modifiers which are currently locked on the core keyboard
+pub(crate) fn locked_modifiers(conn: &impl Connection, keymap: &ServerKeymap) -> u16 {
+    let Ok(Ok(state)) = conn
+        .xkb_get_state(ID::USE_CORE_KBD.into())
+        .map(|cookie| cookie.reply())
+    else {
+        return 0;
+    };
+
+    u16::from(state.locked_mods) & keymap.lock_mask()
+}
+
+// whether the keysym stands for a character, see the keysym encoding in the X11 protocol appendix
+pub(crate) fn is_printable_keysym(keysym: Keysym) -> bool {
+    match keysym {
+        // latin 1 maps straight to its code point
+        0x20..=0x7e | 0xa0..=0xff => true,
+        // the legacy character sets, cyrillic, greek and so on
+        0x100..=0x13be => true,
+        // the keypad digits and operators
+        0xffaa..=0xffb9 => true,
+        // everything else in unicode is the code point plus 0x01000000
+        0x0100_0000..=0x0110_ffff => {
+            char::from_u32(keysym - 0x0100_0000).is_some_and(|c| !c.is_control())
+        }
+        _ => false,
+    }
+}
+
+// dead_grave up to dead_greek, which only compose with the next key instead of typing
+pub(crate) fn is_dead_keysym(keysym: Keysym) -> bool {
+    (0xfe50..=0xfe8c).contains(&keysym)
+}
+
+pub(crate) fn self_test(keymap: &impl KeymapSource) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut names: Vec<String> = ('a'..='z').chain('0'..='9').map(String::from).collect();
+    names.extend(["space", "Return", "BackSpace", "Tab", "Escape"].map(String::from));
+
+    let (mut passed, mut failed) = (0, 0);
+    for name in names {
+        let keysym = parse_keysym(&name)?;
+        let keycode = keymap.keysym_to_keycode(keysym);
+        // the keysym may live on any group or shift level of the keycode
+        let round_trip = keycode != 0
+            && (0..4).any(|group| {
+                (0..2).any(|level| keymap.keycode_to_keysym(keycode, group, level) == keysym)
+            });
+
+        if round_trip {
+            passed += 1;
+            println!("ok\t{name} -> {keycode}");
+        } else {
+            failed += 1;
+            println!("FAIL\t{name} -> {keycode}");
+        }
+    }
+    println!("{passed} passed, {failed} failed");
+
+    Ok(failed == 0)
+}
+
+// the name of a keysym like `xkb_keysym_get_name`
+pub(crate) fn keysym_name(keysym: Keysym) -> String {
+    match crate::keysyms::KEYSYMS
+        .iter()
+        .find(|(_, known)| *known == keysym)
+    {
+        Some((name, _)) => name.to_string(),
+        None if (0x100_0100..=0x110_ffff).contains(&keysym) => {
+            format!("U{:04X}", keysym - 0x100_0000)
+        }
+        None => format!("{keysym:#010x}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{server_keymap, TestKeymap};
+
+    #[test]
+    fn self_test_needs_every_keysym_on_a_key() {
+        let keysyms: Vec<Keysym> = ('a'..='z')
+            .chain('0'..='9')
+            .map(u32::from)
+            .chain([0x20, 0xff0d, 0xff08, 0xff09, 0xff1b])
+            .collect();
+        // only in the second group the keysyms have no keycode to type them with
+        let keys: Vec<[[Keysym; 2]; 2]> = keysyms
+            .iter()
+            .map(|keysym| [[0x1000000 + keysym, 0], [*keysym, 0]])
+            .collect();
+        assert!(!self_test(&TestKeymap::new(&keys)).unwrap());
+
+        let keys: Vec<[[Keysym; 2]; 2]> = keysyms
+            .iter()
+            .map(|keysym| [[*keysym, 0], [0, 0]])
+            .collect();
+        assert!(self_test(&TestKeymap::new(&keys)).unwrap());
+        // Escape is missing
+        assert!(!self_test(&TestKeymap::new(&keys[..keys.len() - 1])).unwrap());
+    }
+
+    #[test]
+    fn group_lock_takes_the_four_xkb_groups() {
+        assert_eq!(group_to_lock(0).unwrap(), Group::M1);
+        assert_eq!(group_to_lock(1).unwrap(), Group::M2);
+        assert_eq!(group_to_lock(3).unwrap(), Group::M4);
+        assert!(group_to_lock(4).is_err());
+        assert!(group_to_lock(256).is_err());
+    }
+
+    #[test]
+    fn shift_level_follows_the_key_type() {
+        let keymap = server_keymap();
+        let (shift, lock) = (u16::from(ModMask::SHIFT), u16::from(ModMask::LOCK));
+
+        assert_eq!(keymap.shift_level(8, 0, 0), 0);
+        assert_eq!(keymap.shift_level(8, 0, shift), 1);
+        assert_eq!(keymap.shift_level(8, 0, lock), 1);
+        // the type has no entry for both, so they cancel out
+        assert_eq!(keymap.shift_level(8, 0, shift | lock), 0);
+        // neither the group nor the modifiers the type ignores pick a level
+        let control = u16::from(ModMask::CONTROL);
+        assert_eq!(keymap.shift_level(8, 1, 0x2000 | control), 0);
+        assert_eq!(keymap.shift_level(8, 1, 0x2000 | shift), 1);
+        // a key the keymap doesn't have
+        assert_eq!(keymap.shift_level(200, 0, shift), 0);
+    }
+
+    #[test]
+    fn num_lock_is_found_in_the_modifier_map() {
+        let keymap = server_keymap();
+        assert_eq!(keymap.lock_mask(), u16::from(ModMask::LOCK | ModMask::M2));
+        // a single way to reach the level, not every one of them
+        assert_eq!(keymap.keysym_to_mods(0x41), u32::from(ModMask::SHIFT));
+        assert_eq!(keymap.keysym_to_mods(0xffb1), u32::from(ModMask::M2));
+        assert_eq!(keymap.keysym_to_mods(0x61), 0);
+    }
+
+    #[test]
+    fn keysym_names_round_trip() {
+        assert_eq!(parse_keysym("a").unwrap(), 0x61);
+        assert_eq!(parse_keysym("Return").unwrap(), 0xff0d);
+        assert_eq!(parse_keysym("XF86AudioMute").unwrap(), 0x1008ff12);
+        assert_eq!(parse_keysym("U20AC").unwrap(), 0x10020ac);
+        assert_eq!(parse_keysym("0xff0d").unwrap(), 0xff0d);
+        assert!(parse_keysym("NoSuchKey").is_err());
+        assert!(parse_keysym("U41").is_err());
+
+        assert_eq!(keysym_name(0xff0d), "Return");
+        assert_eq!(keysym_name(0x10020ac), "U20AC");
+        assert_eq!(keysym_name(0x12345678), "0x12345678");
+    }
+}
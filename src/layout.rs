@@ -0,0 +1,426 @@
+//! The Xkb layouts the X server knows and which one each window uses.
+
+use std::collections::HashMap;
+
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xkb::{ConnectionExt as _, NameDetail, ID},
+        xproto::{AtomEnum, ConnectionExt, PropMode},
+    },
+    wrapper::ConnectionExt as _,
+};
+
+use crate::{
+    config::{json_string, Config, LayoutSpec, Output},
+    matching::{atom_name, window_class, Atoms},
+    window::Tracked,
+};
+
+pub(crate) fn load_layout_memory(
+    path: &str,
+) -> Result<HashMap<String, usize>, Box<dyn std::error::Error>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut ret = HashMap::new();
+    for line in contents.lines() {
+        // each line is `<class>.<instance>\t<layout>`
+        if let Some((class, layout)) = line.rsplit_once('\t') {
+            ret.insert(class.to_string(), layout.trim().parse()?);
+        }
+    }
+
+    Ok(ret)
+}
+
+pub(crate) fn save_layout_memory(
+    path: &str,
+    memory: &HashMap<String, usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut entries: Vec<_> = memory.iter().collect();
+    entries.sort();
+
+    let mut contents = String::new();
+    for (class, layout) in entries {
+        contents.push_str(&format!("{class}\t{layout}\n"));
+    }
+    std::fs::write(path, contents)?;
+
+    Ok(())
+}
+
+#[derive(PartialEq)]
+pub(crate) struct XkbLayout {
+    pub(crate) index: usize,
+    pub(crate) layout: String,
+    pub(crate) variant: Option<String>,
+}
+
+pub(crate) fn parse_rules_names(value: &[u8]) -> Vec<XkbLayout> {
+    // rules, model, layouts, variants and options separated by NUL bytes
+    let fields: Vec<String> = value
+        .split(|byte| *byte == 0)
+        .map(|field| String::from_utf8_lossy(field).into_owned())
+        .collect();
+    let layouts = fields.get(2).map(String::as_str).unwrap_or_default();
+    let variants: Vec<&str> = fields.get(3).map_or(vec![], |v| v.split(',').collect());
+
+    layouts
+        .split(',')
+        .filter(|layout| !layout.is_empty())
+        .enumerate()
+        .map(|(index, layout)| XkbLayout {
+            index,
+            layout: layout.to_string(),
+            variant: variants
+                .get(index)
+                .filter(|variant| !variant.is_empty())
+                .map(|variant| variant.to_string()),
+        })
+        .collect()
+}
+
+pub(crate) fn xkb_layouts(
+    conn: &impl Connection,
+    root: u32,
+    atoms: &Atoms,
+) -> Result<Vec<XkbLayout>, Box<dyn std::error::Error>> {
+    let reply = conn
+        .get_property(
+            false,
+            root,
+            atoms.xkb_rules_names,
+            AtomEnum::STRING,
+            0,
+            1024,
+        )?
+        .reply()?;
+    let layouts = parse_rules_names(&reply.value);
+    if !layouts.is_empty() {
+        return Ok(layouts);
+    }
+
+    // nothing set _XKB_RULES_NAMES, fall back to the group names of the keymap like `English (US)`
+    conn.xkb_use_extension(1, 0)?.reply()?;
+    let names = conn
+        .xkb_get_names(ID::USE_CORE_KBD.into(), NameDetail::GROUP_NAMES)?
+        .reply()?;
+    names
+        .value_list
+        .groups
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+        .map(|(index, atom)| {
+            Ok(XkbLayout {
+                index,
+                layout: atom_name(conn, atom)?,
+                variant: None,
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn publish_layout(
+    conn: &impl Connection,
+    root: u32,
+    atoms: &Atoms,
+    layouts: &[XkbLayout],
+    index: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    conn.change_property8(
+        PropMode::REPLACE,
+        root,
+        atoms.mmk_active_layout,
+        AtomEnum::STRING,
+        active_layout_value(layouts, index).as_bytes(),
+    )?;
+    conn.flush()?;
+
+    Ok(())
+}
+
+// `<index>:<layout>` for _MMK_ACTIVE_LAYOUT, the name is left empty if the server doesn't know it
+fn active_layout_value(layouts: &[XkbLayout], index: usize) -> String {
+    let name = layouts
+        .iter()
+        .find(|layout| layout.index == index)
+        .map(|layout| layout.layout.as_str())
+        .unwrap_or_default();
+
+    format!("{index}:{name}")
+}
+
+// the layout `by` layouts after `layout`, or before it if negative, wrapping around the `count`
+// layouts of the server
+pub(crate) fn shifted_layout(layout: usize, by: i64, count: usize) -> usize {
+    (layout as i64 + by).rem_euclid(count.max(1) as i64) as usize
+}
+
+// the layout a language is usually typed with, for the languages whose code differs from it
+pub(crate) fn locale_layout(lang: &str) -> String {
+    // `pt_BR.UTF-8` and `pt-BR` only need the language
+    let lang = lang
+        .split(['_', '-', '.'])
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let layout = match &lang[..] {
+        "en" => "us",
+        "cs" => "cz",
+        "da" => "dk",
+        "el" => "gr",
+        "et" => "ee",
+        "he" => "il",
+        "ja" => "jp",
+        "ko" => "kr",
+        "sl" => "si",
+        "sr" => "rs",
+        "sv" => "se",
+        "uk" => "ua",
+        "be" => "by",
+        "ka" => "ge",
+        "kk" => "kz",
+        "hy" => "am",
+        "fa" => "ir",
+        "ar" => "ara",
+        "zh" => "cn",
+        "vi" => "vn",
+        "nb" | "nn" => "no",
+        _ => return lang,
+    };
+
+    layout.to_string()
+}
+
+pub(crate) fn resolve_locale(
+    conn: &impl Connection,
+    root: u32,
+    atoms: &Atoms,
+    lang: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    locale_group(&xkb_layouts(conn, root, atoms)?, lang)
+}
+
+// the group of the first of `layouts` which types `lang`
+fn locale_group(layouts: &[XkbLayout], lang: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let wanted = locale_layout(lang);
+    layouts
+        .iter()
+        .find(|layout| layout.layout == wanted)
+        .map(|layout| layout.index)
+        .ok_or_else(|| {
+            let configured: Vec<&str> = layouts
+                .iter()
+                .map(|layout| layout.layout.as_str())
+                .collect();
+            format!(
+                "no configured layout for `{lang}`, expected `{wanted}` among `{}`",
+                configured.join(",")
+            )
+            .into()
+        })
+}
+
+pub(crate) fn resolve_layout_names(
+    conn: &impl Connection,
+    root: u32,
+    atoms: &Atoms,
+    specs: &[LayoutSpec],
+) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let layouts = xkb_layouts(conn, root, atoms)?;
+    specs
+        .iter()
+        .map(|spec| match spec {
+            LayoutSpec::Index(index) => Ok(*index),
+            LayoutSpec::Name(name) => layouts
+                .iter()
+                .find(|layout| &layout.layout == name || layout.variant.as_ref() == Some(name))
+                .map(|layout| layout.index)
+                .ok_or_else(|| {
+                    let configured: Vec<String> = layouts
+                        .iter()
+                        .map(|layout| match &layout.variant {
+                            Some(variant) => format!("{} ({variant})", layout.layout),
+                            None => layout.layout.clone(),
+                        })
+                        .collect();
+                    format!(
+                        "no configured layout is called `{name}`, the layouts are `{}`",
+                        configured.join(", ")
+                    )
+                    .into()
+                }),
+        })
+        .collect()
+}
+
+// the one of two groups which isn't active, for --layout auto-toggle
+pub(crate) fn other_group(active: usize) -> usize {
+    if active == 0 {
+        1
+    } else {
+        0
+    }
+}
+
+// moves the windows' layouts to the index the same layout has after setxkbmap changed the
+// layouts, returns the windows whose layout isn't configured anymore
+pub(crate) fn remap_layouts(
+    before: &[XkbLayout],
+    after: &[XkbLayout],
+    tracked: &mut HashMap<u32, Tracked>,
+) -> Vec<u32> {
+    let mut lost = vec![];
+    for (window, record) in tracked.iter_mut() {
+        let moved = before
+            .iter()
+            .find(|layout| layout.index == record.layout)
+            .and_then(|old| {
+                after
+                    .iter()
+                    .find(|new| new.layout == old.layout && new.variant == old.variant)
+            });
+        match moved {
+            Some(new) => record.layout = new.index,
+            None => lost.push(*window),
+        }
+    }
+    lost
+}
+
+pub(crate) fn print_layouts(layouts: &[XkbLayout], output: Output) {
+    match output {
+        Output::Text => {
+            for layout in layouts {
+                match &layout.variant {
+                    Some(variant) => println!("{}: {} ({variant})", layout.index, layout.layout),
+                    None => println!("{}: {}", layout.index, layout.layout),
+                }
+            }
+        }
+        Output::Json => println!("{}", layouts_json(layouts)),
+    }
+}
+
+pub(crate) fn layouts_json(layouts: &[XkbLayout]) -> String {
+    let entries: Vec<String> = layouts
+        .iter()
+        .map(|layout| {
+            format!(
+                "{{\"index\":{},\"layout\":{},\"variant\":{}}}",
+                layout.index,
+                json_string(&layout.layout),
+                layout
+                    .variant
+                    .as_deref()
+                    .map_or("null".to_string(), json_string)
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+pub(crate) fn resolve_layout(
+    conn: &impl Connection,
+    config: &Config,
+    window: u32,
+    layout: Option<usize>,
+    layout_memory: &mut HashMap<String, usize>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    // an explicit --layout wins over the remembered one and replaces it
+    let class = window_class(conn, config, window)?;
+    let layout = match (layout, class.as_ref()) {
+        (Some(layout), _) => layout,
+        (None, Some(class)) => layout_memory.get(class).copied().unwrap_or(0),
+        (None, None) => 0,
+    };
+    if let Some(class) = class {
+        layout_memory.insert(class, layout);
+    }
+
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts_are_listed_as_json() {
+        let layouts =
+            parse_rules_names(b"evdev\0pc105\0us,de,ru\0,nodeadkeys,\0grp:alt_shift_toggle\0");
+        assert_eq!(
+            layouts_json(&layouts),
+            r#"[{"index":0,"layout":"us","variant":null},{"index":1,"layout":"de","variant":"nodeadkeys"},{"index":2,"layout":"ru","variant":null}]"#
+        );
+        // setxkbmap leaves out the variants if there are none
+        let layouts = parse_rules_names(b"evdev\0pc105\0us\0\0\0");
+        assert_eq!(
+            layouts_json(&layouts),
+            r#"[{"index":0,"layout":"us","variant":null}]"#
+        );
+        assert_eq!(layouts_json(&[]), "[]");
+    }
+
+    #[test]
+    fn the_indicator_names_the_active_layout() {
+        let layouts = parse_rules_names(b"evdev\0pc105\0us,de,ru\0,nodeadkeys,\0\0");
+        assert_eq!(active_layout_value(&layouts, 0), "0:us");
+        assert_eq!(active_layout_value(&layouts, 1), "1:de");
+        assert_eq!(active_layout_value(&layouts, 2), "2:ru");
+        assert_eq!(active_layout_value(&layouts, 3), "3:");
+        assert_eq!(active_layout_value(&[], 1), "1:");
+    }
+
+    #[test]
+    fn auto_toggle_picks_the_group_not_active() {
+        assert_eq!(other_group(0), 1);
+        assert_eq!(other_group(1), 0);
+        // toggling twice lands back on the active group
+        assert_eq!(other_group(other_group(0)), 0);
+        assert_eq!(other_group(other_group(1)), 1);
+    }
+
+    #[test]
+    fn languages_pick_the_group_of_their_layout() {
+        let layouts = parse_rules_names(b"evdev\0pc105\0us,de,ru,ua\0\0\0");
+        let group = |lang| locale_group(&layouts, lang).map_err(|e| e.to_string());
+
+        assert_eq!(group("de"), Ok(1));
+        assert_eq!(group("ru"), Ok(2));
+        // the language and the layout go by different codes
+        assert_eq!(group("en"), Ok(0));
+        assert_eq!(group("uk"), Ok(3));
+        // the rest of a locale doesn't matter
+        assert_eq!(group("ru_RU.UTF-8"), Ok(2));
+        assert_eq!(group("DE-at"), Ok(1));
+        assert_eq!(
+            group("fr"),
+            Err("no configured layout for `fr`, expected `fr` among `us,de,ru,ua`".to_string())
+        );
+    }
+
+    #[test]
+    fn layout_memory_round_trips() {
+        let path = std::env::temp_dir().join(format!("mmk-layouts-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let memory = HashMap::from([
+            ("firefox.Navigator".to_string(), 1),
+            ("Emacs.emacs".to_string(), 0),
+            // a tab can't be part of a class, the last one separates the layout
+            ("with spaces.and.dots".to_string(), 2),
+        ]);
+
+        save_layout_memory(path, &memory).unwrap();
+        let loaded = load_layout_memory(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(loaded.unwrap(), memory);
+        // nothing remembered yet
+        assert_eq!(load_layout_memory(path).unwrap(), HashMap::new());
+    }
+}
@@ -0,0 +1,669 @@
+//! The window matching and key translation of mmk, which the `mmk` binary runs with [`start`].
+
+use std::{collections::HashMap, fmt, sync::Arc, time::Instant};
+
+use x11rb::{
+    connect,
+    connection::Connection,
+    errors::{ConnectionError, ReplyError},
+    protocol::{xkb::ConnectionExt as _, ErrorKind},
+    rust_connection::RustConnection,
+};
+
+pub use crate::{
+    config::Config,
+    keymap::{KeymapSource, ServerKeymap},
+    matching::{
+        find_windows_by_class, find_windows_by_instance, find_windows_by_name, find_windows_by_pid,
+        rec_query_tree, Atoms, MatchCache,
+    },
+    translate::{translate, KeyEvent, Translated},
+};
+
+mod config;
+mod event_loop;
+mod filter;
+mod keymap;
+mod keysyms;
+mod layout;
+mod matching;
+#[cfg(test)]
+mod testing;
+mod translate;
+mod window;
+
+use crate::{
+    config::{
+        default_rules_file, dump_config, has_criteria, load_criteria_file, print_json_schema,
+        usage, MatchMode, XErrorMode,
+    },
+    event_loop::{
+        emit_metrics, handle_signals, listen_on_socket, load_state, report_unmapped_keysyms, run,
+        save_state, Shared,
+    },
+    keymap::{lock_group, self_test},
+    layout::{print_layouts, resolve_layout_names, resolve_locale, xkb_layouts},
+    matching::{
+        find_targets, is_xwayland, list_atoms, list_windows, print_matches, read_window_ids,
+        select_window, unix_seconds, watch_matches, Target, DEFAULT_MATCH_CACHE_TTL,
+    },
+    translate::explain_key,
+    window::{capture_screenshot, create_test_window, destroy_window, restore_windows},
+};
+
+// the longest wait between two attempts of --reconnect, in seconds
+const MAX_RECONNECT_DELAY: u64 = 30;
+
+// everything set up again on a new connection by --reconnect
+struct Reconnected {
+    conn: RustConnection,
+    root: u32,
+    server_keymap: ServerKeymap,
+    atoms: Atoms,
+    targets: Vec<Target>,
+}
+
+// waits for the X server to come back, trying again with a growing delay, and matches the windows
+// again on the new connection. the grabs went away along with the old one
+fn reconnect_display(
+    config: &Config,
+    rules: &[Config],
+) -> Result<Reconnected, Box<dyn std::error::Error>> {
+    let wait = |delay| {
+        if !config.quiet {
+            eprintln!("warning: Lost the connection to the X server, reconnecting in {delay}s.");
+        }
+        std::thread::sleep(std::time::Duration::from_secs(delay));
+    };
+
+    with_backoff(wait, || {
+        let Ok((conn, root)) = open_display() else {
+            return Ok(None);
+        };
+        let server_keymap = ServerKeymap::new(&conn)?;
+        let atoms = Atoms::new(&conn)?;
+        let targets = find_targets(
+            &conn,
+            root,
+            rules,
+            &atoms,
+            &mut MatchCache::default(),
+            &mut None,
+        )?;
+        Ok(Some(Reconnected {
+            conn,
+            root,
+            server_keymap,
+            atoms,
+            targets,
+        }))
+    })
+}
+
+// calls `attempt` until it gives something, `wait` is told the seconds to wait before each call.
+// an error of `attempt` ends the retrying
+fn with_backoff<T, E>(
+    mut wait: impl FnMut(u64),
+    mut attempt: impl FnMut() -> Result<Option<T>, E>,
+) -> Result<T, E> {
+    let mut delay = 1;
+    loop {
+        wait(delay);
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        if let Some(ret) = attempt()? {
+            return Ok(ret);
+        }
+    }
+}
+
+// creates the window of --test-window, prints its id and adds it to the `targets`
+fn open_test_window(
+    conn: &impl Connection,
+    root: u32,
+    config: &Config,
+    targets: &mut Vec<Target>,
+) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+    if !config.test_window {
+        return Ok(None);
+    }
+    let window = create_test_window(conn, root)?;
+    println!("0x{window:08x}");
+    targets.push((window, config.layout.first().copied()));
+
+    Ok(Some(window))
+}
+
+// running as a daemon outlives restarts of the X server unless told otherwise
+fn reconnects(config: &Config) -> bool {
+    !config.no_reconnect
+        && (config.reconnect || config.match_mode == MatchMode::Live || config.follow_class)
+}
+
+/// The exit code of the `mmk` binary when [`start`] fails with [`GrabTaken`].
+pub const GRAB_TAKEN_EXIT_CODE: i32 = 3;
+
+/// The error of [`start`] when another client already grabbed the keyboard of the only window to
+/// run on, usually another mmk. The windows grabbed until then are given back before it returns.
+#[derive(Debug)]
+pub struct GrabTaken {
+    /// The window whose keys couldn't be grabbed.
+    pub window: u32,
+}
+
+impl fmt::Display for GrabTaken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Another client already grabbed the keyboard of 0x{:08x}, is mmk already running?",
+            self.window
+        )
+    }
+}
+
+impl std::error::Error for GrabTaken {}
+
+fn open_display() -> Result<(RustConnection, u32), Box<dyn std::error::Error>> {
+    let (conn, screen) = connect(None)?;
+    let root = conn.setup().roots[screen].root;
+    if !conn.xkb_use_extension(1, 0)?.reply()?.supported {
+        return Err("the X server lacks a usable XKEYBOARD extension".into());
+    }
+
+    Ok((conn, root))
+}
+
+// BadAccess, the answer to grabbing keys another client already grabbed
+fn is_grab_taken(e: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        e.downcast_ref::<ReplyError>(),
+        Some(ReplyError::X11Error(e)) if e.error_kind == ErrorKind::Access
+    )
+}
+
+fn is_connection_lost(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.is::<ConnectionError>()
+        || matches!(
+            e.downcast_ref::<ReplyError>(),
+            Some(ReplyError::ConnectionError(_))
+        )
+}
+
+// with --x-error-mode lenient an error from the X server only skips what caused it
+fn skip_x_error<T>(
+    config: &Config,
+    result: Result<T, Box<dyn std::error::Error>>,
+) -> Result<Option<T>, Box<dyn std::error::Error>> {
+    match result {
+        Err(e)
+            if config.x_error_mode != XErrorMode::Strict
+                && matches!(
+                    e.downcast_ref::<ReplyError>(),
+                    Some(ReplyError::X11Error(_))
+                ) =>
+        {
+            Ok(None)
+        }
+        result => result.map(Some),
+    }
+}
+
+// reports how long a phase of the startup took and starts timing the next one
+fn profile_phase(name: &str, started: &mut Option<Instant>) {
+    if let Some(line) = phase_timing(name, started, Instant::now) {
+        eprintln!("{line}");
+    }
+}
+
+// the line --profile-startup prints for a phase, `now` reads the clock
+fn phase_timing(
+    name: &str,
+    started: &mut Option<Instant>,
+    now: impl FnOnce() -> Instant,
+) -> Option<String> {
+    let started = started.as_mut()?;
+    let now = now();
+    let line = format!("startup: {name} took {:.2?}", now - *started);
+    *started = now;
+    Some(line)
+}
+
+fn warn(config: &Config, message: &str) {
+    if !config.quiet {
+        eprintln!("warning: {message}");
+    }
+}
+
+// warns about one of the conditions --strict stops at, a malformed WM_CLASS or a conflicting grab.
+// the error unwinds through `start`, which gives the windows back
+fn warn_or_fail(config: &Config, message: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if config.strict {
+        return Err(message.into());
+    }
+    warn(config, message);
+    Ok(())
+}
+
+// whether to warn once about Xwayland, --no-xwayland-warning and --quiet both silence it and
+// then the server isn't even asked
+fn warns_about_xwayland(config: &Config) -> bool {
+    !config.no_xwayland_warning && !config.quiet
+}
+
+/// Runs mmk the way the `config` asks for: finds the windows, translates their keys until told
+/// to quit and hands them back afterwards. The options which only print something, like
+/// `--help` or `--dry-run`, return once they are done.
+pub fn start(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    if config.version {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    if config.help {
+        print!("{}", usage());
+        return Ok(());
+    }
+    if config.json_schema {
+        print_json_schema();
+        return Ok(());
+    }
+    if config.translate_buffer == Some(0) {
+        return Err("--translate-buffer needs room for at least one key.".into());
+    }
+    let shared = Arc::new(Shared {
+        started_at: unix_seconds(),
+        ..Default::default()
+    });
+    if let Some(path) = &config.state_file {
+        load_state(path, &shared)?;
+    }
+    let mut started = config.profile_startup.then(Instant::now);
+    let (mut conn, mut root) = open_display()?;
+    profile_phase("connecting to the X server", &mut started);
+    let mut server_keymap = ServerKeymap::new(&conn)?;
+    profile_phase("reading the keymap", &mut started);
+
+    let mut atoms = Atoms::new(&conn)?;
+
+    let config = match &config.layout_locale {
+        Some(lang) => {
+            let index = resolve_locale(&conn, root, &atoms, lang)?;
+            config.clone().with_layout(vec![index])
+        }
+        None => config,
+    };
+    let config = match &config.window_ids_file {
+        Some(path) => {
+            let ids = read_window_ids(&config, path)?;
+            config.clone().with_window_ids(ids)
+        }
+        None => config,
+    };
+    let config = if config.layout_names.is_empty() {
+        config
+    } else {
+        let layout = resolve_layout_names(&conn, root, &atoms, &config.layout_names)?;
+        config.clone().with_layout(layout)
+    };
+
+    if warns_about_xwayland(&config) && is_xwayland(&conn)? {
+        warn(&config, "This looks like Xwayland, keys typed into native Wayland windows won't reach mmk and grabs may behave differently.");
+    }
+
+    if config.self_test {
+        if !self_test(&server_keymap)? {
+            return Err("Some keysyms didn't survive the round trip through the keymap.".into());
+        }
+        return Ok(());
+    }
+
+    if config.list_layouts {
+        print_layouts(&xkb_layouts(&conn, root, &atoms)?, config.output);
+        return Ok(());
+    }
+
+    if let Some(keycode) = config.explain_key {
+        explain_key(&conn, &server_keymap, root, keycode, &config)?;
+        return Ok(());
+    }
+
+    if let Some(wid) = config.list_atoms {
+        list_atoms(&conn, wid)?;
+        return Ok(());
+    }
+
+    if config.pause_on_signal && config.switch_layout_on_signal {
+        return Err(
+            "--pause-on-signal and --switch-layout-on-signal both use SIGUSR1 and SIGUSR2.".into(),
+        );
+    }
+
+    if config.layout_toggle && xkb_layouts(&conn, root, &atoms)?.len() != 2 {
+        return Err(
+            "--layout auto-toggle needs exactly two layouts, set them up with setxkbmap.".into(),
+        );
+    }
+
+    let config = if config.select {
+        let window = select_window(&conn, root)?;
+        if !config.quiet {
+            eprintln!("mmk: selected 0x{window:08x}");
+        }
+        config.clone().with_wid(window)
+    } else {
+        config
+    };
+    let config = match default_rules_file() {
+        Some(path) if config.criteria_file.is_none() && !has_criteria(&config) => {
+            config.clone().with_criteria_file(path)
+        }
+        _ => config,
+    };
+    let mut rules = match &config.criteria_file {
+        Some(path) => load_criteria_file(path)?,
+        None => vec![config.clone()],
+    };
+    for rule in rules.iter_mut() {
+        if let Some(lang) = &rule.layout_locale {
+            rule.layout = vec![resolve_locale(&conn, root, &atoms, lang)?];
+        }
+        if !rule.layout_names.is_empty() {
+            rule.layout = resolve_layout_names(&conn, root, &atoms, &rule.layout_names)?;
+        }
+        if let (Some(path), None) = (&rule.window_ids_file, &rule.window_ids) {
+            rule.window_ids = Some(read_window_ids(&config, path)?);
+        }
+        *rule = config.merge_rule(rule);
+    }
+    if config.dump_config {
+        dump_config(&config, &rules);
+        return Ok(());
+    }
+
+    let mut cache = match &config.match_cache {
+        Some(path) => MatchCache::load(
+            path,
+            config.match_cache_ttl.unwrap_or(DEFAULT_MATCH_CACHE_TTL),
+        )?,
+        None => MatchCache::default(),
+    };
+    profile_phase("resolving layouts and rules", &mut started);
+    let mut targets = find_targets(&conn, root, &rules, &atoms, &mut cache, &mut started)?;
+    cache.save()?;
+
+    if config.list_windows {
+        let windows: Vec<u32> = targets.iter().map(|(window, _)| *window).collect();
+        list_windows(&conn, &config, &atoms, &windows)?;
+        return Ok(());
+    }
+    // without --dry-run watching means grabbing the windows which show up later
+    let config = if config.watch && !config.dry_run {
+        config.clone().with_match_mode(MatchMode::Live)
+    } else {
+        config
+    };
+    if config.dry_run {
+        if config.watch {
+            watch_matches(&conn, root, &config, &rules, &atoms)?;
+        } else {
+            let windows: Vec<u32> = targets.iter().map(|(window, _)| *window).collect();
+            print_matches(&conn, &config, &windows)?;
+            if let Some(dir) = &config.screenshot_dir {
+                for window in windows {
+                    capture_screenshot(&conn, &config, dir, window)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let mut test_window = open_test_window(&conn, root, &config, &mut targets)?;
+    // a client holds at most one keyboard grab, a second window would take it from the first
+    if config.grab_keyboard
+        && (targets.len() > 1 || config.match_mode == MatchMode::Live || config.follow_class)
+    {
+        return Err("--grab-keyboard runs on a single window, without --match-mode live and --follow-class.".into());
+    }
+
+    handle_signals(&config, &shared)?;
+    if let Some(path) = &config.socket {
+        listen_on_socket(path, &shared)?;
+    }
+    if let Some(path) = &config.metrics {
+        emit_metrics(path, &config, &shared);
+    }
+
+    let mut previous_group = config
+        .group_lock
+        .map(|group| lock_group(&conn, group))
+        .transpose()?;
+
+    let mut tracked = HashMap::new();
+    let mut result = run(
+        &conn,
+        &mut server_keymap,
+        root,
+        &atoms,
+        &config,
+        &rules,
+        &shared,
+        &mut tracked,
+        targets,
+        started,
+    );
+    while reconnects(&config) && result.as_ref().is_err_and(|e| is_connection_lost(&**e)) {
+        let reconnected = reconnect_display(&config, &rules)?;
+        (conn, root, server_keymap, atoms) = (
+            reconnected.conn,
+            reconnected.root,
+            reconnected.server_keymap,
+            reconnected.atoms,
+        );
+        // the server dropped the old grabs along with the connection, and the test window too
+        tracked.clear();
+        let mut targets = reconnected.targets;
+        test_window = open_test_window(&conn, root, &config, &mut targets)?;
+        previous_group = config
+            .group_lock
+            .map(|group| lock_group(&conn, group))
+            .transpose()?;
+
+        result = run(
+            &conn,
+            &mut server_keymap,
+            root,
+            &atoms,
+            &config,
+            &rules,
+            &shared,
+            &mut tracked,
+            targets,
+            None,
+        );
+    }
+
+    // an error in the event loop still leaves the connection usable for the restore
+    let restored = if result.as_ref().is_err_and(|e| is_connection_lost(&**e)) {
+        Ok(())
+    } else {
+        let group = match previous_group {
+            Some(group) => lock_group(&conn, group).map(|_| ()),
+            None => Ok(()),
+        };
+        let restored = restore_windows(&conn, &atoms, &tracked).and(group);
+        // only after the restore, which still changes it
+        let destroyed = match test_window {
+            Some(window) => destroy_window(&conn, window),
+            None => Ok(()),
+        };
+        restored.and(destroyed)
+    };
+
+    if config.report_unmapped_keysyms && !config.quiet {
+        report_unmapped_keysyms(&shared);
+    }
+    if let Some(path) = &config.state_file {
+        save_state(path, &shared)?;
+    }
+    if let Some(path) = &config.socket {
+        let _ = std::fs::remove_file(path);
+    }
+    result.and(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, x11_error, FakeServer};
+    use x11rb::protocol::Request;
+
+    #[test]
+    fn the_test_window_joins_the_windows_to_run_on() {
+        let server = FakeServer::new(vec![]);
+        let mut targets = vec![(2, None)];
+        let with_window = config(&["--test-window", "--layout", "1"]);
+
+        let window = open_test_window(&server, 1, &with_window, &mut targets).unwrap();
+        let window = window.unwrap();
+        assert_eq!(targets, [(2, None), (window, Some(1))]);
+        assert!(matches!(
+            &server.take_requests()[..],
+            [
+                Request::CreateWindow(_),
+                Request::ChangeProperty(_),
+                Request::MapWindow(_)
+            ]
+        ));
+        // as after a reconnect, on a server which never saw the first one
+        let server = FakeServer::new(vec![]);
+        assert!(open_test_window(&server, 1, &with_window, &mut vec![])
+            .unwrap()
+            .is_some());
+
+        let without = config(&["--layout", "1"]);
+        assert_eq!(
+            open_test_window(&server, 1, &without, &mut targets).unwrap(),
+            None
+        );
+        assert_eq!(targets.len(), 2);
+    }
+    use std::time::Duration;
+
+    #[test]
+    fn profiling_reports_every_phase() {
+        let start = Instant::now();
+        let phases = [
+            ("connecting to the X server", 5),
+            ("reading the keymap", 20),
+            ("walking the window tree", 300),
+            ("matching the class", 40),
+            ("grabbing the windows", 1000),
+        ];
+        let mut started = Some(start);
+        let mut clock = start;
+        let lines: Vec<_> = phases
+            .iter()
+            .map(|&(name, millis)| {
+                clock += Duration::from_millis(millis);
+                phase_timing(name, &mut started, || clock)
+            })
+            .collect();
+
+        assert_eq!(
+            lines,
+            [
+                Some("startup: connecting to the X server took 5.00ms".to_owned()),
+                Some("startup: reading the keymap took 20.00ms".to_owned()),
+                Some("startup: walking the window tree took 300.00ms".to_owned()),
+                Some("startup: matching the class took 40.00ms".to_owned()),
+                Some("startup: grabbing the windows took 1.00s".to_owned()),
+            ]
+        );
+        assert_eq!(started, Some(clock));
+
+        let mut unprofiled = None;
+        assert_eq!(
+            phase_timing("reading the keymap", &mut unprofiled, || unreachable!()),
+            None
+        );
+    }
+
+    #[test]
+    fn lenient_x_errors_skip_what_caused_them() {
+        let bad_window = || Err(x11_error(ErrorKind::Window).into());
+        let lost = || Err(ConnectionError::UnknownError.into());
+        let lenient = config(&["--x-error-mode", "lenient"]);
+        let strict = config(&["--x-error-mode", "strict"]);
+
+        assert!(matches!(
+            skip_x_error::<()>(&config(&[]), bad_window()),
+            Ok(None)
+        ));
+        assert!(matches!(
+            skip_x_error::<()>(&lenient, bad_window()),
+            Ok(None)
+        ));
+        let err = skip_x_error::<()>(&strict, bad_window()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ReplyError>(),
+            Some(ReplyError::X11Error(e)) if e.error_kind == ErrorKind::Window
+        ));
+        // only errors of the X server are skipped
+        assert!(skip_x_error::<()>(&lenient, lost()).is_err());
+        assert_eq!(skip_x_error(&strict, Ok(7)).unwrap(), Some(7));
+        assert_eq!(skip_x_error(&lenient, Ok(7)).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn strict_turns_warnings_into_errors() {
+        assert!(warn_or_fail(&config(&[]), "a grab conflicts").is_ok());
+        assert_eq!(
+            warn_or_fail(&config(&["--strict"]), "a grab conflicts")
+                .unwrap_err()
+                .to_string(),
+            "a grab conflicts"
+        );
+    }
+
+    #[test]
+    fn reconnecting_backs_off_until_the_server_is_back() {
+        let mut waits = vec![];
+        let mut attempts = 0;
+        let reconnected = with_backoff(
+            |delay| waits.push(delay),
+            || {
+                attempts += 1;
+                Ok::<_, ()>((attempts == 8).then_some("connection"))
+            },
+        );
+        assert_eq!(reconnected, Ok("connection"));
+        assert_eq!(waits, [1, 2, 4, 8, 16, 30, 30, 30]);
+
+        // the server came back, but setting up on it failed
+        let mut waits = vec![];
+        let failed = with_backoff(|delay| waits.push(delay), || Err::<Option<()>, _>("no xkb"));
+        assert_eq!(failed, Err("no xkb"));
+        assert_eq!(waits, [1]);
+    }
+
+    #[test]
+    fn daemons_reconnect_unless_told_otherwise() {
+        assert!(!reconnects(&config(&[])));
+        assert!(reconnects(&config(&["--reconnect"])));
+        assert!(reconnects(&config(&["--match-mode", "live"])));
+        assert!(!reconnects(&config(&[
+            "--match-mode",
+            "live",
+            "--no-reconnect"
+        ])));
+    }
+
+    #[test]
+    fn the_xwayland_warning_can_be_silenced() {
+        assert!(warns_about_xwayland(&config(&[])));
+        assert!(!warns_about_xwayland(&config(&["--no-xwayland-warning"])));
+        assert!(!warns_about_xwayland(&config(&["--quiet"])));
+        assert!(!warns_about_xwayland(&config(&[
+            "--quiet",
+            "--no-xwayland-warning"
+        ])));
+    }
+}
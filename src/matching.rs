@@ -0,0 +1,1842 @@
+//! Finding the windows to run on.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    string::FromUtf8Error,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use regex::Regex;
+use x11rb::{
+    connection::Connection,
+    errors::{ParseError, ReplyError},
+    properties::WmClass,
+    protocol::{
+        xproto::{
+            AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask,
+            GetWindowAttributesReply, GrabMode, GrabStatus, InputFocus, WindowClass,
+        },
+        Event,
+    },
+    CURRENT_TIME, NONE,
+};
+
+use crate::{
+    config::{parse_wid, Combine, Config, DedupBy},
+    profile_phase, skip_x_error, warn, warn_or_fail,
+};
+
+// the window manager lists _NET_WM_STATE_FULLSCREEN in _NET_WM_STATE while it is fullscreen
+pub(crate) fn is_fullscreen(
+    conn: &impl Connection,
+    atoms: &Atoms,
+    window: u32,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let reply = conn
+        .get_property(
+            false,
+            window,
+            atoms.net_wm_state,
+            AtomEnum::ATOM,
+            0,
+            u32::MAX,
+        )?
+        .reply()?;
+
+    Ok(reply
+        .value32()
+        .is_some_and(|mut states| states.any(|state| state == atoms.net_wm_state_fullscreen)))
+}
+
+// the top level windows an EWMH window manager manages, `None` without one
+pub(crate) fn client_list(
+    conn: &impl Connection,
+    root: u32,
+    atom: u32,
+) -> Result<Option<Vec<u32>>, Box<dyn std::error::Error>> {
+    let reply = conn
+        .get_property(false, root, atom, AtomEnum::WINDOW, 0, u32::MAX)?
+        .reply()?;
+
+    Ok(reply.value32().map(|windows| windows.collect()))
+}
+
+/// Adds every window below `win` to `vec`, children before their parents. Windows destroyed
+/// while walking the tree are skipped.
+pub fn rec_query_tree(
+    conn: &impl Connection,
+    win: u32,
+    vec: &mut Vec<u32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    walk_tree(win, vec, &mut |win| match conn.query_tree(win)?.reply() {
+        Ok(reply) => Ok(Some(reply.children)),
+        Err(ReplyError::X11Error(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    })
+}
+
+// adds the descendants of `win` to `vec`, `children` lists them or is None for a window which
+// was destroyed while walking the tree. its own children are skipped then
+pub(crate) fn walk_tree<E>(
+    win: u32,
+    vec: &mut Vec<u32>,
+    children: &mut impl FnMut(u32) -> Result<Option<Vec<u32>>, E>,
+) -> Result<(), E> {
+    if vec.contains(&win) {
+        return Ok(());
+    }
+    for child in children(win)?.unwrap_or_default() {
+        walk_tree(child, vec, children)?;
+        vec.push(child);
+    }
+
+    Ok(())
+}
+
+// see --exclude-wm-frames for the heuristic. a window destroyed while it is looked at is no frame,
+// unless --x-error-mode is strict
+pub(crate) fn is_wm_frame(
+    conn: &impl Connection,
+    config: &Config,
+    root: u32,
+    window: u32,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(skip_x_error(config, check_wm_frame(conn, root, window))?.unwrap_or(false))
+}
+
+fn check_wm_frame(
+    conn: &impl Connection,
+    root: u32,
+    window: u32,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let tree = match conn.query_tree(window)?.reply() {
+        Ok(tree) => tree,
+        Err(ReplyError::X11Error(_)) => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    if tree.parent != root {
+        return Ok(false);
+    }
+    let override_redirect = conn
+        .get_window_attributes(window)?
+        .reply()?
+        .override_redirect;
+    let has_class = wm_class(conn, window)?.is_some();
+
+    looks_like_frame(override_redirect, has_class, &tree.children, |child| {
+        Ok(wm_class(conn, child)?.is_some())
+    })
+}
+
+// a top level window which isn't override-redirect and has no WM_CLASS, but a child with one
+fn looks_like_frame<E>(
+    override_redirect: bool,
+    has_class: bool,
+    children: &[u32],
+    mut child_has_class: impl FnMut(u32) -> Result<bool, E>,
+) -> Result<bool, E> {
+    if override_redirect || has_class {
+        return Ok(false);
+    }
+    for &child in children {
+        if child_has_class(child)? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// only windows which can take input and which someone listens to key events on
+pub(crate) fn takes_input(attrs: &GetWindowAttributesReply) -> bool {
+    attrs.class == WindowClass::INPUT_OUTPUT
+        && attrs.all_event_masks & u32::from(EventMask::KEY_PRESS) != 0
+}
+
+pub(crate) fn input_descendants(
+    conn: &impl Connection,
+    win: u32,
+    vec: &mut Vec<u32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let reply = match conn.query_tree(win)?.reply() {
+        Ok(reply) => reply,
+        // the window was destroyed in the meantime, along with its children
+        Err(ReplyError::X11Error(_)) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for child in reply.children.iter() {
+        match conn.get_window_attributes(*child)?.reply() {
+            Ok(attrs) if takes_input(&attrs) => vec.push(*child),
+            Ok(_) => (),
+            Err(ReplyError::X11Error(_)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+        input_descendants(conn, *child, vec)?;
+    }
+
+    Ok(())
+}
+
+// whether `ancestor` is `win` or one of its parents, which `parent` looks up until the root
+pub(crate) fn descends_from<E>(
+    mut win: u32,
+    ancestor: u32,
+    mut parent: impl FnMut(u32) -> Result<u32, E>,
+) -> Result<bool, E> {
+    while win != NONE {
+        if win == ancestor {
+            return Ok(true);
+        }
+        win = parent(win)?;
+    }
+
+    Ok(false)
+}
+
+pub(crate) fn focused_window(
+    conn: &impl Connection,
+) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+    let focus = conn.get_input_focus()?.reply()?.focus;
+    if focus == NONE || focus == u8::from(InputFocus::POINTER_ROOT).into() {
+        return Ok(None);
+    }
+
+    Ok(Some(focus))
+}
+
+pub(crate) fn has_focus(
+    conn: &impl Connection,
+    win: u32,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    focus_within(focused_window(conn)?, win, |win| {
+        Ok(conn.query_tree(win)?.reply()?.parent)
+    })
+}
+
+// whether the focus is on `win` or one of its children, with --require-focus keys are only
+// translated then
+pub(crate) fn focus_within<E>(
+    focus: Option<u32>,
+    win: u32,
+    parent: impl FnMut(u32) -> Result<u32, E>,
+) -> Result<bool, E> {
+    match focus {
+        Some(focus) => descends_from(focus, win, parent),
+        None => Ok(false),
+    }
+}
+
+pub(crate) fn focus_subtree_target(
+    conn: &impl Connection,
+    grabbed: u32,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    focus_target(focused_window(conn)?, grabbed, |win| {
+        Ok(conn.query_tree(win)?.reply()?.parent)
+    })
+}
+
+// where --emit-to-focus-subtree sends the keys of `grabbed`, the focused window when it is
+// `grabbed` or one of its children and `grabbed` itself otherwise
+pub(crate) fn focus_target<E>(
+    focus: Option<u32>,
+    grabbed: u32,
+    parent: impl FnMut(u32) -> Result<u32, E>,
+) -> Result<u32, E> {
+    match focus {
+        Some(focus) if descends_from(focus, grabbed, parent)? => Ok(focus),
+        _ => Ok(grabbed),
+    }
+}
+
+// returns the (class, instance) pair of the window's WM_CLASS
+pub(crate) fn wm_class(
+    conn: &impl Connection,
+    win: u32,
+) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+    let class_reply = conn
+        .get_property(false, win, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 2048)?
+        .reply()?;
+    if class_reply.format != 8 || class_reply.type_ != AtomEnum::STRING.into() {
+        return Ok(None);
+    }
+
+    let class_struct = WmClass::from_reply(class_reply)?;
+
+    let class_string = String::from_utf8(class_struct.class().to_vec())?;
+    let instance_string = String::from_utf8(class_struct.instance().to_vec())?;
+    Ok(Some((class_string, instance_string)))
+}
+
+// a WM_CLASS which isn't two strings, or not utf-8
+fn is_malformed_class(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.is::<ParseError>() || e.is::<FromUtf8Error>()
+}
+
+// the WM_CLASS to match a client against, skipping a client whose WM_CLASS is malformed
+fn client_class(
+    conn: &impl Connection,
+    config: &Config,
+    cache: &mut MatchCache,
+    client: u32,
+) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+    match cache.wm_class(conn, client) {
+        Err(e) if is_malformed_class(&*e) => {
+            warn_or_fail(
+                config,
+                &format!("Window 0x{client:08x} has a malformed WM_CLASS ({e}), skipping it."),
+            )?;
+            Ok(None)
+        }
+        result => Ok(skip_x_error(config, result)?.flatten()),
+    }
+}
+
+// `<class>.<instance>`, or with the separator of --class-separator
+pub(crate) fn join_class(config: &Config, class: &str, instance: &str) -> String {
+    let separator = config.class_separator.as_deref().unwrap_or(".");
+    format!("{class}{separator}{instance}")
+}
+
+pub(crate) fn window_class(
+    conn: &impl Connection,
+    config: &Config,
+    win: u32,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    Ok(wm_class(conn, win)?.map(|(class, instance)| join_class(config, &class, &instance)))
+}
+
+pub(crate) fn atom_name(
+    conn: &impl Connection,
+    atom: u32,
+) -> Result<String, Box<dyn std::error::Error>> {
+    resolve_atom_name(atom, |atom| Ok(conn.get_atom_name(atom)?.reply()?.name))
+}
+
+// the name of an atom as `lookup` reads it from the server. the atom 0, e.g. the type of a
+// property without a value, has no name there
+pub(crate) fn resolve_atom_name(
+    atom: u32,
+    lookup: impl FnOnce(u32) -> Result<Vec<u8>, Box<dyn std::error::Error>>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if atom == NONE {
+        return Ok("None".to_string());
+    }
+
+    Ok(String::from_utf8(lookup(atom)?)?)
+}
+
+pub(crate) fn list_atoms(
+    conn: &impl Connection,
+    win: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for atom in conn.list_properties(win)?.reply()?.atoms {
+        // a zero length request only reports the type, format and size of the value
+        let reply = conn
+            .get_property(false, win, atom, AtomEnum::ANY, 0, 0)?
+            .reply()?;
+        println!(
+            "{}({})\tformat: {}, size: {} bytes",
+            atom_name(conn, atom)?,
+            atom_name(conn, reply.type_)?,
+            reply.format,
+            reply.bytes_after
+        );
+    }
+
+    Ok(())
+}
+
+// the crosshair of the cursor font, and its mask right after it
+pub(crate) const XC_CROSSHAIR: u16 = 34;
+
+// grabs the pointer until a window is clicked and returns the client window under the click
+pub(crate) fn select_window(
+    conn: &impl Connection,
+    root: u32,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let font = conn.generate_id()?;
+    conn.open_font(font, b"cursor")?;
+    let cursor = conn.generate_id()?;
+    conn.create_glyph_cursor(
+        cursor,
+        font,
+        font,
+        XC_CROSSHAIR,
+        XC_CROSSHAIR + 1,
+        0,
+        0,
+        0,
+        0xffff,
+        0xffff,
+        0xffff,
+    )?;
+    conn.close_font(font)?;
+
+    let status = conn
+        .grab_pointer(
+            false,
+            root,
+            // pointer event masks are the lower 16 bits of the event mask
+            u32::from(EventMask::BUTTON_PRESS) as u16,
+            GrabMode::ASYNC,
+            GrabMode::ASYNC,
+            root,
+            cursor,
+            CURRENT_TIME,
+        )?
+        .reply()?
+        .status;
+    if status != GrabStatus::SUCCESS {
+        conn.free_cursor(cursor)?;
+        return Err(
+            "failed to grab the pointer to select a window, is another client holding it?".into(),
+        );
+    }
+    eprintln!("mmk: click the window to run on");
+
+    let (x, y) = loop {
+        if let Event::ButtonPress(e) = conn.wait_for_event()? {
+            break (e.root_x, e.root_y);
+        }
+    };
+    conn.ungrab_pointer(CURRENT_TIME)?;
+    conn.free_cursor(cursor)?;
+    conn.flush()?;
+
+    // walk down from the root to the window under the click, the first one with WM_STATE is the
+    // client, anything above it is a frame of the window manager
+    let wm_state = conn.intern_atom(false, b"WM_STATE")?.reply()?.atom;
+    let mut window = root;
+    let mut top_level = None;
+    loop {
+        let child = conn
+            .translate_coordinates(root, window, x, y)?
+            .reply()?
+            .child;
+        if child == NONE {
+            break;
+        }
+        window = child;
+        top_level.get_or_insert(child);
+        let state = conn
+            .get_property(false, window, wm_state, AtomEnum::ANY, 0, 0)?
+            .reply()?;
+        if state.type_ != NONE {
+            return Ok(window);
+        }
+    }
+
+    // without a window manager the top level window is the client
+    top_level.ok_or_else(|| "the root window was clicked, no window to run on".into())
+}
+
+pub(crate) fn is_xwayland(conn: &impl Connection) -> Result<bool, Box<dyn std::error::Error>> {
+    // newer Xwayland servers announce themselves with an extension
+    let extension = conn.query_extension(b"XWAYLAND")?.reply()?;
+
+    Ok(looks_like_xwayland(
+        extension.present,
+        std::env::var_os("WAYLAND_DISPLAY").as_deref(),
+    ))
+}
+
+// older Xwayland servers lack the extension, but a Wayland session sets WAYLAND_DISPLAY
+fn looks_like_xwayland(extension: bool, wayland_display: Option<&std::ffi::OsStr>) -> bool {
+    extension || wayland_display.is_some_and(|display| !display.is_empty())
+}
+
+pub(crate) fn window_exists(
+    conn: &impl Connection,
+    window: u32,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    match conn.get_window_attributes(window)?.reply() {
+        Ok(_) => Ok(true),
+        Err(ReplyError::X11Error(_)) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// properties of a window read during an earlier run, each one is only read when a rule needs it
+#[derive(Default)]
+pub(crate) struct CachedWindow {
+    pub(crate) read_at: u64,
+    pub(crate) class: Option<Option<(String, String)>>,
+    pub(crate) pid: Option<u32>,
+    pub(crate) names: Option<(String, String)>,
+}
+
+/// The properties read while matching windows, kept across runs by `--match-cache`. The default
+/// has no path, keeps nothing and reads every property from the server.
+#[derive(Default)]
+pub struct MatchCache {
+    pub(crate) path: Option<String>,
+    pub(crate) windows: HashMap<u32, CachedWindow>,
+}
+
+pub(crate) const DEFAULT_MATCH_CACHE_TTL: u64 = 10;
+
+pub(crate) fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+impl MatchCache {
+    pub(crate) fn load(path: &str, ttl: u64) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut ret = Self {
+            path: Some(path.to_string()),
+            windows: HashMap::new(),
+        };
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ret),
+            Err(e) => return Err(e.into()),
+        };
+
+        let now = unix_seconds();
+        for line in contents.lines() {
+            // each line is `<window>\t<read at>\t<property>\t<values>...`
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 4 {
+                continue;
+            }
+            let (Ok(window), Ok(read_at)) = (fields[0].parse::<u32>(), fields[1].parse::<u64>())
+            else {
+                continue;
+            };
+            // stale entries are dropped, the window id may belong to another window by now
+            if now.saturating_sub(read_at) >= ttl {
+                continue;
+            }
+
+            let cached = ret.windows.entry(window).or_default();
+            cached.read_at = read_at;
+            match (fields[2], &fields[3..]) {
+                ("class", [class, instance]) => {
+                    cached.class = Some(Some((class.to_string(), instance.to_string())))
+                }
+                ("class", [""]) => cached.class = Some(None),
+                ("pid", [pid]) => cached.pid = pid.parse().ok(),
+                ("names", [net_name, name]) => {
+                    cached.names = Some((net_name.to_string(), name.to_string()))
+                }
+                _ => (),
+            }
+        }
+
+        Ok(ret)
+    }
+
+    pub(crate) fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // values with tabs or newlines in them can't be stored and are read again next time
+        let storable = |value: &str| !value.contains(['\t', '\n']);
+
+        let mut entries: Vec<_> = self.windows.iter().collect();
+        entries.sort_by_key(|(window, _)| **window);
+
+        let mut contents = String::new();
+        for (window, cached) in entries {
+            let prefix = format!("{window}\t{}", cached.read_at);
+            match &cached.class {
+                Some(Some((class, instance))) if storable(class) && storable(instance) => {
+                    contents.push_str(&format!("{prefix}\tclass\t{class}\t{instance}\n"))
+                }
+                Some(None) => contents.push_str(&format!("{prefix}\tclass\t\n")),
+                _ => (),
+            }
+            if let Some(pid) = cached.pid {
+                contents.push_str(&format!("{prefix}\tpid\t{pid}\n"));
+            }
+            if let Some((net_name, name)) = &cached.names {
+                if storable(net_name) && storable(name) {
+                    contents.push_str(&format!("{prefix}\tnames\t{net_name}\t{name}\n"));
+                }
+            }
+        }
+        std::fs::write(path, contents)?;
+
+        Ok(())
+    }
+
+    // forget the windows which don't exist anymore
+    pub(crate) fn retain(&mut self, existing: &[u32]) {
+        self.windows.retain(|window, _| existing.contains(window));
+    }
+
+    pub(crate) fn entry(&mut self, window: u32) -> Option<&mut CachedWindow> {
+        self.path.as_ref()?;
+        Some(self.windows.entry(window).or_insert_with(|| CachedWindow {
+            read_at: unix_seconds(),
+            ..Default::default()
+        }))
+    }
+
+    pub(crate) fn wm_class(
+        &mut self,
+        conn: &impl Connection,
+        win: u32,
+    ) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+        match self.entry(win) {
+            Some(CachedWindow {
+                class: Some(class), ..
+            }) => Ok(class.clone()),
+            Some(cached) => {
+                let class = wm_class(conn, win)?;
+                cached.class = Some(class.clone());
+                Ok(class)
+            }
+            None => wm_class(conn, win),
+        }
+    }
+
+    pub(crate) fn window_pid(
+        &mut self,
+        conn: &impl Connection,
+        win: u32,
+        atoms: &Atoms,
+    ) -> Result<u32, Box<dyn std::error::Error>> {
+        match self.entry(win) {
+            Some(CachedWindow { pid: Some(pid), .. }) => Ok(*pid),
+            Some(cached) => {
+                let pid = window_pid(conn, win, atoms)?;
+                cached.pid = Some(pid);
+                Ok(pid)
+            }
+            None => window_pid(conn, win, atoms),
+        }
+    }
+
+    pub(crate) fn window_names(
+        &mut self,
+        conn: &impl Connection,
+        win: u32,
+        atoms: &Atoms,
+    ) -> Result<(String, String), Box<dyn std::error::Error>> {
+        match self.entry(win) {
+            Some(CachedWindow {
+                names: Some(names), ..
+            }) => Ok(names.clone()),
+            Some(cached) => {
+                let names = window_names(conn, win, atoms)?;
+                cached.names = Some(names.clone());
+                Ok(names)
+            }
+            None => window_names(conn, win, atoms),
+        }
+    }
+}
+
+/// The atoms mmk reads and writes properties with.
+pub struct Atoms {
+    pub(crate) net_wm_pid: u32,
+    pub(crate) net_wm_name: u32,
+    pub(crate) utf8_string: u32,
+    pub(crate) xkb_rules_names: u32,
+    pub(crate) mmk_active_layout: u32,
+    pub(crate) net_active_window: u32,
+    pub(crate) net_client_list: u32,
+    pub(crate) net_wm_state: u32,
+    pub(crate) net_wm_state_fullscreen: u32,
+    pub(crate) wm_window_role: u32,
+    pub(crate) net_wm_window_type: u32,
+}
+
+impl Atoms {
+    /// Interns every atom.
+    pub fn new(conn: &impl Connection) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            net_wm_pid: conn.intern_atom(false, b"_NET_WM_PID")?.reply()?.atom,
+            net_wm_name: conn.intern_atom(false, b"_NET_WM_NAME")?.reply()?.atom,
+            utf8_string: conn.intern_atom(false, b"UTF8_STRING")?.reply()?.atom,
+            xkb_rules_names: conn.intern_atom(false, b"_XKB_RULES_NAMES")?.reply()?.atom,
+            mmk_active_layout: conn
+                .intern_atom(false, b"_MMK_ACTIVE_LAYOUT")?
+                .reply()?
+                .atom,
+            net_active_window: conn
+                .intern_atom(false, b"_NET_ACTIVE_WINDOW")?
+                .reply()?
+                .atom,
+            net_client_list: conn.intern_atom(false, b"_NET_CLIENT_LIST")?.reply()?.atom,
+            net_wm_state: conn.intern_atom(false, b"_NET_WM_STATE")?.reply()?.atom,
+            net_wm_state_fullscreen: conn
+                .intern_atom(false, b"_NET_WM_STATE_FULLSCREEN")?
+                .reply()?
+                .atom,
+            wm_window_role: conn.intern_atom(false, b"WM_WINDOW_ROLE")?.reply()?.atom,
+            net_wm_window_type: conn
+                .intern_atom(false, b"_NET_WM_WINDOW_TYPE")?
+                .reply()?
+                .atom,
+        })
+    }
+}
+
+pub(crate) fn window_pid(
+    conn: &impl Connection,
+    win: u32,
+    atoms: &Atoms,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let pid_reply = conn
+        .get_property(false, win, atoms.net_wm_pid, AtomEnum::CARDINAL, 0, 4)?
+        .reply()?;
+
+    Ok(pid_reply
+        .value32()
+        .map(|iter| iter.collect::<Vec<u32>>())
+        .unwrap_or_else(|| vec![0])[0])
+}
+
+// the WM_WINDOW_ROLE of the window, `None` if it has none
+pub(crate) fn window_role(
+    conn: &impl Connection,
+    win: u32,
+    atoms: &Atoms,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let reply = conn
+        .get_property(false, win, atoms.wm_window_role, AtomEnum::STRING, 0, 1024)?
+        .reply()?;
+    if reply.type_ == NONE {
+        return Ok(None);
+    }
+
+    Ok(Some(String::from_utf8_lossy(&reply.value).into_owned()))
+}
+
+// the types of the window, most preferred first, `normal` for a window which sets none
+pub(crate) fn window_types(
+    conn: &impl Connection,
+    win: u32,
+    atoms: &Atoms,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let reply = conn
+        .get_property(false, win, atoms.net_wm_window_type, AtomEnum::ATOM, 0, 32)?
+        .reply()?;
+    let types: Vec<u32> = reply.value32().map(Iterator::collect).unwrap_or_default();
+    if types.is_empty() {
+        return Ok(vec!["normal".to_string()]);
+    }
+
+    let mut ret = vec![];
+    for atom in types {
+        let name = atom_name(conn, atom)?;
+        if let Some(short) = name.strip_prefix("_NET_WM_WINDOW_TYPE_") {
+            ret.push(short.to_lowercase());
+        }
+    }
+
+    Ok(ret)
+}
+
+// returns the _NET_WM_NAME and WM_NAME of the window
+pub(crate) fn window_names(
+    conn: &impl Connection,
+    win: u32,
+    atoms: &Atoms,
+) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let client_net_name_reply = conn
+        .get_property(false, win, atoms.net_wm_name, atoms.utf8_string, 0, 1024)?
+        .reply()?;
+    let mut client_net_name = String::from_utf8(client_net_name_reply.value)?;
+    // some clients count the terminating nul in the length
+    if client_net_name.ends_with('\0') {
+        client_net_name.pop();
+    }
+
+    let client_name_reply = conn
+        .get_property(false, win, AtomEnum::WM_NAME, AtomEnum::STRING, 0, 1024)?
+        .reply()?;
+    let client_name = String::from_utf8(client_name_reply.value)?;
+
+    Ok((client_net_name, client_name))
+}
+
+// what --verbose-matching names as the reason a window wasn't matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Criterion {
+    Window,
+    WindowIds,
+    Class,
+    Instance,
+    InstanceRegex,
+    Role,
+    Pid,
+    Name,
+    NameRegex,
+    WindowType,
+}
+
+// the criteria whose matches don't have the window, of the ones it was checked against
+pub(crate) fn failed_criteria(win: u32, checked: &[(Criterion, Vec<u32>)]) -> Vec<Criterion> {
+    let failed: Vec<Criterion> = checked
+        .iter()
+        .filter(|(_, matched)| !matched.contains(&win))
+        .map(|(criterion, _)| *criterion)
+        .collect();
+    // along with --class the instance was only checked on the windows of the class
+    if failed.contains(&Criterion::Class) {
+        return failed
+            .into_iter()
+            .filter(|criterion| *criterion != Criterion::Instance)
+            .collect();
+    }
+
+    failed
+}
+
+pub(crate) fn explain_rejection(
+    conn: &impl Connection,
+    win: u32,
+    config: &Config,
+    atoms: &Atoms,
+    failed: &[Criterion],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (class, instance) = match wm_class(conn, win) {
+        Ok(Some((class, instance))) => (join_class(config, &class, &instance), instance),
+        Err(e) if !is_malformed_class(&*e) => return Err(e),
+        _ => Default::default(),
+    };
+    let pid = window_pid(conn, win, atoms)?;
+    let (net_name, name) = window_names(conn, win, atoms)?;
+
+    let mut reasons = vec![];
+    for criterion in failed {
+        reasons.push(match criterion {
+            Criterion::Window => format!("window is not 0x{:08x}", config.wid.unwrap_or_default()),
+            Criterion::WindowIds => "window is not in --window-ids-file".to_string(),
+            Criterion::Class => format!(
+                "class `{class}` is not `{}`",
+                config.class.as_deref().unwrap_or_default()
+            ),
+            Criterion::Instance => format!(
+                "instance `{instance}` is not `{}`",
+                config.instance.as_deref().unwrap_or_default()
+            ),
+            Criterion::InstanceRegex => format!(
+                "instance `{instance}` does not match `{}`",
+                config
+                    .instance_regex
+                    .as_ref()
+                    .map_or("", |regex| regex.as_str())
+            ),
+            Criterion::Role => {
+                let role = window_role(conn, win, atoms)?.unwrap_or_default();
+                format!(
+                    "role `{role}` is not `{}`",
+                    config.role.as_deref().unwrap_or_default()
+                )
+            }
+            Criterion::Pid => format!("pid {pid} is not {}", config.pid.unwrap_or_default()),
+            Criterion::Name => format!(
+                "names `{net_name}` and `{name}` are not `{}`",
+                config.name.as_deref().unwrap_or_default()
+            ),
+            Criterion::NameRegex => format!(
+                "names `{net_name}` and `{name}` do not match `{}`",
+                config
+                    .name_regex
+                    .as_ref()
+                    .map_or("", |regex| regex.as_str())
+            ),
+            Criterion::WindowType => format!(
+                "window type `{}` is not `{}`",
+                window_types(conn, win, atoms)?.join(","),
+                config.window_types.join(",")
+            ),
+        });
+    }
+
+    eprintln!(
+        "0x{win:08x} (class: `{class}`, pid: {pid}, name: `{name}`) rejected: {}",
+        reasons.join(", ")
+    );
+
+    Ok(())
+}
+
+// the clients whose WM_CLASS instance, as `class_of` reads it, matches `regex`
+fn instances_matching<E>(
+    clients: &[u32],
+    regex: &Regex,
+    mut class_of: impl FnMut(u32) -> Result<Option<(String, String)>, E>,
+) -> Result<Vec<u32>, E> {
+    let mut matched = vec![];
+    for &client in clients {
+        if let Some((_, instance)) = class_of(client)? {
+            if regex.is_match(&instance) {
+                matched.push(client);
+            }
+        }
+    }
+    Ok(matched)
+}
+
+// compares a --class or --name with the window's, following --ignore-case and --contains
+pub(crate) fn text_matches(config: &Config, wanted: &str, actual: &str) -> bool {
+    let (wanted, actual) = if config.ignore_case {
+        (wanted.to_lowercase(), actual.to_lowercase())
+    } else {
+        (wanted.to_string(), actual.to_string())
+    };
+
+    if config.contains {
+        actual.contains(&wanted)
+    } else {
+        actual == wanted
+    }
+}
+
+/// The windows of `clients` whose WM_CLASS class, or the class and instance joined like
+/// `class.instance`, is `class`. `--ignore-case`, `--contains` and `--class-separator` of `config`
+/// apply. See `--class`.
+pub fn find_windows_by_class(
+    conn: &impl Connection,
+    config: &Config,
+    cache: &mut MatchCache,
+    clients: &[u32],
+    class: &str,
+) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let mut matched = vec![];
+    for client in clients.iter() {
+        let Some((client_class, instance)) = client_class(conn, config, cache, *client)? else {
+            continue;
+        };
+        if class_matches(config, class, &client_class, &instance) {
+            matched.push(*client);
+        }
+    }
+
+    Ok(matched)
+}
+
+// the class alone, or the old `class.instance` form with the --class-separator
+fn class_matches(config: &Config, wanted: &str, class: &str, instance: &str) -> bool {
+    text_matches(config, wanted, class)
+        || text_matches(config, wanted, &join_class(config, class, instance))
+}
+
+/// The windows of `clients` whose WM_CLASS instance is `instance`, following `--ignore-case` and
+/// `--contains` of `config`. See `--instance`.
+pub fn find_windows_by_instance(
+    conn: &impl Connection,
+    config: &Config,
+    cache: &mut MatchCache,
+    clients: &[u32],
+    instance: &str,
+) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let mut matched = vec![];
+    for client in clients.iter() {
+        let client_instance =
+            client_class(conn, config, cache, *client)?.map(|(_, instance)| instance);
+        if client_instance
+            .is_some_and(|client_instance| text_matches(config, instance, &client_instance))
+        {
+            matched.push(*client);
+        }
+    }
+
+    Ok(matched)
+}
+
+/// The windows of `clients` whose _NET_WM_PID is `pid`. See `--pid`.
+pub fn find_windows_by_pid(
+    conn: &impl Connection,
+    config: &Config,
+    cache: &mut MatchCache,
+    clients: &[u32],
+    atoms: &Atoms,
+    pid: u32,
+) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let mut matched = vec![];
+    for client in clients.iter() {
+        if skip_x_error(config, cache.window_pid(conn, *client, atoms))? == Some(pid) {
+            matched.push(*client);
+        }
+    }
+
+    Ok(matched)
+}
+
+/// The windows of `clients` whose _NET_WM_NAME or WM_NAME is `name`, following `--ignore-case`
+/// and `--contains` of `config`. See `--name`.
+pub fn find_windows_by_name(
+    conn: &impl Connection,
+    config: &Config,
+    cache: &mut MatchCache,
+    clients: &[u32],
+    atoms: &Atoms,
+    name: &str,
+) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let mut matched = vec![];
+    for client in clients.iter() {
+        let names = skip_x_error(config, cache.window_names(conn, *client, atoms))?;
+        let Some((client_net_name, client_name)) = names else {
+            continue;
+        };
+        if text_matches(config, name, &client_net_name) || text_matches(config, name, &client_name)
+        {
+            matched.push(*client);
+        }
+    }
+
+    Ok(matched)
+}
+
+// the windows a rule matches, `started` times the phases for --profile-startup
+pub(crate) fn find_windows(
+    conn: &impl Connection,
+    root: u32,
+    config: &Config,
+    atoms: &Atoms,
+    cache: &mut MatchCache,
+    started: &mut Option<Instant>,
+) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let managed = if config.managed_only {
+        client_list(conn, root, atoms.net_client_list)?
+    } else {
+        None
+    };
+    let clients = match managed {
+        Some(clients) => clients,
+        None => {
+            let mut clients = Vec::new();
+            rec_query_tree(conn, root, &mut clients)?;
+            clients
+        }
+    };
+    cache.retain(&clients);
+    profile_phase("walking the window tree", started);
+    // the windows matching each of the given criteria
+    let mut criteria: Vec<(Criterion, Vec<u32>)> = vec![];
+    // the ones only narrowing down another criterion, for --verbose-matching
+    let mut narrowing: Vec<(Criterion, Vec<u32>)> = vec![];
+
+    // try to get the x11 window id
+    if let Some(wid) = config.wid {
+        criteria.push((Criterion::Window, vec![wid]));
+    }
+
+    // the ids listed in --window-ids-file
+    if let Some(ids) = &config.window_ids {
+        criteria.push((Criterion::WindowIds, ids.clone()));
+    }
+
+    // check for class
+    if let Some(class) = &config.class {
+        let matched = find_windows_by_class(conn, config, cache, &clients, class)?;
+        criteria.push((Criterion::Class, matched));
+        profile_phase("matching the class", started);
+    }
+
+    // check for instance, along with --class it only looks at the windows of the class
+    if let Some(instance) = &config.instance {
+        let by_class = config.class.as_ref().and_then(|_| criteria.pop());
+        let candidates = by_class
+            .as_ref()
+            .map_or(&clients[..], |(_, matched)| matched);
+        let matched = find_windows_by_instance(conn, config, cache, candidates, instance)?;
+        narrowing.extend(by_class);
+        criteria.push((Criterion::Instance, matched));
+        profile_phase("matching the instance", started);
+    }
+
+    // check for instance regex
+    if let Some(instance_regex) = &config.instance_regex {
+        let matched = instances_matching(&clients, instance_regex, |client| {
+            skip_x_error(config, cache.wm_class(conn, client)).map(Option::flatten)
+        })?;
+        criteria.push((Criterion::InstanceRegex, matched));
+        profile_phase("matching the instance regex", started);
+    }
+
+    // check for role
+    if let Some(role) = &config.role {
+        let mut matched = vec![];
+        for client in clients.iter() {
+            let client_role = skip_x_error(config, window_role(conn, *client, atoms))?.flatten();
+            if client_role.is_some_and(|client_role| text_matches(config, role, &client_role)) {
+                matched.push(*client);
+            }
+        }
+        criteria.push((Criterion::Role, matched));
+        profile_phase("matching the role", started);
+    }
+
+    // check for pid
+    if let Some(pid) = config.pid {
+        let matched = find_windows_by_pid(conn, config, cache, &clients, atoms, pid)?;
+        criteria.push((Criterion::Pid, matched));
+        profile_phase("matching the pid", started);
+    }
+
+    // check for window name
+    if let Some(name) = &config.name {
+        let matched = find_windows_by_name(conn, config, cache, &clients, atoms, name)?;
+        criteria.push((Criterion::Name, matched));
+        profile_phase("matching the name", started);
+    }
+
+    // check for window name regex
+    if let Some(name_regex) = &config.name_regex {
+        let mut matched = vec![];
+        for client in clients.iter() {
+            let names = skip_x_error(config, cache.window_names(conn, *client, atoms))?;
+            let Some((client_net_name, client_name)) = names else {
+                continue;
+            };
+            if name_regex.is_match(&client_net_name) || name_regex.is_match(&client_name) {
+                matched.push(*client);
+            }
+        }
+        criteria.push((Criterion::NameRegex, matched));
+        profile_phase("matching the name regex", started);
+    }
+
+    let mut windows = combine_criteria(config.combine.unwrap_or_default(), &criteria);
+
+    // check for window type, which narrows down the other criteria instead of adding to them
+    if !config.window_types.is_empty() {
+        let mut matched = vec![];
+        let candidates = if criteria.is_empty() {
+            &clients
+        } else {
+            &windows
+        };
+        for client in candidates.iter() {
+            let types =
+                skip_x_error(config, window_types(conn, *client, atoms))?.unwrap_or_default();
+            if types.iter().any(|name| config.window_types.contains(name)) {
+                matched.push(*client);
+            }
+        }
+        windows = matched;
+        profile_phase("matching the window type", started);
+    }
+
+    if config.verbose_matching && !config.quiet {
+        let checked: Vec<(Criterion, Vec<u32>)> = narrowing.into_iter().chain(criteria).collect();
+        for client in clients.iter().filter(|client| !windows.contains(client)) {
+            // passing every other criterion leaves the window type
+            let mut failed = failed_criteria(*client, &checked);
+            if failed.is_empty() {
+                failed.push(Criterion::WindowType);
+            }
+            // a window which went away in the meantime isn't worth explaining
+            match explain_rejection(conn, *client, config, atoms, &failed) {
+                Err(e)
+                    if matches!(
+                        e.downcast_ref::<ReplyError>(),
+                        Some(ReplyError::X11Error(_))
+                    ) => {}
+                result => result?,
+            }
+        }
+    }
+
+    Ok(windows)
+}
+
+// the windows of any criterion or only those of every one, in the order of the first
+fn combine_criteria(combine: Combine, criteria: &[(Criterion, Vec<u32>)]) -> Vec<u32> {
+    match (combine, criteria.split_first()) {
+        (Combine::And, Some(((_, first), rest))) => first
+            .iter()
+            .filter(|window| rest.iter().all(|(_, matched)| matched.contains(window)))
+            .copied()
+            .collect(),
+        _ => criteria
+            .iter()
+            .flat_map(|(_, matched)| matched)
+            .copied()
+            .collect(),
+    }
+}
+
+pub(crate) fn read_window_ids(
+    config: &Config,
+    path: &str,
+) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)?;
+
+    let mut ids = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match parse_wid(line) {
+            Ok(id) => ids.push(id),
+            Err(_) => warn(
+                config,
+                &format!(
+                    "{path}:{}: `{line}` is not a window id, skipping it.",
+                    number + 1
+                ),
+            ),
+        }
+    }
+
+    Ok(ids)
+}
+
+pub(crate) fn dedup_windows(
+    conn: &impl Connection,
+    config: &Config,
+    atoms: &Atoms,
+    windows: Vec<u32>,
+    by: DedupBy,
+) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    dedup_by_key(windows, |window| match by {
+        DedupBy::Pid => {
+            let pid = window_pid(conn, window, atoms)?;
+            Ok((pid != 0).then(|| pid.to_string()))
+        }
+        DedupBy::Class => window_class(conn, config, window),
+    })
+}
+
+// keeps the lowest window id of each `key`, windows without one are all kept
+fn dedup_by_key<E>(
+    windows: Vec<u32>,
+    mut key: impl FnMut(u32) -> Result<Option<String>, E>,
+) -> Result<Vec<u32>, E> {
+    let mut sorted = windows.clone();
+    sorted.sort_unstable();
+
+    let mut seen = vec![];
+    let mut kept = vec![];
+    for window in sorted {
+        match key(window)? {
+            Some(key) if seen.contains(&key) => (),
+            Some(key) => {
+                seen.push(key);
+                kept.push(window);
+            }
+            None => kept.push(window),
+        }
+    }
+
+    // keep the order the windows matched in
+    Ok(windows
+        .into_iter()
+        .filter(|window| kept.contains(window))
+        .collect())
+}
+
+// a matched window and the layout its rule asked for
+pub(crate) type Target = (u32, Option<usize>);
+
+// the windows of every rule, which already carry the command line, see `Config::merge_rule`
+pub(crate) fn find_targets(
+    conn: &impl Connection,
+    root: u32,
+    rules: &[Config],
+    atoms: &Atoms,
+    cache: &mut MatchCache,
+    started: &mut Option<Instant>,
+) -> Result<Vec<Target>, Box<dyn std::error::Error>> {
+    let mut targets: Vec<Target> = vec![];
+    for rule in rules {
+        let mut wins = find_windows(conn, root, rule, atoms, cache, started)?;
+        if rule.exclude_wm_frames {
+            let mut clients = vec![];
+            for window in wins {
+                if rule.wid == Some(window) || !is_wm_frame(conn, rule, root, window)? {
+                    clients.push(window);
+                }
+            }
+            wins = clients;
+        }
+        if let Some(by) = rule.dedup_windows {
+            wins = dedup_windows(conn, rule, atoms, wins, by)?;
+        }
+        if !rule.all_windows {
+            if rule.strict && wins.len() > 1 {
+                return Err(format!(
+                    "{} windows match, use --all to run on every one of them.",
+                    wins.len()
+                )
+                .into());
+            }
+            wins.truncate(1);
+        }
+        if rule.expand_children {
+            let mut children = vec![];
+            for window in wins.iter() {
+                input_descendants(conn, *window, &mut children)?;
+            }
+            for child in children {
+                if !wins.contains(&child) {
+                    wins.push(child);
+                }
+            }
+        }
+
+        add_rule_targets(&mut targets, wins, &rule.layout);
+    }
+
+    Ok(targets)
+}
+
+// the first rule to match a window decides its layout, a list of layouts is handed out to the
+// rule's windows in turn
+pub(crate) fn add_rule_targets(targets: &mut Vec<Target>, windows: Vec<u32>, layouts: &[usize]) {
+    for (index, window) in windows.into_iter().enumerate() {
+        if !targets.iter().any(|(target, _)| *target == window) {
+            let layout = (!layouts.is_empty()).then(|| layouts[index % layouts.len()]);
+            targets.push((window, layout));
+        }
+    }
+}
+
+pub(crate) fn print_matches(
+    conn: &impl Connection,
+    config: &Config,
+    windows: &[u32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for window in windows {
+        let class = window_class(conn, config, *window)?.unwrap_or_default();
+        println!("0x{window:08x}\t{class}");
+    }
+
+    Ok(())
+}
+
+pub(crate) fn list_windows(
+    conn: &impl Connection,
+    config: &Config,
+    atoms: &Atoms,
+    windows: &[u32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for window in windows {
+        let class = window_class(conn, config, *window)?.unwrap_or_default();
+        let pid = window_pid(conn, *window, atoms)?;
+        let (net_name, name) = window_names(conn, *window, atoms)?;
+        let title = if net_name.is_empty() { name } else { net_name };
+        println!("0x{window:08x}\t{class}\t{pid}\t{title}");
+    }
+
+    Ok(())
+}
+
+// the windows of the active window's class for --follow-class, `None` while the class stays
+pub(crate) fn follow_class(
+    conn: &impl Connection,
+    root: u32,
+    config: &Config,
+    atoms: &Atoms,
+    followed: &mut Option<String>,
+) -> Result<Option<Vec<u32>>, Box<dyn std::error::Error>> {
+    let active = conn
+        .get_property(false, root, atoms.net_active_window, AtomEnum::WINDOW, 0, 1)?
+        .reply()?
+        .value32()
+        .and_then(|mut value| value.next())
+        .filter(|window| *window != NONE);
+    let class = match active {
+        Some(window) => skip_x_error(config, window_class(conn, config, window))?.flatten(),
+        None => None,
+    };
+    let Some(class) = class_to_follow(class, followed.as_ref()) else {
+        return Ok(None);
+    };
+
+    let rule = follow_rule(config, &class);
+    let windows = find_windows(
+        conn,
+        root,
+        &rule,
+        atoms,
+        &mut MatchCache::default(),
+        &mut None,
+    )?;
+    *followed = Some(class);
+
+    Ok(Some(windows))
+}
+
+// the class the focus moved to, `None` keeps the windows of the last app while the desktop or a
+// window without a class is active, or another window of the same app
+fn class_to_follow(active: Option<String>, followed: Option<&String>) -> Option<String> {
+    active.filter(|class| followed != Some(class))
+}
+
+// the windows of the followed app, with every other setting of the command line
+fn follow_rule(config: &Config, class: &str) -> Config {
+    config.merge_rule(&Config::default().with_class(class.to_string()))
+}
+
+// whether the event changes which windows `--dry-run --watch` may match
+pub(crate) fn rematches(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::CreateNotify(_)
+            | Event::DestroyNotify(_)
+            | Event::MapNotify(_)
+            | Event::UnmapNotify(_)
+            | Event::ReparentNotify(_)
+    )
+}
+
+pub(crate) fn watch_matches(
+    conn: &impl Connection,
+    root: u32,
+    config: &Config,
+    rules: &[Config],
+    atoms: &Atoms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    conn.change_window_attributes(
+        root,
+        &ChangeWindowAttributesAux::new().event_mask(u32::from(EventMask::SUBSTRUCTURE_NOTIFY)),
+    )?;
+    conn.flush()?;
+
+    loop {
+        let windows: Vec<u32> = find_targets(
+            conn,
+            root,
+            rules,
+            atoms,
+            &mut MatchCache::default(),
+            &mut None,
+        )?
+        .into_iter()
+        .map(|(window, _)| window)
+        .collect();
+        // clear the terminal and move the cursor to the top left
+        print!("\x1b[2J\x1b[H");
+        print_matches(conn, config, &windows)?;
+        std::io::stdout().flush()?;
+
+        // wait for a change to the set of top level windows
+        while !rematches(&conn.wait_for_event()?) {}
+        // a single window usually generates a burst of events, re-match only once
+        while conn.poll_for_event()?.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::parse_criteria,
+        testing::{config, error_reply, tree_reply, FakeServer},
+    };
+    use x11rb::protocol::xproto::WINDOW_ERROR;
+
+    #[test]
+    fn windows_gone_while_looked_at_are_no_frames() {
+        // the top level window 2 is destroyed between QueryTree and GetWindowAttributes
+        let gone = || FakeServer::new(vec![tree_reply(1, &[3]), error_reply(WINDOW_ERROR)]);
+
+        let frame = is_wm_frame(&gone(), &config(&["--exclude-wm-frames"]), 1, 2);
+        assert!(!frame.unwrap());
+        let strict = config(&["--exclude-wm-frames", "--x-error-mode", "strict"]);
+        assert!(is_wm_frame(&gone(), &strict, 1, 2).is_err());
+    }
+
+    // a tree of the root 1 with the top level windows 2 and 3, and 4 and 5 nested in 2
+    fn parent(win: u32) -> Result<u32, ()> {
+        Ok(match win {
+            2 | 3 => 1,
+            4 => 2,
+            5 => 4,
+            _ => NONE,
+        })
+    }
+
+    #[test]
+    fn unfocused_windows_get_no_keys() {
+        assert_eq!(focus_within(Some(2), 2, parent), Ok(true));
+        // the focus on a widget deep inside of the window still counts
+        assert_eq!(focus_within(Some(5), 2, parent), Ok(true));
+        assert_eq!(focus_within(Some(3), 2, parent), Ok(false));
+        // nothing or the pointer root has the focus
+        assert_eq!(focus_within(None, 2, parent), Ok(false));
+    }
+
+    #[test]
+    fn every_rule_gives_its_windows_its_layout() {
+        let rules = parse_criteria(
+            "rules.toml",
+            "[[rule]]\nclass = \"X\"\nlayout = 1\n\n[[rule]]\nname = \"Y\"\nlayout = 2\n",
+        )
+        .unwrap();
+        // the command line fills in what the rules leave out
+        let rules: Vec<Config> = rules
+            .iter()
+            .map(|rule| config(&["--layout", "3", "--all"]).merge_rule(rule))
+            .collect();
+        assert_eq!(rules[0].class.as_deref(), Some("X"));
+        assert_eq!(rules[1].name.as_deref(), Some("Y"));
+        assert!(rules.iter().all(|rule| rule.all_windows));
+
+        // window 2 matches both, the first rule takes it
+        let mut targets = vec![];
+        add_rule_targets(&mut targets, vec![1, 2], &rules[0].layout);
+        add_rule_targets(&mut targets, vec![2, 3], &rules[1].layout);
+        add_rule_targets(&mut targets, vec![4], &[]);
+        assert_eq!(
+            targets,
+            [(1, Some(1)), (2, Some(1)), (3, Some(2)), (4, None)]
+        );
+    }
+
+    #[test]
+    fn xwayland_is_told_by_the_extension_or_the_session() {
+        let display = Some(std::ffi::OsStr::new("wayland-0"));
+        assert!(looks_like_xwayland(true, None));
+        assert!(looks_like_xwayland(false, display));
+        assert!(looks_like_xwayland(true, display));
+        assert!(!looks_like_xwayland(false, None));
+        assert!(!looks_like_xwayland(false, Some(std::ffi::OsStr::new(""))));
+    }
+
+    #[test]
+    fn cached_properties_last_as_long_as_the_ttl() {
+        let path = std::env::temp_dir().join(format!("mmk-match-cache-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let now = unix_seconds();
+        std::fs::write(
+            path,
+            format!(
+                "2\t{now}\tclass\tFirefox\tNavigator\n\
+                 2\t{now}\tpid\t42\n\
+                 3\t{}\tnames\tnet name\tname\n\
+                 4\t{now}\tclass\t\n",
+                now - 10
+            ),
+        )
+        .unwrap();
+
+        let cache = MatchCache::load(path, 5).unwrap();
+        let cached = &cache.windows[&2];
+        let class = Some(("Firefox".to_string(), "Navigator".to_string()));
+        assert_eq!(cached.class, Some(class.clone()));
+        assert_eq!(cached.pid, Some(42));
+        // read 10 seconds ago, past the ttl
+        assert!(!cache.windows.contains_key(&3));
+        // a window without WM_CLASS is cached as such
+        assert_eq!(cache.windows[&4].class, Some(None));
+
+        let cache = MatchCache::load(path, 60).unwrap();
+        assert_eq!(
+            cache.windows[&3].names,
+            Some(("net name".to_string(), "name".to_string()))
+        );
+
+        // the windows which are gone are dropped before saving
+        let mut cache = MatchCache::load(path, 60).unwrap();
+        cache.retain(&[2, 3]);
+        cache.save().unwrap();
+        let cache = MatchCache::load(path, 60).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let mut windows: Vec<_> = cache.windows.keys().copied().collect();
+        windows.sort();
+        assert_eq!(windows, [2, 3]);
+        assert_eq!(cache.windows[&2].class, Some(class));
+
+        assert!(MatchCache::load(path, 60).unwrap().windows.is_empty());
+    }
+
+    #[test]
+    fn frames_wrap_a_client_with_a_class() {
+        // 4 has a WM_CLASS, 5 doesn't
+        let child_has_class = |child| Ok::<_, ()>(child == 4);
+
+        assert_eq!(
+            looks_like_frame(false, false, &[5, 4], child_has_class),
+            Ok(true)
+        );
+        // the client itself, and what the window manager doesn't manage
+        assert_eq!(
+            looks_like_frame(false, true, &[4], child_has_class),
+            Ok(false)
+        );
+        assert_eq!(
+            looks_like_frame(true, false, &[4], child_has_class),
+            Ok(false)
+        );
+        // a window without WM_CLASS and no client in it
+        assert_eq!(
+            looks_like_frame(false, false, &[5], child_has_class),
+            Ok(false)
+        );
+        assert_eq!(
+            looks_like_frame(false, false, &[], child_has_class),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn windows_sharing_a_pid_keep_the_lowest_id() {
+        // 6 and 4 belong to process 10, 5 and 7 to 20, 3 has no _NET_WM_PID
+        let pid = |window| {
+            Ok::<_, ()>(match window {
+                4 | 6 => Some("10".to_string()),
+                5 | 7 => Some("20".to_string()),
+                _ => None,
+            })
+        };
+
+        assert_eq!(
+            dedup_by_key(vec![6, 7, 3, 4, 5, 8], pid),
+            Ok(vec![3, 4, 5, 8])
+        );
+        assert_eq!(dedup_by_key(vec![7, 6], pid), Ok(vec![7, 6]));
+        assert_eq!(dedup_by_key(vec![], pid), Ok(vec![]));
+    }
+
+    #[test]
+    fn combining_with_and_needs_every_criterion() {
+        // 2 only has the class, 4 only the name
+        let criteria = [
+            (Criterion::Class, vec![2, 3, 5]),
+            (Criterion::Name, vec![5, 3, 4]),
+        ];
+        assert_eq!(combine_criteria(Combine::And, &criteria), [3, 5]);
+        let any = combine_criteria(Combine::Or, &criteria);
+        assert!([2, 3, 4, 5].iter().all(|window| any.contains(window)));
+        assert_eq!(combine_criteria(Combine::And, &criteria[..1]), [2, 3, 5]);
+        assert!(combine_criteria(Combine::And, &[]).is_empty());
+
+        // a rule file sets it for its rules, the command line for the others
+        let contents = "[[rule]]\nclass = \"X\"\ncombine = \"and\"\n[[rule]]\nname = \"Y\"\n";
+        let rules = parse_criteria("rules.toml", contents).unwrap();
+        let config = config(&["--combine", "or"]);
+        assert_eq!(config.merge_rule(&rules[0]).combine, Some(Combine::And));
+        assert_eq!(config.merge_rule(&rules[1]).combine, Some(Combine::Or));
+    }
+
+    #[test]
+    fn the_focus_moving_to_another_app_follows_its_class() {
+        let config = config(&["--follow-class", "--layout", "1"]);
+        let firefox = "Firefox.Navigator".to_string();
+        let emacs = "Emacs.emacs".to_string();
+
+        assert_eq!(
+            class_to_follow(Some(firefox.clone()), None),
+            Some(firefox.clone())
+        );
+        // another window of the same app, or the desktop, keeps the grabs
+        assert_eq!(class_to_follow(Some(firefox.clone()), Some(&firefox)), None);
+        assert_eq!(class_to_follow(None, Some(&firefox)), None);
+        assert_eq!(
+            class_to_follow(Some(emacs.clone()), Some(&firefox)),
+            Some(emacs)
+        );
+
+        let rule = follow_rule(&config, &firefox);
+        assert_eq!(rule.class, Some(firefox));
+        assert_eq!(rule.layout, [1]);
+        assert!(rule.follow_class);
+    }
+
+    #[test]
+    fn class_and_instance_are_joined_with_the_separator() {
+        let (class, instance) = ("Org.gnome.Nautilus", "org.gnome.Nautilus");
+        let dot = config(&[]);
+        let bar = config(&["--class-separator", "|"]);
+
+        assert!(class_matches(&dot, "Org.gnome.Nautilus", class, instance));
+        assert!(class_matches(&bar, "Org.gnome.Nautilus", class, instance));
+        assert!(class_matches(
+            &dot,
+            "Org.gnome.Nautilus.org.gnome.Nautilus",
+            class,
+            instance
+        ));
+        assert!(!class_matches(
+            &bar,
+            "Org.gnome.Nautilus.org.gnome.Nautilus",
+            class,
+            instance
+        ));
+        assert!(class_matches(
+            &bar,
+            "Org.gnome.Nautilus|org.gnome.Nautilus",
+            class,
+            instance
+        ));
+        assert!(!class_matches(&bar, "Org.gnome|Nautilus", class, instance));
+        // and printed the same way
+        assert_eq!(
+            join_class(&bar, class, instance),
+            "Org.gnome.Nautilus|org.gnome.Nautilus"
+        );
+    }
+
+    #[test]
+    fn window_ids_are_read_from_a_file() {
+        let path = std::env::temp_dir().join(format!("mmk-window-ids-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            "# the editor\n0x3a00007\n1234  # the browser\n\nfirefox\n  0x4c00002\n",
+        )
+        .unwrap();
+
+        let ids = read_window_ids(&config(&["--quiet"]), path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(ids.unwrap(), [0x3a00007, 1234, 0x4c00002]);
+        assert!(read_window_ids(&config(&[]), path).is_err());
+    }
+
+    #[test]
+    fn keys_go_to_the_focused_descendant() {
+        assert_eq!(focus_target(Some(5), 2, parent), Ok(5));
+        assert_eq!(focus_target(Some(4), 2, parent), Ok(4));
+        assert_eq!(focus_target(Some(2), 2, parent), Ok(2));
+        // the focus is outside the grabbed window
+        assert_eq!(focus_target(Some(3), 2, parent), Ok(2));
+        assert_eq!(focus_target(Some(2), 4, parent), Ok(4));
+        assert_eq!(focus_target(None, 2, parent), Ok(2));
+    }
+
+    #[test]
+    fn layout_lists_are_handed_out_round_robin() {
+        let config = config(&["--layout", "1,2,3", "--all"]);
+        assert_eq!(config.layout, [1, 2, 3]);
+
+        let mut targets = vec![];
+        add_rule_targets(&mut targets, vec![10, 11, 12, 13, 14], &config.layout);
+        assert_eq!(
+            targets,
+            [
+                (10, Some(1)),
+                (11, Some(2)),
+                (12, Some(3)),
+                (13, Some(1)),
+                (14, Some(2))
+            ]
+        );
+    }
+
+    #[test]
+    fn the_walk_skips_windows_destroyed_on_the_way() {
+        // 2 is destroyed before its children are asked for
+        let mut children = |win| match win {
+            1 => Ok(Some(vec![2, 3])),
+            2 => Ok(None),
+            3 => Ok(Some(vec![4, 5])),
+            5 => Ok(Some(vec![6])),
+            _ => Ok(Some(vec![])),
+        };
+        let mut windows = vec![];
+        walk_tree::<()>(1, &mut windows, &mut children).unwrap();
+        assert_eq!(windows, [2, 4, 6, 5, 3]);
+
+        // other errors still end the walk
+        let mut windows = vec![];
+        let mut broken = |win| {
+            if win == 3 {
+                Err("connection lost")
+            } else {
+                Ok(Some(vec![win + 1, 3]))
+            }
+        };
+        assert_eq!(
+            walk_tree(1, &mut windows, &mut broken),
+            Err("connection lost")
+        );
+    }
+
+    #[test]
+    fn atoms_are_listed_by_name() {
+        let lookup = |atom| match atom {
+            39 => Ok(b"WM_NAME".to_vec()),
+            _ => Err("BadAtom".into()),
+        };
+
+        assert_eq!(resolve_atom_name(39, lookup).unwrap(), "WM_NAME");
+        // asking the server about atom 0 is an error
+        assert_eq!(resolve_atom_name(NONE, lookup).unwrap(), "None");
+        assert!(resolve_atom_name(40, lookup).is_err());
+        assert!(resolve_atom_name(41, |_| Ok(vec![0xff])).is_err());
+    }
+
+    #[test]
+    fn watching_matches_again_when_windows_come_and_go() {
+        assert!(rematches(&Event::CreateNotify(Default::default())));
+        assert!(rematches(&Event::DestroyNotify(Default::default())));
+        assert!(rematches(&Event::MapNotify(Default::default())));
+        assert!(rematches(&Event::UnmapNotify(Default::default())));
+        assert!(rematches(&Event::ReparentNotify(Default::default())));
+        // the tree stays the same
+        assert!(!rematches(&Event::ConfigureNotify(Default::default())));
+        assert!(!rematches(&Event::PropertyNotify(Default::default())));
+        assert!(!rematches(&Event::KeyPress(Default::default())));
+    }
+
+    #[test]
+    fn children_taking_keys_are_input_descendants() {
+        let key_press = u32::from(EventMask::KEY_PRESS);
+        let attrs = |class, all_event_masks| GetWindowAttributesReply {
+            class,
+            all_event_masks,
+            ..Default::default()
+        };
+
+        assert!(takes_input(&attrs(WindowClass::INPUT_OUTPUT, key_press)));
+        assert!(takes_input(&attrs(
+            WindowClass::INPUT_OUTPUT,
+            key_press | u32::from(EventMask::EXPOSURE)
+        )));
+        // nobody listens to its keys
+        assert!(!takes_input(&attrs(
+            WindowClass::INPUT_OUTPUT,
+            u32::from(EventMask::EXPOSURE)
+        )));
+        // an InputOnly window has nothing to type into
+        assert!(!takes_input(&attrs(WindowClass::INPUT_ONLY, key_press)));
+    }
+
+    #[test]
+    fn rejections_name_only_the_failed_criteria() {
+        let checked = [
+            (Criterion::Class, vec![1, 2]),
+            (Criterion::Name, vec![2, 3]),
+        ];
+        // under --combine and window 1 only misses the name
+        assert_eq!(failed_criteria(1, &checked), [Criterion::Name]);
+        assert_eq!(
+            failed_criteria(4, &checked),
+            [Criterion::Class, Criterion::Name]
+        );
+
+        // the instance was only looked at on the windows of the class
+        let checked = [
+            (Criterion::Class, vec![1, 2]),
+            (Criterion::Instance, vec![2]),
+        ];
+        assert_eq!(failed_criteria(1, &checked), [Criterion::Instance]);
+        assert_eq!(failed_criteria(3, &checked), [Criterion::Class]);
+    }
+
+    #[test]
+    fn instance_regex_looks_at_the_instance_only() {
+        let config = config(&["--instance-regex", "^fire"]);
+        let regex = config.instance_regex.as_ref().unwrap();
+        let class_of = |client| -> Result<_, ()> {
+            Ok(match client {
+                2 => Some(("Firefox".into(), "firefox".into())),
+                3 => Some(("firefox".into(), "Navigator".into())),
+                4 => Some(("Firewall".into(), "fire-wall".into())),
+                _ => None,
+            })
+        };
+        assert_eq!(
+            instances_matching(&[2, 3, 4, 5], regex, class_of),
+            Ok(vec![2, 4])
+        );
+        assert_eq!(instances_matching(&[3, 5], regex, class_of), Ok(vec![]));
+
+        let args = ["mmk", "--instance-regex", "(fire"];
+        assert!(Config::from_args(args.map(String::from).to_vec()).is_err());
+    }
+}
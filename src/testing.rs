@@ -0,0 +1,435 @@
+//! Stand-ins for the X server and the keymap, shared by the tests of every module.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    io::IoSlice,
+};
+
+use x11rb::{
+    connection::{
+        BufWithFds, Connection, DiscardMode, RawEventAndSeqNumber, ReplyOrError, RequestConnection,
+        RequestKind, SequenceNumber,
+    },
+    cookie::{Cookie, CookieWithFds, VoidCookie},
+    errors::{ConnectionError, ParseError, ReplyError, ReplyOrIdError},
+    protocol::{
+        xkb::{KTMapEntry, KeyModMap, KeySymMap, KeyType},
+        xproto::{
+            AtomEnum, GrabStatus, KeyPressEvent, Keysym, ModMask, Screen, Setup, KEY_PRESS_EVENT,
+        },
+        ErrorKind, Event, Request,
+    },
+    utils::RawFdContainer,
+    x11_utils::{
+        parse_request_header, BigRequests, ExtInfoProvider, ExtensionInformation, TryParse,
+        TryParseFd, X11Error,
+    },
+    CURRENT_TIME, NONE,
+};
+
+use crate::{
+    config::Config,
+    keymap::{KeymapSource, ServerKeymap},
+    matching::Atoms,
+};
+
+// a keymap of two groups with the keysyms of each key in every group, `keys[keycode - 8]`
+pub(crate) struct TestKeymap {
+    pub(crate) keys: Vec<[[Keysym; 2]; 2]>,
+}
+
+impl TestKeymap {
+    pub(crate) fn new(keys: &[[[Keysym; 2]; 2]]) -> Self {
+        Self {
+            keys: keys.to_vec(),
+        }
+    }
+}
+
+impl KeymapSource for TestKeymap {
+    fn keycode_to_keysym(&self, keycode: u8, group: usize, level: usize) -> Keysym {
+        let key = usize::from(keycode).checked_sub(8);
+        key.and_then(|key| self.keys.get(key))
+            .and_then(|groups| groups.get(group)?.get(level).copied())
+            .unwrap_or(0)
+    }
+
+    fn keysym_to_keycode(&self, keysym: Keysym) -> u8 {
+        self.keys
+            .iter()
+            .position(|groups| groups[0].contains(&keysym))
+            .map_or(0, |key| key as u8 + 8)
+    }
+
+    fn keysym_to_mods(&self, keysym: Keysym) -> u32 {
+        let shifted = self.keys.iter().any(|groups| groups[0][1] == keysym);
+        if shifted {
+            ModMask::SHIFT.into()
+        } else {
+            0
+        }
+    }
+
+    fn max_keycode(&self) -> u8 {
+        self.keys.len() as u8 + 7
+    }
+}
+
+// a key type of the Xkb keymap which maps each of `map`, (modifiers, level), to its level
+fn key_type(mods_mask: ModMask, num_levels: u8, map: &[(ModMask, u8)]) -> KeyType {
+    KeyType {
+        mods_mask: u16::from(mods_mask) as u8,
+        mods_mods: u16::from(mods_mask) as u8,
+        mods_vmods: 0,
+        num_levels,
+        has_preserve: false,
+        map: map
+            .iter()
+            .map(|(mods, level)| KTMapEntry {
+                active: true,
+                mods_mask: u16::from(*mods) as u8,
+                level: *level,
+                mods_mods: u16::from(*mods) as u8,
+                mods_vmods: 0,
+            })
+            .collect(),
+        preserve: vec![],
+    }
+}
+
+// the server keymap of `us,ru` with the ALPHABETIC and KEYPAD types and NumLock on mod2:
+// keycode 8 is `a`/`ф`, 9 is `f`/`а`, 10 is the keypad 1 and 11 is NumLock
+pub(crate) fn server_keymap() -> ServerKeymap {
+    let alphabetic = key_type(
+        ModMask::SHIFT | ModMask::LOCK,
+        2,
+        &[(ModMask::SHIFT, 1), (ModMask::LOCK, 1)],
+    );
+    let keypad = key_type(
+        ModMask::SHIFT | ModMask::M2,
+        2,
+        &[(ModMask::M2, 1), (ModMask::SHIFT, 1)],
+    );
+    let one_level = key_type(ModMask::from(0u16), 1, &[]);
+    let key = |kt_index: u8, groups: u8, syms: &[Keysym]| KeySymMap {
+        kt_index: [kt_index; 4],
+        group_info: groups,
+        width: 2,
+        syms: syms.to_vec(),
+    };
+
+    ServerKeymap {
+        min_keycode: 8,
+        max_keycode: 255,
+        types: vec![one_level, alphabetic, keypad],
+        keys: vec![
+            key(1, 2, &[0x61, 0x41, 0x6c6, 0x6e6]),
+            key(1, 2, &[0x66, 0x46, 0x6c1, 0x6e1]),
+            key(2, 1, &[0xff9c, 0xffb1]),
+            key(0, 1, &[0xff7f]),
+        ],
+        modifier_map: vec![KeyModMap {
+            keycode: 11,
+            mods: u16::from(ModMask::M2) as u8,
+        }],
+    }
+}
+
+// a key press without any modifiers but `state` on the window 2
+pub(crate) fn press(detail: u8, state: u16) -> KeyPressEvent {
+    KeyPressEvent {
+        response_type: KEY_PRESS_EVENT,
+        detail,
+        sequence: 0,
+        time: CURRENT_TIME,
+        root: 1,
+        event: 2,
+        child: NONE,
+        root_x: 0,
+        root_y: 0,
+        event_x: 0,
+        event_y: 0,
+        state,
+        same_screen: true,
+    }
+}
+
+// atoms as the server could have interned them
+pub(crate) fn atoms() -> Atoms {
+    Atoms {
+        net_wm_pid: 300,
+        net_wm_name: 301,
+        utf8_string: 302,
+        xkb_rules_names: 303,
+        mmk_active_layout: 304,
+        net_active_window: 305,
+        net_client_list: 306,
+        net_wm_state: 307,
+        net_wm_state_fullscreen: 308,
+        wm_window_role: 309,
+        net_wm_window_type: 310,
+    }
+}
+
+// the config of a command line, without the name of the program
+pub(crate) fn config(args: &[&str]) -> Config {
+    let args = std::iter::once("mmk").chain(args.iter().copied());
+    Config::from_args(args.map(String::from).collect()).unwrap()
+}
+
+// the error the X server answers a request with, with only its kind filled in
+pub(crate) fn x11_error(error_kind: ErrorKind) -> ReplyError {
+    ReplyError::X11Error(x11rb::x11_utils::X11Error {
+        error_kind,
+        error_code: 0,
+        sequence: 0,
+        bad_value: 0,
+        minor_opcode: 0,
+        major_opcode: 0,
+        extension_name: None,
+        request_name: None,
+    })
+}
+
+// an X server with the root window 1 which keeps every request sent to it and answers the ones
+// with a reply from `replies`, in the order they come in
+pub(crate) struct FakeServer {
+    setup: Setup,
+    requests: RefCell<Vec<Request<'static>>>,
+    replies: RefCell<VecDeque<Vec<u8>>>,
+    answered: RefCell<HashMap<SequenceNumber, Vec<u8>>>,
+    next_id: Cell<u32>,
+}
+
+impl FakeServer {
+    pub(crate) fn new(replies: Vec<Vec<u8>>) -> Self {
+        let screen = Screen {
+            root: 1,
+            white_pixel: 0xffffff,
+            ..Default::default()
+        };
+        Self {
+            setup: Setup {
+                roots: vec![screen],
+                ..Default::default()
+            },
+            requests: RefCell::default(),
+            replies: RefCell::new(replies.into()),
+            answered: RefCell::default(),
+            next_id: Cell::new(0x400001),
+        }
+    }
+
+    // the requests sent so far, taken out of the server
+    pub(crate) fn take_requests(&self) -> Vec<Request<'static>> {
+        self.requests.take()
+    }
+
+    fn send(&self, bufs: &[IoSlice], has_reply: bool) -> SequenceNumber {
+        let bytes: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        let (header, body) = parse_request_header(&bytes, BigRequests::NotEnabled).unwrap();
+        let request = Request::parse(header, body, &mut vec![], self).unwrap();
+        let mut requests = self.requests.borrow_mut();
+        requests.push(request.into_owned());
+        let sequence = requests.len() as SequenceNumber;
+        if has_reply {
+            let reply = self.replies.borrow_mut().pop_front();
+            let reply = reply.unwrap_or_else(|| panic!("no reply for {:?}", requests.last()));
+            self.answered.borrow_mut().insert(sequence, reply);
+        }
+        sequence
+    }
+}
+
+// no extensions to know about
+impl ExtInfoProvider for FakeServer {
+    fn get_from_major_opcode(&self, _: u8) -> Option<(&str, ExtensionInformation)> {
+        None
+    }
+
+    fn get_from_event_code(&self, _: u8) -> Option<(&str, ExtensionInformation)> {
+        None
+    }
+
+    fn get_from_error_code(&self, _: u8) -> Option<(&str, ExtensionInformation)> {
+        None
+    }
+}
+
+impl RequestConnection for FakeServer {
+    type Buf = Vec<u8>;
+
+    fn send_request_with_reply<R: TryParse>(
+        &self,
+        bufs: &[IoSlice],
+        _: Vec<RawFdContainer>,
+    ) -> Result<Cookie<'_, Self, R>, ConnectionError> {
+        Ok(Cookie::new(self, self.send(bufs, true)))
+    }
+
+    fn send_request_with_reply_with_fds<R: TryParseFd>(
+        &self,
+        _: &[IoSlice],
+        _: Vec<RawFdContainer>,
+    ) -> Result<CookieWithFds<'_, Self, R>, ConnectionError> {
+        Err(ConnectionError::FdPassingFailed)
+    }
+
+    fn send_request_without_reply(
+        &self,
+        bufs: &[IoSlice],
+        _: Vec<RawFdContainer>,
+    ) -> Result<VoidCookie<'_, Self>, ConnectionError> {
+        Ok(VoidCookie::new(self, self.send(bufs, false)))
+    }
+
+    fn discard_reply(&self, sequence: SequenceNumber, _: RequestKind, _: DiscardMode) {
+        self.answered.borrow_mut().remove(&sequence);
+    }
+
+    fn prefetch_extension_information(&self, _: &'static str) -> Result<(), ConnectionError> {
+        Ok(())
+    }
+
+    fn extension_information(
+        &self,
+        _: &'static str,
+    ) -> Result<Option<ExtensionInformation>, ConnectionError> {
+        Ok(None)
+    }
+
+    fn wait_for_reply_or_raw_error(
+        &self,
+        sequence: SequenceNumber,
+    ) -> Result<ReplyOrError<Vec<u8>>, ConnectionError> {
+        let reply = self.answered.borrow_mut().remove(&sequence);
+        match reply {
+            // see error_reply
+            Some(error) if error[0] == 0 => Ok(ReplyOrError::Error(error)),
+            Some(reply) => Ok(ReplyOrError::Reply(reply)),
+            None => Err(ConnectionError::UnknownError),
+        }
+    }
+
+    fn wait_for_reply(&self, sequence: SequenceNumber) -> Result<Option<Vec<u8>>, ConnectionError> {
+        Ok(self.answered.borrow_mut().remove(&sequence))
+    }
+
+    fn wait_for_reply_with_fds_raw(
+        &self,
+        _: SequenceNumber,
+    ) -> Result<ReplyOrError<BufWithFds<Vec<u8>>, Vec<u8>>, ConnectionError> {
+        Err(ConnectionError::FdPassingFailed)
+    }
+
+    fn check_for_raw_error(&self, _: SequenceNumber) -> Result<Option<Vec<u8>>, ConnectionError> {
+        Ok(None)
+    }
+
+    fn prefetch_maximum_request_bytes(&self) {}
+
+    fn maximum_request_bytes(&self) -> usize {
+        usize::from(u16::MAX) * 4
+    }
+
+    fn parse_error(&self, error: &[u8]) -> Result<X11Error, ParseError> {
+        X11Error::try_parse(error, self)
+    }
+
+    fn parse_event(&self, event: &[u8]) -> Result<Event, ParseError> {
+        Event::parse(event, self)
+    }
+}
+
+impl Connection for FakeServer {
+    fn wait_for_raw_event_with_sequence(
+        &self,
+    ) -> Result<RawEventAndSeqNumber<Vec<u8>>, ConnectionError> {
+        Err(ConnectionError::UnknownError)
+    }
+
+    fn poll_for_raw_event_with_sequence(
+        &self,
+    ) -> Result<Option<RawEventAndSeqNumber<Vec<u8>>>, ConnectionError> {
+        Ok(None)
+    }
+
+    fn flush(&self) -> Result<(), ConnectionError> {
+        Ok(())
+    }
+
+    fn setup(&self) -> &Setup {
+        &self.setup
+    }
+
+    fn generate_id(&self) -> Result<u32, ReplyOrIdError> {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        Ok(id)
+    }
+}
+
+// a reply with `header` as its second byte and `fields` after the sequence number and length,
+// padded to at least 32 bytes
+fn reply(header: u8, fields: &[u8]) -> Vec<u8> {
+    let mut reply = vec![1, header, 0, 0];
+    let length = fields.len().saturating_sub(24).div_ceil(4) as u32;
+    reply.extend(length.to_ne_bytes());
+    reply.extend(fields);
+    reply.resize(32 + length as usize * 4, 0);
+    reply
+}
+
+// the error the server answers a request with instead of a reply, like WINDOW_ERROR for a window
+// which is gone
+pub(crate) fn error_reply(error_code: u8) -> Vec<u8> {
+    let mut error = vec![0; 32];
+    error[1] = error_code;
+    error
+}
+
+// the reply to QueryTree of a window below `parent`
+pub(crate) fn tree_reply(parent: u32, children: &[u32]) -> Vec<u8> {
+    let mut fields = 1u32.to_ne_bytes().to_vec();
+    fields.extend(parent.to_ne_bytes());
+    fields.extend((children.len() as u16).to_ne_bytes());
+    fields.extend([0; 14]);
+    fields.extend(children.iter().flat_map(|child| child.to_ne_bytes()));
+    reply(0, &fields)
+}
+
+// the reply to GetProperty with the `format` bit items of `value`
+fn property_reply(type_: u32, format: u8, value: &[u8]) -> Vec<u8> {
+    let mut fields = type_.to_ne_bytes().to_vec();
+    fields.extend(0u32.to_ne_bytes());
+    let items = value.len() / usize::from(format / 8);
+    fields.extend((items as u32).to_ne_bytes());
+    fields.extend([0; 12]);
+    fields.extend(value);
+    reply(format, &fields)
+}
+
+// the reply to GetProperty of a list of atoms like _NET_WM_STATE
+pub(crate) fn atoms_reply(atoms: &[u32]) -> Vec<u8> {
+    let value: Vec<u8> = atoms.iter().flat_map(|atom| atom.to_ne_bytes()).collect();
+    property_reply(AtomEnum::ATOM.into(), 32, &value)
+}
+
+// the reply to GetProperty of a text property like WM_NAME
+pub(crate) fn text_reply(type_: u32, text: &str) -> Vec<u8> {
+    property_reply(type_, 8, text.as_bytes())
+}
+
+// the reply to GetWindowAttributes of a mapped window which selected `your_event_mask`
+pub(crate) fn window_attributes_reply(your_event_mask: u32) -> Vec<u8> {
+    let mut fields = [0; 36];
+    // map_state
+    fields[18] = 2;
+    fields[28..32].copy_from_slice(&your_event_mask.to_ne_bytes());
+    reply(0, &fields)
+}
+
+pub(crate) fn grab_keyboard_reply(status: GrabStatus) -> Vec<u8> {
+    reply(status.into(), &[])
+}
@@ -0,0 +1,813 @@
+//! Picking the key a window gets instead of the one typed.
+
+use std::{collections::HashMap, io::Write};
+
+use x11rb::{
+    connection::Connection,
+    protocol::xproto::{KeyPressEvent, KeyReleaseEvent, Keysym, KEY_PRESS_EVENT},
+    CURRENT_TIME, NONE,
+};
+#[cfg(feature = "xkbcommon")]
+use xkbcommon::xkb;
+
+use crate::{
+    config::Config,
+    keymap::{
+        is_dead_keysym, is_printable_keysym, keysym_name, locked_modifiers, KeymapSource,
+        ServerKeymap,
+    },
+};
+
+/// A key event of a window mmk runs on.
+pub enum KeyEvent {
+    Press(KeyPressEvent),
+    Release(KeyReleaseEvent),
+}
+
+/// What a key is sent as instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translated {
+    /// The keycode.
+    pub detail: u8,
+    /// The modifier mask.
+    pub state: u16,
+    /// The keysym the keycode and modifiers type.
+    pub keysym: Keysym,
+}
+
+// what the event loop does with a grabbed key. the grab keeps the window from getting the key
+// on its own, so a key that isn't translated still has to be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Delivery {
+    // sent as the translation
+    Translated(Translated),
+    // sent the way it came in
+    Untouched,
+    // not sent at all, like the bounces of --debounce and the keys of a compose sequence
+    Dropped,
+}
+
+impl From<Option<Translated>> for Delivery {
+    fn from(translated: Option<Translated>) -> Self {
+        translated.map_or(Self::Untouched, Self::Translated)
+    }
+}
+
+// the event a grabbed key is sent as, `None` when it is dropped
+pub(crate) fn outgoing_event(event: &KeyPressEvent, delivery: Delivery) -> Option<KeyPressEvent> {
+    let mut sent = *event;
+    match delivery {
+        Delivery::Translated(translated) => {
+            sent.detail = translated.detail;
+            sent.state = translated.state;
+        }
+        Delivery::Untouched => (),
+        Delivery::Dropped => return None,
+    }
+    sent.time = CURRENT_TIME;
+
+    Some(sent)
+}
+
+// the translated keys run through the Compose table for --translate-compose
+#[cfg(feature = "xkbcommon")]
+pub(crate) struct Compose {
+    pub(crate) state: xkb::compose::State,
+    // what the release of a key sends, dropped for the keys of a sequence
+    pub(crate) pending: HashMap<u8, Delivery>,
+}
+
+#[cfg(feature = "xkbcommon")]
+impl Compose {
+    pub(crate) fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .iter()
+            .filter_map(std::env::var_os)
+            .find(|locale| !locale.is_empty())
+            .unwrap_or_else(|| "C".into());
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let table =
+            xkb::compose::Table::new_from_locale(&context, &locale, xkb::compose::COMPILE_NO_FLAGS)
+                .map_err(|_| {
+                    format!("failed to load the compose table for the locale `{locale:?}`")
+                })?;
+
+        Ok(Self::with_table(&table))
+    }
+
+    pub(crate) fn with_table(table: &xkb::compose::Table) -> Self {
+        Self {
+            state: xkb::compose::State::new(table, xkb::compose::STATE_NO_FLAGS),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn feed(
+        &mut self,
+        keymap: &impl KeymapSource,
+        press: bool,
+        detail: u8,
+        delivery: Delivery,
+    ) -> Delivery {
+        if !press {
+            return self.pending.remove(&detail).unwrap_or(delivery);
+        }
+        // keys which aren't translated go out as they are, like in a window without mmk
+        let Delivery::Translated(translated) = delivery else {
+            return delivery;
+        };
+
+        self.state.feed(translated.keysym);
+        match self.state.status() {
+            xkb::compose::Status::Composing => {
+                self.pending.insert(detail, Delivery::Dropped);
+                Delivery::Dropped
+            }
+            xkb::compose::Status::Composed => {
+                let composed = self.state.keysym().and_then(|keysym| {
+                    let keycode = keymap.keysym_to_keycode(keysym);
+                    // a character the keymap can't type is lost like in a sequence without a result
+                    (keycode != 0).then(|| Translated {
+                        detail: keycode,
+                        state: keymap.keysym_to_mods(keysym) as _,
+                        keysym,
+                    })
+                });
+                let composed = composed.map_or(Delivery::Dropped, Delivery::Translated);
+                self.state.reset();
+                self.pending.insert(detail, composed);
+                composed
+            }
+            xkb::compose::Status::Cancelled => {
+                self.state.reset();
+                delivery
+            }
+            xkb::compose::Status::Nothing => delivery,
+        }
+    }
+}
+
+// without xkbcommon there is no Compose table to read
+#[cfg(not(feature = "xkbcommon"))]
+pub(crate) struct Compose;
+
+#[cfg(not(feature = "xkbcommon"))]
+impl Compose {
+    pub(crate) fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        Err("--translate-compose needs mmk to be built with the `xkbcommon` feature".into())
+    }
+
+    pub(crate) fn feed(
+        &mut self,
+        _keymap: &impl KeymapSource,
+        _press: bool,
+        _detail: u8,
+        delivery: Delivery,
+    ) -> Delivery {
+        delivery
+    }
+}
+
+// why a key is left alone no matter which keycode it would translate to
+pub(crate) fn skip_reason(config: &Config, lookup: &Lookup) -> Option<&'static str> {
+    if lookup.filtered {
+        return Some("--event-filter doesn't hold for it");
+    }
+    let dead = config.translate_dead_keys && is_dead_keysym(lookup.source_keysym);
+    if config.only_printable && !dead && !is_printable_keysym(lookup.source_keysym) {
+        return Some("it doesn't type a character");
+    }
+    if config.translate_dead_keys && is_dead_keysym(lookup.layout_keysym) && lookup.keycode == 0 {
+        return Some("it is a dead key the keymap can't type");
+    }
+
+    None
+}
+
+// every intermediate step of a translation, printed by --explain-key
+pub(crate) struct Lookup {
+    pub(crate) source_keysym: Keysym,
+    pub(crate) remapped: bool,
+    pub(crate) layout_keysym: Keysym,
+    pub(crate) keycode: u8,
+    pub(crate) modifiers: u32,
+    pub(crate) filtered: bool,
+    // `None` means the key goes to the window the way it came in
+    pub(crate) translated: Option<Translated>,
+}
+
+// `locked` are the modifiers locked on the keyboard, see --respect-numlock-state
+pub(crate) fn lookup(
+    keymap: &impl KeymapSource,
+    event: &KeyPressEvent,
+    layout_index: usize,
+    locked: u16,
+    config: &Config,
+) -> Lookup {
+    // remaps apply to the unshifted keysym in the currently active group, bits 13 and 14
+    let group = (event.state >> 13) & 0x3;
+    let source_keysym = keymap.keycode_to_keysym(event.detail, group.into(), 0);
+    let state = (event.state | locked) & !config.ignore_modifiers;
+    // an explicit remap wins over the layout, see the translation order in the help
+    let remap = config.remaps.iter().find(|(src, _)| *src == source_keysym);
+    let layout_keysym = match (remap, &config.keymap) {
+        (Some((_, dst)), _) => *dst,
+        (None, Some(keymap)) => match config.translate_level {
+            Some(level) => keymap.keysym_at_level(event.detail, layout_index, level),
+            None => keymap.keysym(event.detail, layout_index, state),
+        },
+        (None, None) => {
+            let level = config.translate_level.map_or_else(
+                || keymap.shift_level(event.detail, layout_index, state),
+                |level| level as _,
+            );
+            keymap.keycode_to_keysym(event.detail, layout_index, level)
+        }
+    };
+
+    let ret = (
+        keymap.keysym_to_keycode(layout_keysym),
+        keymap.keysym_to_mods(layout_keysym),
+    );
+
+    // a keysym no key of the keymap types comes back as keycode 0, sending that would type
+    // nothing at all, so the key is sent untranslated instead
+    let unmapped = ret.0 == 0;
+    let below_min = config.min_keycode.is_some_and(|min| ret.0 < min);
+    let above_max = ret.0 > config.max_keycode.unwrap_or(keymap.max_keycode());
+    let mut trace = Lookup {
+        source_keysym,
+        remapped: remap.is_some(),
+        layout_keysym,
+        keycode: ret.0,
+        modifiers: ret.1,
+        filtered: config
+            .event_filter
+            .as_ref()
+            .is_some_and(|filter| !filter.matches(event.detail, event.state)),
+        translated: None,
+    };
+    if skip_reason(config, &trace).is_none() && !unmapped && !below_min && !above_max {
+        trace.translated = Some(Translated {
+            detail: ret.0,
+            state: ret.1 as _,
+            keysym: layout_keysym,
+        });
+    }
+
+    trace
+}
+
+/// Picks what to send to a window instead of `ev`, which the window expects in the layout
+/// `layout_index` of `keymap`. `locked` are the locked modifiers the key is translated with for
+/// `--respect-numlock-state`. `None` means the key isn't translated and should reach the window
+/// the way it came in.
+pub fn translate(
+    keymap: &impl KeymapSource,
+    ev: KeyEvent,
+    layout_index: usize,
+    locked: u16,
+    config: &Config,
+) -> Result<Option<Translated>, Box<dyn std::error::Error>> {
+    let event = match ev {
+        KeyEvent::Press(e) => e,
+        KeyEvent::Release(e) => e,
+    };
+
+    Ok(lookup(keymap, &event, layout_index, locked, config).translated)
+}
+
+pub(crate) fn explain_key(
+    conn: &impl Connection,
+    server_keymap: &ServerKeymap,
+    root: u32,
+    keycode: u8,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let locked = if config.respect_numlock_state {
+        locked_modifiers(conn, server_keymap)
+    } else {
+        0
+    };
+
+    Ok(write_explanation(
+        &mut std::io::stdout(),
+        server_keymap,
+        root,
+        keycode,
+        locked,
+        config,
+    )?)
+}
+
+// the steps of translating a press of `keycode`, one `step:\tvalue` line each
+fn write_explanation(
+    out: &mut impl Write,
+    keymap: &impl KeymapSource,
+    root: u32,
+    keycode: u8,
+    locked: u16,
+    config: &Config,
+) -> std::io::Result<()> {
+    let layout_index = config.layout.first().copied().unwrap_or(0);
+    // a plain press without any modifiers, in the first group
+    let event = KeyPressEvent {
+        response_type: KEY_PRESS_EVENT,
+        detail: keycode,
+        sequence: 0,
+        time: CURRENT_TIME,
+        root,
+        event: root,
+        child: NONE,
+        root_x: 0,
+        root_y: 0,
+        event_x: 0,
+        event_y: 0,
+        state: 0,
+        same_screen: true,
+    };
+    let trace = lookup(keymap, &event, layout_index, locked, config);
+
+    writeln!(out, "keycode:\t{keycode}")?;
+    writeln!(out, "layout:\t{layout_index}")?;
+    writeln!(
+        out,
+        "source keysym:\t{} ({:#x})",
+        keysym_name(trace.source_keysym),
+        trace.source_keysym
+    )?;
+    writeln!(
+        out,
+        "layout keysym:\t{} ({:#x}){}",
+        keysym_name(trace.layout_keysym),
+        trace.layout_keysym,
+        if trace.remapped { ", remapped" } else { "" }
+    )?;
+    writeln!(out, "layout keycode:\t{}", trace.keycode)?;
+    writeln!(out, "layout modifiers:\t{:#x}", trace.modifiers)?;
+    match trace.translated {
+        Some(translated) => {
+            writeln!(out, "decision:\tsend the translated key")?;
+            writeln!(
+                out,
+                "result:\t(detail {}, state {:#x})",
+                translated.detail, translated.state
+            )?;
+        }
+        None => {
+            let min = config.min_keycode.unwrap_or(1);
+            let max = config.max_keycode.unwrap_or(keymap.max_keycode());
+            let reason = match skip_reason(config, &trace) {
+                Some(reason) => reason.to_string(),
+                None if trace.keycode == 0 => "no key of the keymap types it".to_string(),
+                None => format!("keycode outside of {min}..={max}"),
+            };
+            writeln!(out, "decision:\tleave the key alone, {reason}")?;
+            writeln!(out, "result:\t(detail {keycode}, state 0x0)")?;
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn is_bounce(
+    last_press: &mut HashMap<u8, u32>,
+    keycode: u8,
+    time: u32,
+    debounce: u32,
+) -> bool {
+    // server timestamps wrap around after ~49 days
+    last_press
+        .insert(keycode, time)
+        .is_some_and(|last| time.wrapping_sub(last) < debounce)
+}
+
+#[cfg(test)]
+mod tests {
+    use x11rb::protocol::xproto::ModMask;
+
+    use super::*;
+    use crate::{
+        layout::shifted_layout,
+        testing::{config, press, server_keymap, TestKeymap},
+    };
+
+    #[test]
+    fn translate_picks_the_key_of_the_window_layout() {
+        // `a` on keycode 8 in the first group types `o` in the second
+        let keymap = TestKeymap::new(&[[[0x61, 0x41], [0x6f, 0x4f]], [[0x6f, 0x4f], [0, 0]]]);
+        let config = config(&["--layout", "1"]);
+
+        let translated = translate(&keymap, KeyEvent::Press(press(8, 0)), 1, 0, &config);
+        let translated = translated.unwrap().unwrap();
+        assert_eq!((translated.detail, translated.state), (9, 0));
+        assert_eq!(translated.keysym, 0x6f);
+    }
+
+    #[test]
+    fn translate_looks_up_the_level_of_the_modifiers() {
+        let keymap = server_keymap();
+        let config = config(&["--layout", "1"]);
+        let keysym = |state: u16| {
+            let event = KeyEvent::Press(press(9, state));
+            translate(&keymap, event, 1, 0, &config)
+                .unwrap()
+                .map(|translated| (translated.detail, translated.keysym))
+        };
+
+        // `f` is `а` in the second group, on the same key
+        assert_eq!(keysym(0), Some((9, 0x6c1)));
+        assert_eq!(keysym(ModMask::SHIFT.into()), Some((9, 0x6e1)));
+        assert_eq!(keysym(ModMask::LOCK.into()), Some((9, 0x6e1)));
+        // pressed while the second group is active, the group bits aren't modifiers
+        assert_eq!(keysym(0x2000), Some((9, 0x6c1)));
+        assert_eq!(keysym(0x2000 | u16::from(ModMask::SHIFT)), Some((9, 0x6e1)));
+    }
+
+    #[test]
+    fn ignored_modifiers_are_cleared_before_the_lookup() {
+        let keymap = server_keymap();
+        let keysym = |args: &[&str], state: ModMask| {
+            let event = KeyEvent::Press(press(9, state.into()));
+            translate(&keymap, event, 1, 0, &config(args))
+                .unwrap()
+                .map(|translated| translated.keysym)
+        };
+        let shift = ModMask::SHIFT | ModMask::M3;
+        let lock = ModMask::LOCK | ModMask::M3 | ModMask::M5;
+
+        assert_eq!(keysym(&["--layout", "1"], shift), Some(0x6e1));
+        assert_eq!(keysym(&["--layout", "1"], lock), Some(0x6e1));
+        // `а` instead of `А`, as if the ignored modifiers weren't held
+        let args = ["--layout", "1", "--ignore-modifiers", "Shift,Lock"];
+        assert_eq!(keysym(&args, shift), Some(0x6c1));
+        assert_eq!(keysym(&args, lock), Some(0x6c1));
+        // only the listed bits are cleared
+        let args = ["--layout", "1", "--ignore-modifiers", "Mod3,Mod5"];
+        assert_eq!(
+            config(&args).ignore_modifiers,
+            u16::from(ModMask::M3 | ModMask::M5)
+        );
+        assert_eq!(keysym(&args, shift), Some(0x6e1));
+        assert_eq!(keysym(&args, lock), Some(0x6e1));
+    }
+
+    #[test]
+    fn translate_level_forces_the_shift_level() {
+        let keymap = server_keymap();
+        let keysym = |args: &[&str], state: u16| {
+            let event = KeyEvent::Press(press(9, state));
+            translate(&keymap, event, 1, 0, &config(args))
+                .unwrap()
+                .map(|translated| translated.keysym)
+        };
+        let shift = u16::from(ModMask::SHIFT);
+
+        // `А` with and without Shift held
+        let args = ["--layout", "1", "--translate-level", "1"];
+        assert_eq!(keysym(&args, 0), Some(0x6e1));
+        assert_eq!(keysym(&args, shift), Some(0x6e1));
+        // and `а` even with it
+        let args = ["--layout", "1", "--translate-level", "0"];
+        assert_eq!(keysym(&args, shift), Some(0x6c1));
+        // without it the modifiers pick the level
+        assert_eq!(keysym(&["--layout", "1"], 0), Some(0x6c1));
+    }
+
+    #[test]
+    fn keypad_keys_type_digits_with_num_lock_locked() {
+        let keymap = server_keymap();
+        let config = config(&["--respect-numlock-state"]);
+        let keypad = |locked: u16| {
+            let event = KeyEvent::Press(press(10, 0));
+            translate(&keymap, event, 0, locked, &config)
+                .unwrap()
+                .unwrap()
+        };
+
+        // the event doesn't carry NumLock, the locked state of the keyboard does
+        let locked = u16::from(ModMask::M2 | ModMask::M4) & keymap.lock_mask();
+        let translated = keypad(locked);
+        assert_eq!(translated.keysym, 0xffb1);
+        assert_eq!(
+            (translated.detail, translated.state),
+            (10, ModMask::M2.into())
+        );
+        assert_eq!(keypad(0).keysym, 0xff9c);
+    }
+
+    #[test]
+    fn untranslated_keys_are_sent_unchanged() {
+        // `a` types `ä` in the second group, which no key of the first group has
+        let keymap = TestKeymap::new(&[[[0x61, 0x41], [0xe4, 0xc4]]]);
+        let config = config(&["--layout", "1"]);
+        let original = press(8, 0);
+
+        let translated = translate(&keymap, KeyEvent::Press(original), 1, 0, &config).unwrap();
+        assert!(translated.is_none());
+        let sent = outgoing_event(&original, Delivery::from(translated)).unwrap();
+        assert_eq!((sent.detail, sent.state), (8, 0));
+        assert_eq!(sent.event, original.event);
+    }
+
+    #[test]
+    fn remaps_win_over_the_layout() {
+        // Caps_Lock types `x` in the second group, `a` types `o`
+        let keymap = TestKeymap::new(&[
+            [[0xffe5, 0], [0x78, 0x58]],
+            [[0xff1b, 0], [0xff1b, 0]],
+            [[0x61, 0x41], [0x6f, 0x4f]],
+            [[0x62, 0x42], [0x62, 0x42]],
+            [[0x78, 0x58], [0x78, 0x58]],
+            [[0x6f, 0x4f], [0x61, 0x41]],
+        ]);
+        let detail = |config: &Config, keycode| {
+            translate(&keymap, KeyEvent::Press(press(keycode, 0)), 1, 0, config)
+                .unwrap()
+                .map(|translated| translated.detail)
+        };
+
+        let layout_only = config(&["--layout", "1"]);
+        assert_eq!(detail(&layout_only, 8), Some(12));
+        assert_eq!(detail(&layout_only, 10), Some(13));
+
+        let remapped = config(&[
+            "--layout",
+            "1",
+            "--translate-caps-to",
+            "Escape",
+            "--remap",
+            "a=b",
+        ]);
+        assert_eq!(detail(&remapped, 8), Some(9));
+        assert_eq!(detail(&remapped, 10), Some(11));
+        // keys without a remap still follow the layout
+        assert_eq!(detail(&remapped, 13), Some(10));
+        let trace = lookup(&keymap, &press(8, 0), 1, 0, &remapped);
+        assert!(trace.remapped);
+        assert_eq!(trace.layout_keysym, 0xff1b);
+    }
+
+    #[test]
+    fn explain_key_traces_every_step() {
+        // `a` types `o` in the second group, `ä` no key of the first group has
+        let keymap = TestKeymap::new(&[[[0x61, 0x41], [0x6f, 0x4f]], [[0x6f, 0x4f], [0xe4, 0xc4]]]);
+        let explain = |args: &[&str], keycode| {
+            let mut out = vec![];
+            write_explanation(&mut out, &keymap, 1, keycode, 0, &config(args)).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            explain(&["--layout", "1"], 8),
+            "keycode:\t8\n\
+             layout:\t1\n\
+             source keysym:\ta (0x61)\n\
+             layout keysym:\to (0x6f)\n\
+             layout keycode:\t9\n\
+             layout modifiers:\t0x0\n\
+             decision:\tsend the translated key\n\
+             result:\t(detail 9, state 0x0)\n"
+        );
+        assert_eq!(
+            explain(&["--layout", "1", "--remap", "o=adiaeresis"], 9),
+            "keycode:\t9\n\
+             layout:\t1\n\
+             source keysym:\to (0x6f)\n\
+             layout keysym:\tadiaeresis (0xe4), remapped\n\
+             layout keycode:\t0\n\
+             layout modifiers:\t0x0\n\
+             decision:\tleave the key alone, no key of the keymap types it\n\
+             result:\t(detail 9, state 0x0)\n"
+        );
+    }
+
+    #[test]
+    fn remaps_outlast_a_layout_switch() {
+        // `a` and `o` swap places in the second group, `b` is on keycode 10
+        let keymap = TestKeymap::new(&[
+            [[0x61, 0x41], [0x6f, 0x4f]],
+            [[0x6f, 0x4f], [0x61, 0x41]],
+            [[0x62, 0x42], [0x62, 0x42]],
+        ]);
+        let config = config(&["--layout", "1", "--remap", "a=b"]);
+        let detail = |layout, keycode| {
+            translate(
+                &keymap,
+                KeyEvent::Press(press(keycode, 0)),
+                layout,
+                0,
+                &config,
+            )
+            .unwrap()
+            .map(|translated| translated.detail)
+        };
+
+        assert_eq!(detail(1, 8), Some(10));
+        assert_eq!(detail(1, 9), Some(8));
+        // SIGUSR1 of --switch-layout-on-signal moves the window on to the first layout
+        let switched = shifted_layout(1, 1, 2);
+        assert_eq!(switched, 0);
+        assert_eq!(detail(switched, 8), Some(10));
+        assert_eq!(detail(switched, 9), Some(9));
+        assert_eq!(shifted_layout(switched, -1, 2), 1);
+    }
+
+    #[test]
+    fn only_printable_sends_other_keys_unchanged() {
+        // `a` and `o`, Return and Left, which are the same in both groups
+        let keymap = TestKeymap::new(&[
+            [[0x61, 0x41], [0x6f, 0x4f]],
+            [[0x6f, 0x4f], [0x65, 0x45]],
+            [[0xff0d, 0xff0d], [0xff0d, 0xff0d]],
+            [[0xff51, 0xff51], [0xff51, 0xff51]],
+        ]);
+        let config = config(&["--layout", "1", "--only-printable"]);
+        let sent = |keycode| {
+            let original = press(keycode, 0);
+            let translated = translate(&keymap, KeyEvent::Press(original), 1, 0, &config);
+            let sent = outgoing_event(&original, Delivery::from(translated.unwrap())).unwrap();
+            (sent.detail, sent.state)
+        };
+
+        assert_eq!(sent(8), (9, 0));
+        assert_eq!(sent(10), (10, 0));
+        assert_eq!(sent(11), (11, 0));
+        let lookup = lookup(&keymap, &press(10, 0), 1, 0, &config);
+        assert_eq!(
+            skip_reason(&config, &lookup),
+            Some("it doesn't type a character")
+        );
+    }
+
+    #[test]
+    fn out_of_range_keycodes_fall_back_to_the_original_key() {
+        // `a` and `o` swap places in the second group
+        let keymap = TestKeymap::new(&[[[0x61, 0x41], [0x6f, 0x4f]], [[0x6f, 0x4f], [0x61, 0x41]]]);
+        let sent = |args: &[&str], keycode| {
+            let original = press(keycode, u16::from(ModMask::SHIFT));
+            let translated = translate(&keymap, KeyEvent::Press(original), 1, 0, &config(args));
+            let sent = outgoing_event(&original, Delivery::from(translated.unwrap())).unwrap();
+            (sent.detail, sent.state)
+        };
+        let shift = u16::from(ModMask::SHIFT);
+
+        assert_eq!(sent(&["--layout", "1"], 8), (9, shift));
+        // `o` is on keycode 9, above --max-keycode
+        assert_eq!(
+            sent(&["--layout", "1", "--max-keycode", "8"], 8),
+            (8, shift)
+        );
+        // `a` is on keycode 8, below --min-keycode
+        assert_eq!(
+            sent(&["--layout", "1", "--min-keycode", "9"], 9),
+            (9, shift)
+        );
+    }
+
+    #[test]
+    fn keycode_bounds_override_the_keymap_range() {
+        // `a` on keycode 8 types `z`, which is on keycode 210, above the old magic 204
+        let mut keys = vec![[[0, 0], [0, 0]]; 203];
+        keys[0] = [[0x61, 0x41], [0x7a, 0x5a]];
+        keys[202] = [[0x7a, 0x5a], [0x61, 0x41]];
+        let keymap = TestKeymap::new(&keys);
+        let sent = |args: &[&str]| {
+            let original = press(8, 0);
+            let translated = translate(&keymap, KeyEvent::Press(original), 1, 0, &config(args));
+            outgoing_event(&original, Delivery::from(translated.unwrap()))
+                .unwrap()
+                .detail
+        };
+
+        assert_eq!(sent(&["--layout", "1"]), 210);
+        assert_eq!(sent(&["--layout", "1", "--max-keycode", "204"]), 8);
+        assert_eq!(sent(&["--layout", "1", "--min-keycode", "211"]), 8);
+        assert_eq!(
+            sent(&[
+                "--layout",
+                "1",
+                "--min-keycode",
+                "210",
+                "--max-keycode",
+                "255"
+            ]),
+            210
+        );
+
+        let args = ["mmk", "--max-keycode", "256"];
+        assert!(Config::from_args(args.map(String::from).to_vec()).is_err());
+    }
+
+    #[test]
+    fn presses_within_the_debounce_window_bounce() {
+        let mut last_press = HashMap::new();
+
+        assert!(!is_bounce(&mut last_press, 38, 1000, 30));
+        assert!(is_bounce(&mut last_press, 38, 1029, 30));
+        // every press starts the window again, also one which bounced
+        assert!(is_bounce(&mut last_press, 38, 1058, 30));
+        assert!(!is_bounce(&mut last_press, 38, 1088, 30));
+        // other keys have a window of their own
+        assert!(!is_bounce(&mut last_press, 39, 1090, 30));
+        // across the wrap around of the server time
+        assert!(!is_bounce(&mut last_press, 40, u32::MAX - 5, 30));
+        assert!(is_bounce(&mut last_press, 40, 10, 30));
+    }
+
+    #[test]
+    fn dropped_keys_are_not_sent() {
+        assert!(outgoing_event(&press(8, 0), Delivery::Dropped).is_none());
+    }
+
+    #[cfg(not(feature = "xkbcommon"))]
+    #[test]
+    fn translate_compose_needs_the_xkbcommon_feature() {
+        let err = Compose::new().err().unwrap();
+        assert!(err.to_string().contains("`xkbcommon` feature"));
+    }
+
+    #[cfg(feature = "xkbcommon")]
+    #[test]
+    fn compose_sequences_send_the_composed_character() {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let table = xkb::compose::Table::new_from_buffer(
+            &context,
+            "<dead_diaeresis> <u> : \"ü\" udiaeresis\n",
+            "C",
+            xkb::compose::FORMAT_TEXT_V1,
+            xkb::compose::COMPILE_NO_FLAGS,
+        )
+        .unwrap();
+        let mut compose = Compose::with_table(&table);
+        // ¨ on keycode 8, u on 9 and ü on 10
+        let keymap = TestKeymap::new(&[
+            [[0xfe57, 0], [0xfe57, 0]],
+            [[0x75, 0x55], [0x75, 0x55]],
+            [[0xfc, 0xdc], [0xfc, 0xdc]],
+        ]);
+        let key = |detail, keysym| {
+            Delivery::Translated(Translated {
+                detail,
+                state: 0,
+                keysym,
+            })
+        };
+        let composed = key(10, 0xfc);
+
+        assert_eq!(
+            compose.feed(&keymap, true, 8, key(8, 0xfe57)),
+            Delivery::Dropped
+        );
+        assert_eq!(
+            compose.feed(&keymap, false, 8, key(8, 0xfe57)),
+            Delivery::Dropped
+        );
+        assert_eq!(compose.feed(&keymap, true, 9, key(9, 0x75)), composed);
+        assert_eq!(compose.feed(&keymap, false, 9, key(9, 0x75)), composed);
+        // the sequence is over, the next u is a u again
+        assert_eq!(compose.feed(&keymap, true, 9, key(9, 0x75)), key(9, 0x75));
+    }
+
+    #[cfg(not(feature = "xkbcommon"))]
+    #[test]
+    fn keymap_string_needs_the_xkbcommon_feature() {
+        let args = ["mmk", "--keymap-string", "xkb_keymap {};"];
+        let err = Config::from_args(args.map(String::from).to_vec()).unwrap_err();
+        assert!(err.to_string().contains("`xkbcommon` feature"));
+    }
+
+    #[cfg(feature = "xkbcommon")]
+    #[test]
+    fn keymap_string_is_compiled_and_translated_with() {
+        let keymap = r#"xkb_keymap {
+            xkb_keycodes "test" { minimum = 8; maximum = 255; <AC01> = 38; <AC02> = 39; };
+            xkb_types "test" {
+                type "TWO_LEVEL" {
+                    modifiers = Shift;
+                    map[Shift] = Level2;
+                    level_name[Level1] = "Base";
+                    level_name[Level2] = "Shift";
+                };
+            };
+            xkb_compat "test" { };
+            xkb_symbols "test" {
+                key <AC01> { type = "TWO_LEVEL", symbols[Group1] = [ a, A ], symbols[Group2] = [ o, O ] };
+                key <AC02> { type = "TWO_LEVEL", symbols[Group1] = [ o, O ], symbols[Group2] = [ e, E ] };
+            };
+        };"#;
+        let mut server = TestKeymap::new(&[[[0, 0], [0, 0]]; 40]);
+        server.keys[38 - 8] = [[0x61, 0x41], [0x6f, 0x4f]];
+        server.keys[39 - 8] = [[0x6f, 0x4f], [0x65, 0x45]];
+        let config = config(&["--keymap-string", keymap, "--layout", "1"]);
+
+        let shifted = press(38, ModMask::SHIFT.into());
+        let translated = translate(&server, KeyEvent::Press(shifted), 1, 0, &config);
+        let translated = translated.unwrap().unwrap();
+        assert_eq!(translated.keysym, 0x4f);
+        assert_eq!(
+            (translated.detail, translated.state),
+            (39, ModMask::SHIFT.into())
+        );
+    }
+}
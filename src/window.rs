@@ -0,0 +1,907 @@
+//! Grabbing the windows and handing them back.
+
+use std::collections::HashMap;
+
+use x11rb::{
+    connection::Connection,
+    errors::ReplyError,
+    image::{Image, PixelLayout},
+    protocol::{
+        xproto::{
+            Allow, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, CreateWindowAux, EventMask,
+            GrabMode, GrabStatus, Keysym, MapState, ModMask, PropMode, WindowClass,
+        },
+        ErrorKind,
+    },
+    wrapper::ConnectionExt as _,
+    CURRENT_TIME,
+};
+
+use crate::{
+    config::Config,
+    keymap::{keysym_name, KeymapSource, ServerKeymap},
+    layout::XkbLayout,
+    matching::{window_names, Atoms},
+    warn,
+};
+
+// hands the grabbed windows back the way they were found
+pub(crate) fn restore_windows(
+    conn: &impl Connection,
+    atoms: &Atoms,
+    tracked: &HashMap<u32, Tracked>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (window, record) in tracked.iter() {
+        release_window(conn, Some(atoms), *window, record)?;
+    }
+    conn.flush()?;
+    Ok(())
+}
+
+// the windows beyond `max` which had no key for the longest time, never seen ones first
+pub(crate) fn windows_to_evict(
+    windows: impl Iterator<Item = u32>,
+    last_seen: &HashMap<u32, u64>,
+    max: usize,
+) -> Vec<u32> {
+    let mut windows: Vec<u32> = windows.collect();
+    windows.sort_by_key(|window| (last_seen.get(window).copied().unwrap_or(0), *window));
+    windows.truncate(windows.len().saturating_sub(max));
+    windows
+}
+
+// lets go of the windows beyond --max-tracked, returns them to forget about them everywhere else
+pub(crate) fn evict_windows(
+    conn: &impl Connection,
+    atoms: &Atoms,
+    config: &Config,
+    tracked: &mut HashMap<u32, Tracked>,
+    last_seen: &mut HashMap<u32, u64>,
+) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let Some(max) = config.max_tracked else {
+        return Ok(vec![]);
+    };
+    let evicted = windows_to_evict(tracked.keys().copied(), last_seen, max);
+    for window in evicted.iter() {
+        if let Some(record) = tracked.remove(window) {
+            release_window(conn, Some(atoms), *window, &record)?;
+        }
+        last_seen.remove(window);
+        warn(
+            config,
+            &format!("Running on more than {max} windows, letting go of 0x{window:08x}."),
+        );
+    }
+    conn.flush()?;
+
+    Ok(evicted)
+}
+
+// `atoms` are only needed to put back a title changed by --layout-name-in-title
+pub(crate) fn release_window(
+    conn: &impl Connection,
+    atoms: Option<&Atoms>,
+    window: u32,
+    record: &Tracked,
+) -> Result<(), Box<dyn std::error::Error>> {
+    conn.change_window_attributes(
+        window,
+        &ChangeWindowAttributesAux::new().event_mask(record.original_mask),
+    )?;
+    conn.ungrab_key(0, window, 32768u16)?;
+    if record.keyboard_grab {
+        conn.ungrab_keyboard(CURRENT_TIME)?;
+    }
+    if let (Some(atoms), Some(title)) = (atoms, &record.title) {
+        set_window_names(conn, atoms, window, &title.net_name, &title.name)?;
+    }
+    Ok(())
+}
+
+// saves what is visible of the window as a ppm file named after it
+pub(crate) fn capture_screenshot(
+    conn: &impl Connection,
+    config: &Config,
+    dir: &str,
+    window: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let attributes = conn.get_window_attributes(window)?.reply()?;
+    if attributes.map_state != MapState::VIEWABLE {
+        warn(
+            config,
+            &format!("Window 0x{window:08x} isn't mapped, no screenshot taken."),
+        );
+        return Ok(());
+    }
+
+    // the server only has contents for the part of the window on the screen
+    let geometry = conn.get_geometry(window)?.reply()?;
+    let screen = conn.get_geometry(geometry.root)?.reply()?;
+    let origin = conn
+        .translate_coordinates(window, geometry.root, 0, 0)?
+        .reply()?;
+    let visible = visible_part(
+        (geometry.width, geometry.height),
+        (origin.dst_x, origin.dst_y),
+        (screen.width, screen.height),
+    );
+    let Some((x, y, width, height)) = visible else {
+        warn(
+            config,
+            &format!("Window 0x{window:08x} has nothing on screen, no screenshot taken."),
+        );
+        return Ok(());
+    };
+
+    let layout = conn
+        .setup()
+        .roots
+        .iter()
+        .flat_map(|screen| screen.allowed_depths.iter())
+        .flat_map(|depth| depth.visuals.iter())
+        .find(|visual| visual.visual_id == attributes.visual)
+        .and_then(|visual| PixelLayout::from_visual_type(*visual).ok());
+    let Some(layout) = layout else {
+        warn(
+            config,
+            &format!("Window 0x{window:08x} doesn't use a true color visual, no screenshot taken."),
+        );
+        return Ok(());
+    };
+    let image = match Image::get(conn, window, x, y, width, height) {
+        Ok(image) => image,
+        // the window went away or was unmapped in the meantime
+        Err(ReplyError::X11Error(_)) => {
+            warn(
+                config,
+                &format!("Couldn't read the contents of window 0x{window:08x}."),
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut ppm = format!("P6\n{width} {height}\n255\n").into_bytes();
+    for row in 0..height {
+        for column in 0..width {
+            let (red, green, blue) = layout.decode(image.get_pixel(column, row));
+            ppm.extend([red >> 8, green >> 8, blue >> 8].map(|channel| channel as u8));
+        }
+    }
+    std::fs::write(
+        std::path::Path::new(dir).join(format!("0x{window:08x}.ppm")),
+        ppm,
+    )?;
+
+    Ok(())
+}
+
+// the (x, y, width, height) of a window of `size` at `origin` of the root which is on the screen,
+// in the coordinates of the window
+fn visible_part(
+    size: (u16, u16),
+    origin: (i16, i16),
+    screen: (u16, u16),
+) -> Option<(i16, i16, u16, u16)> {
+    let (x, y) = (-origin.0.min(0), -origin.1.min(0));
+    let width = i32::from(size.0).min(i32::from(screen.0) - i32::from(origin.0)) - i32::from(x);
+    let height = i32::from(size.1).min(i32::from(screen.1) - i32::from(origin.1)) - i32::from(y);
+    if width <= 0 || height <= 0 {
+        return None;
+    }
+
+    Some((x, y, width as u16, height as u16))
+}
+
+pub(crate) fn destroy_window(
+    conn: &impl Connection,
+    window: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    conn.destroy_window(window)?;
+    conn.flush()?;
+    Ok(())
+}
+
+// a plain mapped window to type into for --test-window
+pub(crate) fn create_test_window(
+    conn: &impl Connection,
+    root: u32,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let white = conn
+        .setup()
+        .roots
+        .iter()
+        .find(|screen| screen.root == root)
+        .map_or(0, |screen| screen.white_pixel);
+    let window = conn.generate_id()?;
+    conn.create_window(
+        0,
+        window,
+        root,
+        0,
+        0,
+        400,
+        200,
+        0,
+        WindowClass::INPUT_OUTPUT,
+        0,
+        &CreateWindowAux::new().background_pixel(white),
+    )?;
+    conn.change_property8(
+        PropMode::REPLACE,
+        window,
+        AtomEnum::WM_NAME,
+        AtomEnum::STRING,
+        b"mmk test window",
+    )?;
+    conn.map_window(window)?;
+    conn.flush()?;
+
+    Ok(window)
+}
+
+// a grabbed window, with the mask keys are sent with, the one it had before mmk came along
+// and the layout it is translated to
+#[derive(Clone)]
+pub(crate) struct Tracked {
+    pub(crate) send_mask: u32,
+    pub(crate) original_mask: u32,
+    // the index of the layout its keys are translated to
+    pub(crate) layout: usize,
+    // --grab-keyboard, let go of with the window
+    pub(crate) keyboard_grab: bool,
+    // set once --layout-name-in-title changed the title
+    pub(crate) title: Option<TitleRecord>,
+}
+
+impl Tracked {
+    // the mask its keys are sent with, --deliver-mask overrides the one of the window
+    pub(crate) fn delivery_mask(&self, config: &Config) -> u32 {
+        config.deliver_mask.unwrap_or(self.send_mask)
+    }
+}
+
+// the _NET_WM_NAME and WM_NAME a window had before --layout-name-in-title, and the layout shown
+#[derive(Clone)]
+pub(crate) struct TitleRecord {
+    pub(crate) net_name: String,
+    pub(crate) name: String,
+    pub(crate) layout: usize,
+    pub(crate) suffix: String,
+}
+
+pub(crate) fn grab_window(
+    conn: &impl Connection,
+    server_keymap: &ServerKeymap,
+    config: &Config,
+    window: u32,
+) -> Result<Tracked, Box<dyn std::error::Error>> {
+    let m = conn.get_window_attributes(window)?.reply()?.your_event_mask;
+    let mask = m | EventMask::KEY_PRESS | EventMask::KEY_RELEASE;
+    // structure notify only tells us when the window goes away, keys aren't delivered with it
+    let mut selected = mask | EventMask::STRUCTURE_NOTIFY;
+    if config.suspend_while_fullscreen || config.layout_name_in_title {
+        // to hear about _NET_WM_STATE or the title changing
+        selected = selected | EventMask::PROPERTY_CHANGE;
+    }
+    conn.change_window_attributes(
+        window,
+        &ChangeWindowAttributesAux::new().event_mask(Some(selected.into())),
+    )?;
+    let record = Tracked {
+        send_mask: mask.into(),
+        original_mask: m,
+        layout: 0,
+        keyboard_grab: config.grab_keyboard,
+        title: None,
+    };
+    // a window which can't be grabbed is left the way it was found
+    if let Err(e) = grab_keys(conn, server_keymap, config, window) {
+        release_window(conn, None, window, &record)?;
+        conn.flush()?;
+        return Err(e);
+    }
+
+    conn.flush()?;
+    Ok(record)
+}
+
+pub(crate) fn grab_keys(
+    conn: &impl Connection,
+    server_keymap: &ServerKeymap,
+    config: &Config,
+    window: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (pointer_mode, keyboard_mode) = grab_modes(config);
+    if config.grab_keyboard {
+        let status = conn
+            .grab_keyboard(false, window, CURRENT_TIME, pointer_mode, keyboard_mode)?
+            .reply()?
+            .status;
+        return match status {
+            GrabStatus::SUCCESS => Ok(()),
+            GrabStatus::ALREADY_GRABBED => Err("another client has grabbed the keyboard".into()),
+            GrabStatus::FROZEN => {
+                Err("the keyboard is frozen by the grab of another client".into())
+            }
+            GrabStatus::NOT_VIEWABLE => {
+                Err("the window to grab the keyboard for isn't mapped".into())
+            }
+            _ => Err(format!("grabbing the keyboard failed with status {status:?}").into()),
+        };
+    }
+    // checked right away so a grab another client holds is told apart from other errors
+    if config.grab_combos.is_empty() {
+        conn.grab_key(false, window, 32768u16, 0, pointer_mode, keyboard_mode)?
+            .check()?;
+    }
+    for (keysym, mods) in config.grab_combos.iter() {
+        let keycode = server_keymap.keysym_to_keycode(*keysym);
+        if keycode == 0 {
+            return Err(format!("no keycode for keysym {keysym:#x} in the current keymap").into());
+        }
+        conn.grab_key(false, window, *mods, keycode, pointer_mode, keyboard_mode)?
+            .check()?;
+    }
+
+    Ok(())
+}
+
+// grabs the --toggle-key combination on the root, with and without CapsLock and NumLock
+pub(crate) fn grab_toggle_key(
+    conn: &impl Connection,
+    server_keymap: &ServerKeymap,
+    root: u32,
+    (keysym, mods): (Keysym, u16),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let keycode = server_keymap.keysym_to_keycode(keysym);
+    if keycode == 0 {
+        return Err(format!("no keycode for the --toggle-key keysym {keysym:#x}").into());
+    }
+    let locks = [
+        0,
+        ModMask::LOCK.into(),
+        ModMask::M2.into(),
+        u16::from(ModMask::LOCK | ModMask::M2),
+    ];
+    for lock in locks {
+        conn.grab_key(
+            false,
+            root,
+            mods | lock,
+            keycode,
+            GrabMode::ASYNC,
+            GrabMode::ASYNC,
+        )?
+        .check()?;
+    }
+
+    Ok(())
+}
+
+// tries every grab the window needs, returns how many were tried and which ones another
+// client already holds
+// the (pointer, keyboard) modes of the grabs, asynchronous unless --pointer-mode or
+// --keyboard-mode say otherwise
+pub(crate) fn grab_modes(config: &Config) -> (GrabMode, GrabMode) {
+    (
+        config.pointer_mode.unwrap_or(GrabMode::ASYNC),
+        config.keyboard_mode.unwrap_or(GrabMode::ASYNC),
+    )
+}
+
+// what a key has to let go of after a synchronous grab froze the devices for it
+pub(crate) fn events_to_allow(config: &Config) -> Vec<Allow> {
+    let mut ret = vec![];
+    if config.keyboard_mode == Some(GrabMode::SYNC) {
+        ret.push(Allow::SYNC_KEYBOARD);
+    }
+    if config.pointer_mode == Some(GrabMode::SYNC) {
+        ret.push(Allow::ASYNC_POINTER);
+    }
+
+    ret
+}
+
+pub(crate) fn grab_conflicts(
+    conn: &impl Connection,
+    server_keymap: &ServerKeymap,
+    config: &Config,
+    window: u32,
+) -> Result<(usize, Vec<String>), Box<dyn std::error::Error>> {
+    let mut grabs = vec![];
+    if config.grab_combos.is_empty() {
+        grabs.push((32768u16, 0, "all keys".to_string()));
+    }
+    for (keysym, mods) in config.grab_combos.iter() {
+        let keycode = server_keymap.keysym_to_keycode(*keysym);
+        grabs.push((*mods, keycode, keysym_name(*keysym)));
+    }
+
+    let (pointer_mode, keyboard_mode) = grab_modes(config);
+    let mut statuses = vec![];
+    for (mods, keycode, description) in grabs.iter() {
+        let grab = conn.grab_key(false, window, *mods, *keycode, pointer_mode, keyboard_mode)?;
+        statuses.push((description.clone(), grab.check()));
+    }
+
+    Ok((grabs.len(), conflicting_grabs(statuses)?))
+}
+
+// the grabs another client already holds, which the server refuses with BadAccess.
+// any other error is one of ours
+fn conflicting_grabs(
+    statuses: Vec<(String, Result<(), ReplyError>)>,
+) -> Result<Vec<String>, ReplyError> {
+    let mut conflicts = vec![];
+    for (description, status) in statuses {
+        match status {
+            Ok(()) => (),
+            Err(ReplyError::X11Error(e)) if e.error_kind == ErrorKind::Access => {
+                conflicts.push(description)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(conflicts)
+}
+
+// whether the window's keys are still ours, tells when that changes
+// how long the result of checking a grab for --translate-only-when-grab-active is used, in ms
+pub(crate) const GRAB_CHECK_INTERVAL: u32 = 1000;
+
+// whether a key at `time`, in server milliseconds, has to check the grab on its window again.
+// every check costs a round trip, so the keys in between use the result of the last one
+pub(crate) fn grab_check_due(checked: &mut HashMap<u32, u32>, window: u32, time: u32) -> bool {
+    match checked.get(&window) {
+        // the server time wraps around after 49 days
+        Some(last) if time.wrapping_sub(*last) < GRAB_CHECK_INTERVAL => false,
+        _ => {
+            checked.insert(window, time);
+            true
+        }
+    }
+}
+
+pub(crate) fn grab_held(
+    conn: &impl Connection,
+    server_keymap: &ServerKeymap,
+    config: &Config,
+    window: u32,
+    lost: &mut Vec<u32>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    // grabbing again is a no-op for our own grab and fails while another client holds it
+    let held = grab_conflicts(conn, server_keymap, config, window)?
+        .1
+        .is_empty();
+    if !held && !lost.contains(&window) {
+        warn(
+            config,
+            &format!(
+                "Lost the grab on window 0x{window:08x}, leaving its keys alone until it is back."
+            ),
+        );
+        lost.push(window);
+    }
+    if held && lost.contains(&window) {
+        if !config.quiet {
+            eprintln!("mmk: got the grab on window 0x{window:08x} back, translating again");
+        }
+        lost.retain(|lost| *lost != window);
+    }
+
+    Ok(held)
+}
+
+// an empty name deletes the property instead of leaving an empty title around
+pub(crate) fn set_window_names(
+    conn: &impl Connection,
+    atoms: &Atoms,
+    window: u32,
+    net_name: &str,
+    name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let names = [
+        (atoms.net_wm_name, atoms.utf8_string, net_name),
+        (AtomEnum::WM_NAME.into(), AtomEnum::STRING.into(), name),
+    ];
+    for (property, type_, value) in names {
+        if value.is_empty() {
+            conn.delete_property(window, property)?;
+        } else {
+            conn.change_property8(PropMode::REPLACE, window, property, type_, value.as_bytes())?;
+        }
+    }
+    conn.flush()?;
+
+    Ok(())
+}
+
+// appends the name of the layout to the title of the window for --layout-name-in-title
+pub(crate) fn show_layout_in_title(
+    conn: &impl Connection,
+    atoms: &Atoms,
+    layout_names: &[XkbLayout],
+    window: u32,
+    record: &mut Tracked,
+    layout: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if record
+        .title
+        .as_ref()
+        .is_some_and(|title| title.layout == layout)
+    {
+        return Ok(());
+    }
+    let (net_name, name) = match record.title.take() {
+        Some(title) => (title.net_name, title.name),
+        None => window_names(conn, window, atoms)?,
+    };
+    let layout_name = layout_names
+        .iter()
+        .find(|known| known.index == layout)
+        .map_or_else(|| layout.to_string(), |known| known.layout.clone());
+    let suffix = format!(" [{layout_name}]");
+
+    // a window without any title still gets a WM_NAME
+    let titled = |original: &str| format!("{original}{suffix}");
+    let new_net_name = if net_name.is_empty() {
+        String::new()
+    } else {
+        titled(&net_name)
+    };
+    let new_name = if name.is_empty() && !net_name.is_empty() {
+        String::new()
+    } else {
+        titled(&name)
+    };
+    set_window_names(conn, atoms, window, &new_net_name, &new_name)?;
+    record.title = Some(TitleRecord {
+        net_name,
+        name,
+        layout,
+        suffix,
+    });
+
+    Ok(())
+}
+
+// the client set a title of its own, which becomes the one to append to and to put back
+pub(crate) fn refresh_title(
+    conn: &impl Connection,
+    atoms: &Atoms,
+    window: u32,
+    record: &mut Tracked,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(title) = &record.title else {
+        return Ok(());
+    };
+    let (net_name, name) = window_names(conn, window, atoms)?;
+    // our own change comes back as a PropertyNotify too
+    let ours = |value: &str| value.is_empty() || value.ends_with(&title.suffix);
+    if ours(&net_name) && ours(&name) {
+        return Ok(());
+    }
+
+    let original = |value: &str| {
+        value
+            .strip_suffix(&title.suffix)
+            .unwrap_or(value)
+            .to_string()
+    };
+    let (net_name, name) = (original(&net_name), original(&name));
+    record.title = None;
+    set_window_names(conn, atoms, window, &net_name, &name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::parse_rules_names;
+    use crate::testing::{
+        atoms, config, grab_keyboard_reply, server_keymap, text_reply, window_attributes_reply,
+        x11_error, FakeServer,
+    };
+    use x11rb::protocol::Request;
+
+    // the titles ChangeProperty set on `window`, _NET_WM_NAME and WM_NAME
+    fn titles_set(requests: &[Request], window: u32) -> Vec<(u32, String)> {
+        requests
+            .iter()
+            .filter_map(|request| match request {
+                Request::ChangeProperty(change) if change.window == window => Some((
+                    change.property,
+                    String::from_utf8(change.data.to_vec()).unwrap(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn grab_keyboard_holds_the_keyboard_until_the_window_is_let_go() {
+        let config = config(&["--grab-keyboard"]);
+        let server = FakeServer::new(vec![
+            window_attributes_reply(0),
+            grab_keyboard_reply(GrabStatus::SUCCESS),
+        ]);
+
+        let record = grab_window(&server, &server_keymap(), &config, 2).unwrap();
+        assert!(record.keyboard_grab);
+        let requests = server.take_requests();
+        let [Request::GetWindowAttributes(_), Request::ChangeWindowAttributes(_), Request::GrabKeyboard(grab)] =
+            &requests[..]
+        else {
+            panic!("unexpected requests {requests:#?}");
+        };
+        assert_eq!(grab.grab_window, 2);
+
+        release_window(&server, None, 2, &record).unwrap();
+        let requests = server.take_requests();
+        assert!(matches!(requests.last(), Some(Request::UngrabKeyboard(_))));
+    }
+
+    #[test]
+    fn a_refused_keyboard_grab_says_why() {
+        let config = config(&["--grab-keyboard"]);
+        let refused = [
+            (
+                GrabStatus::ALREADY_GRABBED,
+                "another client has grabbed the keyboard",
+            ),
+            (
+                GrabStatus::FROZEN,
+                "the keyboard is frozen by the grab of another client",
+            ),
+            (
+                GrabStatus::NOT_VIEWABLE,
+                "the window to grab the keyboard for isn't mapped",
+            ),
+            (
+                GrabStatus::INVALID_TIME,
+                "grabbing the keyboard failed with status INVALID_TIME",
+            ),
+        ];
+        for (status, message) in refused {
+            let server = FakeServer::new(vec![
+                window_attributes_reply(EventMask::EXPOSURE.into()),
+                grab_keyboard_reply(status),
+            ]);
+            let grabbed = grab_window(&server, &server_keymap(), &config, 2);
+            assert_eq!(
+                grabbed.err().map(|e| e.to_string()).as_deref(),
+                Some(message)
+            );
+            // the window is left the way it was found
+            let requests = server.take_requests();
+            let [.., Request::ChangeWindowAttributes(restored), Request::UngrabKey(_), Request::UngrabKeyboard(_)] =
+                &requests[..]
+            else {
+                panic!("unexpected requests {requests:#?}");
+            };
+            assert_eq!(
+                restored.value_list.event_mask,
+                Some(EventMask::EXPOSURE.into())
+            );
+        }
+    }
+
+    #[test]
+    fn the_layout_is_shown_in_the_title_until_the_window_is_let_go() {
+        let atoms = atoms();
+        let server = FakeServer::new(vec![
+            text_reply(atoms.utf8_string, "notes.txt \u{2014} Editor"),
+            text_reply(AtomEnum::STRING.into(), "notes.txt - Editor"),
+        ]);
+        let layouts = parse_rules_names(b"evdev\0pc105\0us,de\0\0\0");
+        let wm_name = u32::from(AtomEnum::WM_NAME);
+        let mut record = Tracked {
+            send_mask: 0,
+            original_mask: 0,
+            layout: 1,
+            keyboard_grab: false,
+            title: None,
+        };
+
+        show_layout_in_title(&server, &atoms, &layouts, 2, &mut record, 1).unwrap();
+        assert_eq!(
+            titles_set(&server.take_requests(), 2),
+            [
+                (
+                    atoms.net_wm_name,
+                    "notes.txt \u{2014} Editor [de]".to_string()
+                ),
+                (wm_name, "notes.txt - Editor [de]".to_string()),
+            ]
+        );
+        // nothing to do while the layout stays
+        show_layout_in_title(&server, &atoms, &layouts, 2, &mut record, 1).unwrap();
+        assert!(server.take_requests().is_empty());
+
+        // the original titles are kept, not read back with the layout appended
+        show_layout_in_title(&server, &atoms, &layouts, 2, &mut record, 5).unwrap();
+        assert_eq!(
+            titles_set(&server.take_requests(), 2),
+            [
+                (
+                    atoms.net_wm_name,
+                    "notes.txt \u{2014} Editor [5]".to_string()
+                ),
+                (wm_name, "notes.txt - Editor [5]".to_string()),
+            ]
+        );
+
+        release_window(&server, Some(&atoms), 2, &record).unwrap();
+        assert_eq!(
+            titles_set(&server.take_requests(), 2),
+            [
+                (atoms.net_wm_name, "notes.txt \u{2014} Editor".to_string()),
+                (wm_name, "notes.txt - Editor".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_test_window_is_grabbed_and_destroyed_on_exit() {
+        let server = FakeServer::new(vec![window_attributes_reply(EventMask::EXPOSURE.into())]);
+        let config = config(&["--test-window"]);
+
+        let window = create_test_window(&server, 1).unwrap();
+        let record = grab_window(&server, &server_keymap(), &config, window).unwrap();
+        release_window(&server, None, window, &record).unwrap();
+        destroy_window(&server, window).unwrap();
+
+        let requests = server.take_requests();
+        let [Request::CreateWindow(created), Request::ChangeProperty(name), Request::MapWindow(mapped), Request::GetWindowAttributes(_), Request::ChangeWindowAttributes(selected), Request::GrabKey(grab), Request::ChangeWindowAttributes(restored), Request::UngrabKey(ungrab), Request::DestroyWindow(destroyed)] =
+            &requests[..]
+        else {
+            panic!("unexpected requests {requests:#?}");
+        };
+        assert_eq!((created.wid, created.parent), (window, 1));
+        assert_eq!(created.value_list.background_pixel, Some(0xffffff));
+        assert_eq!(
+            (name.window, &name.data[..]),
+            (window, &b"mmk test window"[..])
+        );
+        assert_eq!(mapped.window, window);
+        assert_eq!(
+            selected.value_list.event_mask,
+            Some(
+                (EventMask::EXPOSURE
+                    | EventMask::KEY_PRESS
+                    | EventMask::KEY_RELEASE
+                    | EventMask::STRUCTURE_NOTIFY)
+                    .into()
+            )
+        );
+        assert_eq!(
+            (grab.grab_window, grab.key, grab.modifiers),
+            (window, 0, 32768)
+        );
+        assert_eq!(
+            restored.value_list.event_mask,
+            Some(EventMask::EXPOSURE.into())
+        );
+        assert_eq!(ungrab.grab_window, window);
+        assert_eq!(destroyed.window, window);
+    }
+
+    #[test]
+    fn deliver_mask_overrides_the_mask_of_the_window() {
+        let tracked = Tracked {
+            send_mask: (EventMask::KEY_PRESS | EventMask::KEY_RELEASE).into(),
+            original_mask: 0,
+            layout: 0,
+            keyboard_grab: false,
+            title: None,
+        };
+
+        assert_eq!(tracked.delivery_mask(&config(&[])), 3);
+        assert_eq!(
+            tracked.delivery_mask(&config(&["--deliver-mask", "key-press,focus-change"])),
+            u32::from(EventMask::KEY_PRESS | EventMask::FOCUS_CHANGE)
+        );
+        assert_eq!(tracked.delivery_mask(&config(&["--deliver-mask", "0"])), 0);
+        assert_eq!(
+            tracked.delivery_mask(&config(&["--deliver-mask", "0x8000"])),
+            0x8000
+        );
+    }
+
+    #[test]
+    fn screenshots_take_the_part_of_the_window_on_the_screen() {
+        let screen = (1920, 1080);
+        assert_eq!(
+            visible_part((800, 600), (100, 50), screen),
+            Some((0, 0, 800, 600))
+        );
+        // hanging over the top left and the bottom right corner
+        assert_eq!(
+            visible_part((800, 600), (-100, -50), screen),
+            Some((100, 50, 700, 550))
+        );
+        assert_eq!(
+            visible_part((800, 600), (1500, 900), screen),
+            Some((0, 0, 420, 180))
+        );
+        // off the screen or without any size
+        assert_eq!(visible_part((800, 600), (1920, 0), screen), None);
+        assert_eq!(visible_part((800, 600), (-800, 0), screen), None);
+        assert_eq!(visible_part((0, 600), (0, 0), screen), None);
+    }
+
+    #[test]
+    fn grabs_use_the_given_modes() {
+        let (sync, not) = (GrabMode::SYNC, GrabMode::ASYNC);
+        assert_eq!(grab_modes(&config(&[])), (not, not));
+        assert!(events_to_allow(&config(&[])).is_empty());
+
+        let keyboard = config(&["--keyboard-mode", "sync"]);
+        assert_eq!(grab_modes(&keyboard), (not, sync));
+        assert_eq!(events_to_allow(&keyboard), [Allow::SYNC_KEYBOARD]);
+
+        let pointer = config(&["--pointer-mode", "sync", "--keyboard-mode", "async"]);
+        assert_eq!(grab_modes(&pointer), (sync, not));
+        assert_eq!(events_to_allow(&pointer), [Allow::ASYNC_POINTER]);
+    }
+
+    #[test]
+    fn refused_grabs_are_conflicts() {
+        let statuses = vec![
+            ("a".to_string(), Ok(())),
+            ("b".to_string(), Err(x11_error(ErrorKind::Access))),
+            ("c".to_string(), Ok(())),
+            ("d".to_string(), Err(x11_error(ErrorKind::Access))),
+        ];
+        assert_eq!(conflicting_grabs(statuses).unwrap(), ["b", "d"]);
+
+        let statuses = vec![("a".to_string(), Ok(())), ("b".to_string(), Ok(()))];
+        assert!(conflicting_grabs(statuses).unwrap().is_empty());
+
+        // a window gone in the meantime is no conflict
+        let statuses = vec![
+            ("a".to_string(), Err(x11_error(ErrorKind::Access))),
+            ("b".to_string(), Err(x11_error(ErrorKind::Window))),
+        ];
+        assert!(conflicting_grabs(statuses).is_err());
+    }
+
+    #[test]
+    fn windows_without_keys_for_the_longest_time_are_evicted() {
+        // windows 1 to 4 sent their last key in this order, 5 never sent one
+        let last_seen = HashMap::from([(3, 10), (1, 20), (4, 30), (2, 40)]);
+        let windows = || [1, 2, 3, 4, 5].into_iter();
+
+        assert_eq!(windows_to_evict(windows(), &last_seen, 5), []);
+        assert_eq!(windows_to_evict(windows(), &last_seen, 10), []);
+        assert_eq!(windows_to_evict(windows(), &last_seen, 4), [5]);
+        assert_eq!(windows_to_evict(windows(), &last_seen, 2), [5, 3, 1]);
+        assert_eq!(windows_to_evict(windows(), &last_seen, 0), [5, 3, 1, 4, 2]);
+    }
+
+    #[test]
+    fn grabs_are_checked_again_once_the_interval_passed() {
+        let mut checked = HashMap::new();
+
+        assert!(grab_check_due(&mut checked, 1, 5000));
+        assert!(!grab_check_due(
+            &mut checked,
+            1,
+            5000 + GRAB_CHECK_INTERVAL - 1
+        ));
+        // every window keeps its own time
+        assert!(grab_check_due(&mut checked, 2, 5500));
+        assert!(grab_check_due(&mut checked, 1, 5000 + GRAB_CHECK_INTERVAL));
+        assert!(!grab_check_due(
+            &mut checked,
+            1,
+            5000 + GRAB_CHECK_INTERVAL + 1
+        ));
+
+        // across the wrap around of the server time
+        assert!(grab_check_due(&mut checked, 3, u32::MAX - 10));
+        assert!(!grab_check_due(&mut checked, 3, 10));
+        assert!(grab_check_due(&mut checked, 3, GRAB_CHECK_INTERVAL));
+    }
+}